@@ -1,25 +1,118 @@
 use std::fmt::{self, write, Display, Error};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum ARegisterNode {
     AX,
+    BX,
+    CX,
     DX,
+    DI,
+    SI,
+    R8,
+    R9,
     R10,
     R11,
+    R12,
+    R13,
+    R14,
+    R15,
+    // only handed out when the frame pointer is omitted
+    BP,
+    // never allocated, but addresses stack slots when there is no frame pointer
+    SP,
 }
 
-impl Display for ARegisterNode {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+// where the System V calling convention passes the first six integer arguments, in order
+pub const ARGUMENT_REGISTERS: [ARegisterNode; 6] = [
+    ARegisterNode::DI,
+    ARegisterNode::SI,
+    ARegisterNode::DX,
+    ARegisterNode::CX,
+    ARegisterNode::R8,
+    ARegisterNode::R9,
+];
+
+// where the System V calling convention returns the eightbytes of a value, in order
+pub const RETURN_REGISTERS: [ARegisterNode; 2] = [ARegisterNode::AX, ARegisterNode::DX];
+
+impl ARegisterNode {
+    // registers a function must restore before returning if it uses them
+    pub fn is_callee_saved(&self) -> bool {
+        use ARegisterNode::*;
+        matches!(self, BX | R12 | R13 | R14 | R15 | BP)
+    }
+
+    pub fn quadword_name(&self) -> &'static str {
+        use ARegisterNode::*;
+        match self {
+            AX => "rax",
+            BX => "rbx",
+            CX => "rcx",
+            DX => "rdx",
+            DI => "rdi",
+            SI => "rsi",
+            R8 => "r8",
+            R9 => "r9",
+            R10 => "r10",
+            R11 => "r11",
+            R12 => "r12",
+            R13 => "r13",
+            R14 => "r14",
+            R15 => "r15",
+            BP => "rbp",
+            SP => "rsp",
+        }
+    }
+
+    fn byte_name(&self) -> &'static str {
+        use ARegisterNode::*;
         match self {
-            ARegisterNode::AX => write!(f, "eax"),
-            ARegisterNode::DX => write!(f, "edx"),
-            ARegisterNode::R10 => write!(f, "r10d"),
-            ARegisterNode::R11 => write!(f, "r11d"),
+            AX => "al",
+            BX => "bl",
+            CX => "cl",
+            DX => "dl",
+            DI => "dil",
+            SI => "sil",
+            R8 => "r8b",
+            R9 => "r9b",
+            R10 => "r10b",
+            R11 => "r11b",
+            R12 => "r12b",
+            R13 => "r13b",
+            R14 => "r14b",
+            R15 => "r15b",
+            BP => "bpl",
+            SP => "spl",
         }
     }
 }
 
-#[derive(Debug, Clone)]
+impl Display for ARegisterNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ARegisterNode::*;
+        let name = match self {
+            AX => "eax",
+            BX => "ebx",
+            CX => "ecx",
+            DX => "edx",
+            DI => "edi",
+            SI => "esi",
+            R8 => "r8d",
+            R9 => "r9d",
+            R10 => "r10d",
+            R11 => "r11d",
+            R12 => "r12d",
+            R13 => "r13d",
+            R14 => "r14d",
+            R15 => "r15d",
+            BP => "ebp",
+            SP => "esp",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum AConditionCode {
     E,
     NE,
@@ -29,6 +122,34 @@ pub enum AConditionCode {
     LE,
 }
 
+impl AConditionCode {
+    // the condition that holds exactly when this one doesn't
+    pub fn inverse(&self) -> AConditionCode {
+        use AConditionCode::*;
+        match self {
+            E => NE,
+            NE => E,
+            G => LE,
+            GE => L,
+            L => GE,
+            LE => G,
+        }
+    }
+
+    // the condition that holds for the same values compared the other way round
+    pub fn swapped(&self) -> AConditionCode {
+        use AConditionCode::*;
+        match self {
+            E => E,
+            NE => NE,
+            G => L,
+            GE => LE,
+            L => G,
+            LE => GE,
+        }
+    }
+}
+
 impl Display for AConditionCode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use AConditionCode::*;
@@ -44,12 +165,15 @@ impl Display for AConditionCode {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum AOperandNode {
-    Imm(usize),
+    Imm(i32),
     Reg(ARegisterNode),
     Pseudo(String),
+    PseudoMem(String, usize),
     Stack(isize),
+    // a stack slot addressed through a register other than %rbp
+    Memory(ARegisterNode, isize),
 }
 
 impl Display for AOperandNode {
@@ -58,12 +182,80 @@ impl Display for AOperandNode {
             AOperandNode::Imm(c) => write!(f, "${c}"),
             AOperandNode::Reg(reg) => write!(f, "%{reg}"),
             AOperandNode::Stack(addr) => write!(f, "{addr}(%rbp)"),
+            AOperandNode::Memory(reg, addr) => write!(f, "{addr}(%{})", reg.quadword_name()),
             _ => Err(Error),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+// an operand of an instruction that works on all 64 bits of a register
+struct Quadword<'a>(&'a AOperandNode);
+
+impl Display for Quadword<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            AOperandNode::Reg(reg) => write!(f, "%{}", reg.quadword_name()),
+            operand => write!(f, "{operand}"),
+        }
+    }
+}
+
+// an address computed by lea, which only does the arithmetic and never touches memory; the
+// operands have to end up in registers
+#[derive(Debug, Clone, PartialEq)]
+pub enum AAddressNode {
+    // base + displacement
+    Offset(AOperandNode, i32),
+    // base + index * scale, where the scale is 1, 2, 4 or 8
+    Indexed(AOperandNode, AOperandNode, u8),
+}
+
+impl AAddressNode {
+    pub fn operands(&self) -> Vec<&AOperandNode> {
+        match self {
+            AAddressNode::Offset(base, _) => vec![base],
+            AAddressNode::Indexed(base, index, _) => vec![base, index],
+        }
+    }
+
+    pub fn map_operands(&self, mut f: impl FnMut(&AOperandNode) -> AOperandNode) -> AAddressNode {
+        match self {
+            AAddressNode::Offset(base, displacement) => {
+                AAddressNode::Offset(f(base), *displacement)
+            }
+            AAddressNode::Indexed(base, index, scale) => {
+                AAddressNode::Indexed(f(base), f(index), *scale)
+            }
+        }
+    }
+}
+
+// addresses are 64 bits wide, but only the low 32 bits of the result are kept, so whatever is in
+// the upper halves of the registers doesn't matter
+fn address_register(operand: &AOperandNode) -> Result<&'static str, Error> {
+    match operand {
+        AOperandNode::Reg(reg) => Ok(reg.quadword_name()),
+        _ => Err(Error),
+    }
+}
+
+impl Display for AAddressNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AAddressNode::Offset(base, displacement) => {
+                write!(f, "{displacement}(%{})", address_register(base)?)
+            }
+            AAddressNode::Indexed(base, index, scale) => write!(
+                f,
+                "(%{}, %{}, {scale})",
+                address_register(base)?,
+                address_register(index)?
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum AUnaryOperatorNode {
     Neg,
     Not,
@@ -78,11 +270,15 @@ impl Display for AUnaryOperatorNode {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ABinaryOperatorNode {
     Add,
     Sub,
     Mult,
+    And,
+    Xor,
+    Sal,
+    Sar,
 }
 
 impl Display for ABinaryOperatorNode {
@@ -91,16 +287,28 @@ impl Display for ABinaryOperatorNode {
             ABinaryOperatorNode::Add => write!(f, "addl"),
             ABinaryOperatorNode::Sub => write!(f, "subl"),
             ABinaryOperatorNode::Mult => write!(f, "imull"),
+            ABinaryOperatorNode::And => write!(f, "andl"),
+            ABinaryOperatorNode::Xor => write!(f, "xorl"),
+            ABinaryOperatorNode::Sal => write!(f, "sall"),
+            ABinaryOperatorNode::Sar => write!(f, "sarl"),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum AInstructionNode {
     Mov(AOperandNode, AOperandNode),
+    // moves a whole eightbyte of a structure passed or returned in a register
+    Movq(AOperandNode, AOperandNode),
+    // the address of a stack slot, handed to a function that returns a structure through it
+    LoadAddress(AOperandNode, ARegisterNode),
     Unary(AUnaryOperatorNode, AOperandNode),
     Binary(ABinaryOperatorNode, AOperandNode, AOperandNode),
     Cmp(AOperandNode, AOperandNode),
+    // sets the flags from the bitwise and of its operands, so testing a value against itself
+    // compares it with zero
+    Test(AOperandNode, AOperandNode),
+    Lea(AAddressNode, AOperandNode),
     Idiv(AOperandNode),
     Cdq,
     Jmp(String),
@@ -108,21 +316,23 @@ pub enum AInstructionNode {
     SetCC(AConditionCode, AOperandNode),
     Label(String),
     AllocateStack(usize),
+    DeallocateStack(usize),
+    Push(ARegisterNode),
+    Pop(ARegisterNode),
+    // the number of arguments passed in registers, which the call reads
+    Call(String, usize),
+    // a call in tail position, which tears down the frame and jumps, leaving the callee to
+    // return straight to this function's caller
+    TailCall(String, usize),
     Ret,
 }
 
 fn setcc_helper(instruction: &AInstructionNode, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     if let AInstructionNode::SetCC(cc, operand) = instruction {
-        let op = match operand {
-            AOperandNode::Reg(reg) => match reg {
-                ARegisterNode::AX => "%al",
-                ARegisterNode::DX => "%dl",
-                ARegisterNode::R10 => "%r10b",
-                ARegisterNode::R11 => "%r11b",
-            },
-            _ => &format!("{operand}"),
-        };
-        write!(f, "set{cc}\t{op}")
+        match operand {
+            AOperandNode::Reg(reg) => write!(f, "set{cc}\t%{}", reg.byte_name()),
+            _ => write!(f, "set{cc}\t{operand}"),
+        }
     } else {
         unimplemented!();
     }
@@ -133,17 +343,27 @@ impl Display for AInstructionNode {
         write!(f, "\t")?;
         match self {
             AInstructionNode::Mov(src, dst) => write!(f, "movl\t{src}, {dst}"),
+            AInstructionNode::Movq(src, dst) => {
+                write!(f, "movq\t{}, {}", Quadword(src), Quadword(dst))
+            }
+            AInstructionNode::LoadAddress(src, dst) => {
+                write!(f, "leaq\t{src}, %{}", dst.quadword_name())
+            }
             AInstructionNode::Unary(operator, operand) => write!(f, "{operator}\t{operand}"),
             AInstructionNode::AllocateStack(size) => write!(f, "subq\t$({size}), %rsp"),
-            AInstructionNode::Ret => {
-                write!(f, "movq\t%rbp, %rsp\n")?;
-                write!(f, "\tpopq\t%rbp\n")?;
-                write!(f, "\tret")
-            }
+            AInstructionNode::DeallocateStack(size) => write!(f, "addq\t$({size}), %rsp"),
+            AInstructionNode::Call(name, _) => write!(f, "call\t{name}"),
+            AInstructionNode::Push(reg) => write!(f, "pushq\t%{}", reg.quadword_name()),
+            AInstructionNode::Pop(reg) => write!(f, "popq\t%{}", reg.quadword_name()),
+            // the emitter tears down the frame first
+            AInstructionNode::Ret => write!(f, "ret"),
+            AInstructionNode::TailCall(name, _) => write!(f, "jmp\t{name}"),
             AInstructionNode::Binary(operator, src, dst) => write!(f, "{operator}\t{src}, {dst}"),
             AInstructionNode::Idiv(operand) => write!(f, "idivl\t{operand}"),
             AInstructionNode::Cdq => write!(f, "cdq"),
             AInstructionNode::Cmp(op1, op2) => write!(f, "cmpl\t{op1}, {op2}"),
+            AInstructionNode::Test(op1, op2) => write!(f, "testl\t{op1}, {op2}"),
+            AInstructionNode::Lea(address, dst) => write!(f, "leal\t{address}, {dst}"),
             AInstructionNode::Jmp(target) => write!(f, "jmp\t.L{target}"),
             AInstructionNode::JmpCC(cc, target) => write!(f, "j{cc}\t.L{target}"),
             AInstructionNode::SetCC(_, _) => setcc_helper(self, f),
@@ -161,5 +381,5 @@ pub enum AFunctionDefinitionNode {
 
 #[derive(Debug)]
 pub enum AProgramNode {
-    Program(AFunctionDefinitionNode),
+    Program(Vec<AFunctionDefinitionNode>),
 }
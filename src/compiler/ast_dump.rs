@@ -0,0 +1,382 @@
+use std::fmt::Write;
+
+use super::{
+    ast_tree::*,
+    tacker::declare,
+    tokens::SourceLocation,
+    types::{IdentifierEntry, SymbolTable},
+};
+
+// a line of the dump, saying what the parser built and what the symbol table resolved it to
+struct DumpNode {
+    label: String,
+    children: Vec<DumpNode>,
+}
+
+fn leaf(label: String) -> DumpNode {
+    DumpNode {
+        label,
+        children: vec![],
+    }
+}
+
+// every node but the program itself ends with where it was written
+fn located(label: String, location: SourceLocation) -> String {
+    format!("{label} <{location}>")
+}
+
+// a type as written, followed by the type it resolves to when that reads differently, the way
+// clang shows typedefs
+fn describe_type(written: &TypeNode, resolved: &TypeNode) -> String {
+    let (written, resolved) = (format!("{written}"), format!("{resolved}"));
+    if written == resolved {
+        return format!("'{written}'");
+    }
+    format!("'{written}':'{resolved}'")
+}
+
+fn dump_expression(expression: &ExpressionNode, symbols: &SymbolTable) -> DumpNode {
+    use ExpressionNode::*;
+    let value_type = symbols.type_of_expression(expression);
+    let (label, children) = match expression {
+        Constant(literal, _) => (format!("Constant {literal}"), vec![]),
+        Var(name, _) => match symbols.lookup(name) {
+            IdentifierEntry::Enumerator(value) => {
+                (format!("Var {name} (enumerator = {value})"), vec![])
+            }
+            _ => (format!("Var {name} (variable)"), vec![]),
+        },
+        Unary(operator, operand, _) => (
+            format!("Unary {operator:?}"),
+            vec![dump_expression(operand, symbols)],
+        ),
+        Binary(operator, left, right, _) => (
+            format!("Binary {operator:?}"),
+            vec![
+                dump_expression(left, symbols),
+                dump_expression(right, symbols),
+            ],
+        ),
+        Assignment(left, right, _) => (
+            String::from("Assignment"),
+            vec![
+                dump_expression(left, symbols),
+                dump_expression(right, symbols),
+            ],
+        ),
+        Conditional(condition, then_branch, else_branch, _) => (
+            String::from("Conditional"),
+            vec![
+                dump_expression(condition, symbols),
+                dump_expression(then_branch, symbols),
+                dump_expression(else_branch, symbols),
+            ],
+        ),
+        Dot(inner, member, _) => {
+            // typing the whole expression has already checked the member exists
+            let offset = match symbols.type_of_expression(inner) {
+                TypeNode::Struct(key) | TypeNode::Union(key) => {
+                    symbols.types.layout_of(&key).member(member).unwrap().offset
+                }
+                _ => unreachable!("member access on a non-aggregate is rejected while typing"),
+            };
+            (
+                format!("Dot {member} (offset {offset})"),
+                vec![dump_expression(inner, symbols)],
+            )
+        }
+        Arrow(inner, member, _) => (
+            format!("Arrow {member}"),
+            vec![dump_expression(inner, symbols)],
+        ),
+        Cast(target, inner, _) => (
+            format!(
+                "Cast to {}",
+                describe_type(target, &symbols.lookup_type(target))
+            ),
+            vec![dump_expression(inner, symbols)],
+        ),
+        SizeOfType(operand, _) => {
+            let resolved = symbols.lookup_type(operand);
+            let size = symbols.types.size_of(&resolved);
+            (
+                format!(
+                    "SizeOfType {} ({size} bytes)",
+                    describe_type(operand, &resolved)
+                ),
+                vec![],
+            )
+        }
+        SizeOfExpression(inner, _) => (
+            format!(
+                "SizeOfExpression ({} bytes)",
+                symbols.size_of_expression(inner)
+            ),
+            vec![dump_expression(inner, symbols)],
+        ),
+        FunctionCall(name, arguments, _) => (
+            format!("FunctionCall {name}"),
+            arguments
+                .iter()
+                .map(|argument| dump_expression(argument, symbols))
+                .collect(),
+        ),
+    };
+    DumpNode {
+        label: located(format!("{label} '{value_type}'"), expression.location()),
+        children,
+    }
+}
+
+fn dump_statement(statement: &StatementNode, symbols: &SymbolTable) -> DumpNode {
+    let (label, children) = match statement {
+        StatementNode::Return(expression, _) => {
+            ("Return", vec![dump_expression(expression, symbols)])
+        }
+        StatementNode::Expression(expression, _) => {
+            ("Expression", vec![dump_expression(expression, symbols)])
+        }
+        StatementNode::Null(_) => ("Null", vec![]),
+    };
+    DumpNode {
+        label: located(String::from(label), statement.location()),
+        children,
+    }
+}
+
+fn dump_aggregate(
+    kind: &str,
+    written: TypeNode,
+    members: &Option<Vec<MemberDeclarationNode>>,
+    location: SourceLocation,
+    symbols: &SymbolTable,
+) -> DumpNode {
+    let resolved = symbols.lookup_type(&written);
+    let mut label = format!("{kind} {}", describe_type(&written, &resolved));
+    let Some(members) = members else {
+        return leaf(located(label, location));
+    };
+    let (TypeNode::Struct(key) | TypeNode::Union(key)) = &resolved else {
+        unreachable!("struct and union tags resolve to struct and union types")
+    };
+    let layout = symbols.types.layout_of(key);
+    write!(label, " ({} bytes) <{location}>", layout.size).unwrap();
+    // the layout lists the members in the order they were declared
+    let children = members
+        .iter()
+        .zip(&layout.members)
+        .map(
+            |(MemberDeclarationNode::Member(member_type, name, location), member)| {
+                leaf(located(
+                    format!(
+                        "Member {name} {} (offset {})",
+                        describe_type(member_type, &member.member_type),
+                        member.offset
+                    ),
+                    *location,
+                ))
+            },
+        )
+        .collect();
+    DumpNode { label, children }
+}
+
+fn dump_declaration(declaration: &DeclarationNode, symbols: &mut SymbolTable) -> DumpNode {
+    // a variable is in scope in its own initializer, so everything is declared up front
+    declare(declaration, symbols);
+    match declaration {
+        DeclarationNode::Declaration(declared_type, name, initializer, location) => {
+            let IdentifierEntry::Variable(resolved) = symbols.lookup(name) else {
+                unreachable!("{name} was just declared as a variable")
+            };
+            DumpNode {
+                label: located(
+                    format!(
+                        "Declaration {name} {}",
+                        describe_type(declared_type, resolved)
+                    ),
+                    *location,
+                ),
+                children: initializer
+                    .iter()
+                    .map(|expression| dump_expression(expression, symbols))
+                    .collect(),
+            }
+        }
+        DeclarationNode::FunctionDeclaration(_, name, parameters, location) => {
+            let IdentifierEntry::Function(function_type) = symbols.lookup(name) else {
+                unreachable!("{name} was just declared as a function")
+            };
+            DumpNode {
+                label: located(
+                    format!("FunctionDeclaration {name} '{function_type}'"),
+                    *location,
+                ),
+                children: parameters
+                    .iter()
+                    .zip(&function_type.parameters)
+                    .map(|((written, name, location), resolved)| {
+                        let parameter_type = describe_type(written, resolved);
+                        let label = match name {
+                            Some(name) => format!("Parameter {name} {parameter_type}"),
+                            None => format!("Parameter {parameter_type}"),
+                        };
+                        leaf(located(label, *location))
+                    })
+                    .collect(),
+            }
+        }
+        DeclarationNode::StructDeclaration(tag, members, location) => dump_aggregate(
+            "StructDeclaration",
+            TypeNode::Struct(tag.clone()),
+            members,
+            *location,
+            symbols,
+        ),
+        DeclarationNode::UnionDeclaration(tag, members, location) => dump_aggregate(
+            "UnionDeclaration",
+            TypeNode::Union(tag.clone()),
+            members,
+            *location,
+            symbols,
+        ),
+        DeclarationNode::EnumDeclaration(tag, enumerators, location) => {
+            let label = match tag {
+                Some(tag) => {
+                    let written = TypeNode::Enum(tag.clone());
+                    format!(
+                        "EnumDeclaration {}",
+                        describe_type(&written, &symbols.lookup_type(&written))
+                    )
+                }
+                None => String::from("EnumDeclaration"),
+            };
+            let children = enumerators
+                .iter()
+                .map(|EnumeratorNode::Enumerator(name, value, location)| {
+                    let IdentifierEntry::Enumerator(resolved) = symbols.lookup(name) else {
+                        unreachable!("{name} was just declared as an enumerator")
+                    };
+                    DumpNode {
+                        label: located(format!("Enumerator {name} = {resolved}"), *location),
+                        children: value
+                            .iter()
+                            .map(|expression| dump_expression(expression, symbols))
+                            .collect(),
+                    }
+                })
+                .collect();
+            DumpNode {
+                label: located(label, *location),
+                children,
+            }
+        }
+        DeclarationNode::TypedefDeclaration(declared_type, name, location) => {
+            let IdentifierEntry::Typedef(resolved) = symbols.lookup(name) else {
+                unreachable!("{name} was just declared as a typedef")
+            };
+            leaf(located(
+                format!(
+                    "TypedefDeclaration {name} {}",
+                    describe_type(declared_type, resolved)
+                ),
+                *location,
+            ))
+        }
+    }
+}
+
+// scopes are entered and left as the tacker does, so every name resolves the way it will when
+// the function is compiled
+fn dump_function(function: &FunctionDefinitionNode, symbols: &mut SymbolTable) -> DumpNode {
+    let FunctionDefinitionNode::Function(return_type, name, parameters, block_items, location) =
+        function;
+    let parameter_types: Vec<TypeNode> = parameters
+        .iter()
+        .map(|(parameter_type, ..)| parameter_type.clone())
+        .collect();
+    let function_type = symbols.define_function(name, return_type, &parameter_types);
+    symbols.enter_scope();
+    let mut children = vec![];
+    for ((written, parameter, parameter_location), resolved) in
+        parameters.iter().zip(&function_type.parameters)
+    {
+        symbols.declare_variable(parameter, written);
+        children.push(leaf(located(
+            format!("Parameter {parameter} {}", describe_type(written, resolved)),
+            *parameter_location,
+        )));
+    }
+    for block_item in block_items {
+        children.push(match block_item {
+            BlockItemNode::DeclarationItem(declaration) => dump_declaration(declaration, symbols),
+            BlockItemNode::StatementItem(statement) => dump_statement(statement, symbols),
+        });
+    }
+    symbols.exit_scope();
+    DumpNode {
+        label: located(
+            format!("FunctionDefinition {name} '{function_type}'"),
+            *location,
+        ),
+        children,
+    }
+}
+
+// draws the lines from each node down to its children the way clang's -ast-dump does
+fn render_children(node: &DumpNode, prefix: &str, output: &mut String) {
+    for (index, child) in node.children.iter().enumerate() {
+        let last = index + 1 == node.children.len();
+        let (connector, continuation) = if last { ("`-", "  ") } else { ("|-", "| ") };
+        writeln!(output, "{prefix}{connector}{}", child.label).unwrap();
+        render_children(child, &format!("{prefix}{continuation}"), output);
+    }
+}
+
+// the program as an indented tree, one node per line, with the resolved type of every expression
+// and the source location of every node
+pub fn dump_program(program: &ProgramNode) -> String {
+    let ProgramNode::Program(items) = program;
+    let mut symbols = SymbolTable::default();
+    symbols.enter_scope();
+    let children = items
+        .iter()
+        .map(|item| match item {
+            ExternalDeclarationNode::Declaration(declaration) => {
+                dump_declaration(declaration, &mut symbols)
+            }
+            ExternalDeclarationNode::Function(function) => dump_function(function, &mut symbols),
+        })
+        .collect();
+    let root = DumpNode {
+        label: String::from("Program"),
+        children,
+    };
+    let mut output = format!("{}\n", root.label);
+    render_children(&root, "", &mut output);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::parser::tests::parse_source;
+    use super::*;
+
+    #[test]
+    fn every_node_but_the_program_is_located() {
+        let dump = dump_program(&parse_source(
+            "struct s { int a; };\nenum { A };\nint f(int, struct s x);\nint main(void) {\n    struct s v;\n    ;\n    return A;\n}\n",
+        ));
+        let mut lines = dump.lines();
+        assert_eq!(lines.next(), Some("Program"));
+        for line in lines {
+            assert!(line.ends_with('>'), "{line} has no location");
+        }
+        assert!(dump.contains("|-Null <6:5>\n"), "{dump}");
+        assert!(dump.contains("`-Return <7:5>\n"), "{dump}");
+        assert!(
+            dump.contains("|-Declaration v 'struct s' <5:14>\n"),
+            "{dump}"
+        );
+    }
+}
@@ -1,11 +1,28 @@
-#[derive(Debug)]
+use std::fmt::{self, Display};
+
+use super::{
+    parser::{binary_operator_precedence, operator_precedence},
+    tokens::{IntegerLiteral, SourceLocation, SymbolToken},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum UnaryOperatorNode {
     Complement,
     Negate,
     Not,
 }
 
-#[derive(Debug)]
+impl Display for UnaryOperatorNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnaryOperatorNode::Complement => write!(f, "~"),
+            UnaryOperatorNode::Negate => write!(f, "-"),
+            UnaryOperatorNode::Not => write!(f, "!"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum BinaryOperatorNode {
     Add,
     Subtract,
@@ -22,25 +39,349 @@ pub enum BinaryOperatorNode {
     GreaterOrEqual,
 }
 
+impl Display for BinaryOperatorNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BinaryOperatorNode::Add => write!(f, "+"),
+            BinaryOperatorNode::Subtract => write!(f, "-"),
+            BinaryOperatorNode::Multiply => write!(f, "*"),
+            BinaryOperatorNode::Divide => write!(f, "/"),
+            BinaryOperatorNode::Remainder => write!(f, "%"),
+            BinaryOperatorNode::And => write!(f, "&&"),
+            BinaryOperatorNode::Or => write!(f, "||"),
+            BinaryOperatorNode::Equal => write!(f, "=="),
+            BinaryOperatorNode::NotEqual => write!(f, "!="),
+            BinaryOperatorNode::LessThan => write!(f, "<"),
+            BinaryOperatorNode::LessOrEqual => write!(f, "<="),
+            BinaryOperatorNode::GreaterThan => write!(f, ">"),
+            BinaryOperatorNode::GreaterOrEqual => write!(f, ">="),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeNode {
+    Int,
+    Struct(String),
+    Union(String),
+    Enum(String),
+    Typedef(String),
+    Pointer(Box<TypeNode>),
+}
+
+impl Display for TypeNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TypeNode::Int => write!(f, "int"),
+            TypeNode::Struct(tag) => write!(f, "struct {tag}"),
+            TypeNode::Union(tag) => write!(f, "union {tag}"),
+            TypeNode::Enum(tag) => write!(f, "enum {tag}"),
+            TypeNode::Typedef(name) => write!(f, "{name}"),
+            TypeNode::Pointer(inner) if matches!(**inner, TypeNode::Pointer(_)) => {
+                write!(f, "{inner}*")
+            }
+            TypeNode::Pointer(inner) => write!(f, "{inner} *"),
+        }
+    }
+}
+
+// a name declared with a type, with the stars of a pointer type written against the name
+struct Declarator<'a>(&'a TypeNode, &'a str);
+
+impl Display for Declarator<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Declarator(declared_type, name) = self;
+        match declared_type {
+            TypeNode::Pointer(_) => write!(f, "{declared_type}{name}"),
+            _ => write!(f, "{declared_type} {name}"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ExpressionNode {
-    Constant(usize),
-    Var(String),
-    Unary(UnaryOperatorNode, Box<ExpressionNode>),
-    Binary(BinaryOperatorNode, Box<ExpressionNode>, Box<ExpressionNode>),
-    Assignment(Box<ExpressionNode>, Box<ExpressionNode>),
+    Constant(IntegerLiteral, SourceLocation),
+    Var(String, SourceLocation),
+    Unary(UnaryOperatorNode, Box<ExpressionNode>, SourceLocation),
+    Binary(
+        BinaryOperatorNode,
+        Box<ExpressionNode>,
+        Box<ExpressionNode>,
+        SourceLocation,
+    ),
+    Assignment(Box<ExpressionNode>, Box<ExpressionNode>, SourceLocation),
+    Dot(Box<ExpressionNode>, String, SourceLocation),
+    Arrow(Box<ExpressionNode>, String, SourceLocation),
+    Cast(TypeNode, Box<ExpressionNode>, SourceLocation),
+    Conditional(
+        Box<ExpressionNode>,
+        Box<ExpressionNode>,
+        Box<ExpressionNode>,
+        SourceLocation,
+    ),
+    SizeOfType(TypeNode, SourceLocation),
+    SizeOfExpression(Box<ExpressionNode>, SourceLocation),
+    FunctionCall(String, Vec<ExpressionNode>, SourceLocation),
+}
+
+impl ExpressionNode {
+    pub fn location(&self) -> SourceLocation {
+        use ExpressionNode::*;
+        match self {
+            Constant(_, location)
+            | Var(_, location)
+            | Unary(_, _, location)
+            | Binary(_, _, _, location)
+            | Assignment(_, _, location)
+            | Dot(_, _, location)
+            | Arrow(_, _, location)
+            | Cast(_, _, location)
+            | Conditional(_, _, _, location)
+            | SizeOfType(_, location)
+            | SizeOfExpression(_, location)
+            | FunctionCall(_, _, location) => *location,
+        }
+    }
+}
+
+// how tightly expressions bind when printed, on the scale the parser uses for binary operators;
+// unary operators, casts and sizeof bind tighter than any of those, and member accesses and
+// primary expressions tighter still
+static UNARY_PRECEDENCE: isize = 100;
+static POSTFIX_PRECEDENCE: isize = 110;
+
+impl ExpressionNode {
+    fn precedence(&self) -> isize {
+        use ExpressionNode::*;
+        match self {
+            Assignment(..) => operator_precedence(&SymbolToken::Equal),
+            Conditional(..) => operator_precedence(&SymbolToken::Question),
+            Binary(operator, ..) => binary_operator_precedence(operator),
+            Unary(..) | Cast(..) | SizeOfType(..) | SizeOfExpression(..) => UNARY_PRECEDENCE,
+            Constant(..) | Var(..) | Dot(..) | Arrow(..) | FunctionCall(..) => POSTFIX_PRECEDENCE,
+        }
+    }
+}
+
+// an operand, put in parentheses when it binds more loosely than where it appears requires
+struct Operand<'a>(&'a ExpressionNode, isize);
+
+impl Display for Operand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Operand(expression, min_precedence) = self;
+        if expression.precedence() < *min_precedence {
+            write!(f, "({expression})")
+        } else {
+            write!(f, "{expression}")
+        }
+    }
+}
+
+// prints the expression back out as C, with only the parentheses the parser needs to build the
+// same tree again
+impl Display for ExpressionNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ExpressionNode::*;
+        let precedence = self.precedence();
+        match self {
+            Constant(literal, _) => write!(f, "{literal}"),
+            Var(name, _) => write!(f, "{name}"),
+            Unary(operator, operand, _) => {
+                let operand = format!("{}", Operand(operand, precedence));
+                // two minus signs in a row would be read as a decrement
+                if matches!(operator, UnaryOperatorNode::Negate) && operand.starts_with('-') {
+                    write!(f, "{operator}({operand})")
+                } else {
+                    write!(f, "{operator}{operand}")
+                }
+            }
+            // binary operators group to the left, so only a right operand at the same
+            // precedence needs parentheses
+            Binary(operator, left, right, _) => write!(
+                f,
+                "{} {operator} {}",
+                Operand(left, precedence),
+                Operand(right, precedence + 1)
+            ),
+            // assignments and conditionals group to the right
+            Assignment(left, right, _) => write!(
+                f,
+                "{} = {}",
+                Operand(left, precedence + 1),
+                Operand(right, precedence)
+            ),
+            Conditional(condition, then_branch, else_branch, _) => write!(
+                f,
+                "{} ? {then_branch} : {}",
+                Operand(condition, precedence + 1),
+                Operand(else_branch, precedence)
+            ),
+            Dot(inner, member, _) => write!(f, "{}.{member}", Operand(inner, POSTFIX_PRECEDENCE)),
+            Arrow(inner, member, _) => {
+                write!(f, "{}->{member}", Operand(inner, POSTFIX_PRECEDENCE))
+            }
+            Cast(target, inner, _) => write!(f, "({target}) {}", Operand(inner, precedence)),
+            SizeOfType(operand, _) => write!(f, "sizeof({operand})"),
+            // a cast straight after sizeof would be read as sizeof applied to the type
+            SizeOfExpression(inner, _) if matches!(**inner, Cast(..)) => {
+                write!(f, "sizeof ({inner})")
+            }
+            SizeOfExpression(inner, _) => write!(f, "sizeof {}", Operand(inner, precedence)),
+            FunctionCall(name, arguments, _) => {
+                let arguments: Vec<String> = arguments
+                    .iter()
+                    .map(|argument| format!("{argument}"))
+                    .collect();
+                write!(f, "{name}({})", arguments.join(", "))
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum StatementNode {
-    Return(ExpressionNode),
-    Expression(ExpressionNode),
-    Null,
+    Return(ExpressionNode, SourceLocation),
+    Expression(ExpressionNode, SourceLocation),
+    Null(SourceLocation),
+}
+
+impl StatementNode {
+    pub fn location(&self) -> SourceLocation {
+        match self {
+            StatementNode::Return(_, location)
+            | StatementNode::Expression(_, location)
+            | StatementNode::Null(location) => *location,
+        }
+    }
+}
+
+impl Display for StatementNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatementNode::Return(expression, _) => write!(f, "return {expression};"),
+            StatementNode::Expression(expression, _) => write!(f, "{expression};"),
+            StatementNode::Null(_) => write!(f, ";"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MemberDeclarationNode {
+    Member(TypeNode, String, SourceLocation),
+}
+
+impl Display for MemberDeclarationNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let MemberDeclarationNode::Member(member_type, name, _) = self;
+        write!(f, "{};", Declarator(member_type, name))
+    }
 }
 
+#[derive(Debug)]
+pub enum EnumeratorNode {
+    Enumerator(String, Option<ExpressionNode>, SourceLocation),
+}
+
+impl Display for EnumeratorNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnumeratorNode::Enumerator(name, Some(value), _) => write!(f, "{name} = {value}"),
+            EnumeratorNode::Enumerator(name, None, _) => write!(f, "{name}"),
+        }
+    }
+}
+
+// a declaration is located at the name it declares, or at the keyword of a struct, union or enum
 #[derive(Debug)]
 pub enum DeclarationNode {
-    Declaration(String, Option<ExpressionNode>),
+    Declaration(TypeNode, String, Option<ExpressionNode>, SourceLocation),
+    // a function declared without a body, with its return type and the types of its parameters
+    // along with the names they were given, if any, and where each of them was written
+    FunctionDeclaration(
+        TypeNode,
+        String,
+        Vec<(TypeNode, Option<String>, SourceLocation)>,
+        SourceLocation,
+    ),
+    StructDeclaration(String, Option<Vec<MemberDeclarationNode>>, SourceLocation),
+    UnionDeclaration(String, Option<Vec<MemberDeclarationNode>>, SourceLocation),
+    EnumDeclaration(Option<String>, Vec<EnumeratorNode>, SourceLocation),
+    TypedefDeclaration(TypeNode, String, SourceLocation),
+}
+
+fn write_aggregate(
+    f: &mut fmt::Formatter<'_>,
+    keyword: &str,
+    tag: &str,
+    members: &Option<Vec<MemberDeclarationNode>>,
+) -> fmt::Result {
+    match members {
+        Some(members) => {
+            let members: Vec<String> = members.iter().map(|member| format!("{member}")).collect();
+            write!(f, "{keyword} {tag} {{ {} }};", members.join(" "))
+        }
+        None => write!(f, "{keyword} {tag};"),
+    }
+}
+
+// a function without any parameters is written as taking void
+fn write_parameters(
+    f: &mut fmt::Formatter<'_>,
+    parameters: &[(&TypeNode, Option<&str>)],
+) -> fmt::Result {
+    let parameters: Vec<String> = parameters
+        .iter()
+        .map(|(parameter_type, name)| match name {
+            Some(name) => format!("{}", Declarator(parameter_type, name)),
+            None => format!("{parameter_type}"),
+        })
+        .collect();
+    match parameters.is_empty() {
+        true => write!(f, "void"),
+        false => write!(f, "{}", parameters.join(", ")),
+    }
+}
+
+// declarations are written on a single line each, member lists included
+impl Display for DeclarationNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeclarationNode::Declaration(declared_type, name, Some(initializer), _) => {
+                write!(f, "{} = {initializer};", Declarator(declared_type, name))
+            }
+            DeclarationNode::Declaration(declared_type, name, None, _) => {
+                write!(f, "{};", Declarator(declared_type, name))
+            }
+            DeclarationNode::FunctionDeclaration(return_type, name, parameters, _) => {
+                write!(f, "{}(", Declarator(return_type, name))?;
+                let parameters: Vec<(&TypeNode, Option<&str>)> = parameters
+                    .iter()
+                    .map(|(parameter_type, name, _)| (parameter_type, name.as_deref()))
+                    .collect();
+                write_parameters(f, &parameters)?;
+                write!(f, ");")
+            }
+            DeclarationNode::StructDeclaration(tag, members, _) => {
+                write_aggregate(f, "struct", tag, members)
+            }
+            DeclarationNode::UnionDeclaration(tag, members, _) => {
+                write_aggregate(f, "union", tag, members)
+            }
+            DeclarationNode::EnumDeclaration(tag, enumerators, _) => {
+                let enumerators: Vec<String> = enumerators
+                    .iter()
+                    .map(|enumerator| format!("{enumerator}"))
+                    .collect();
+                match tag {
+                    Some(tag) => write!(f, "enum {tag} {{ {} }};", enumerators.join(", ")),
+                    None => write!(f, "enum {{ {} }};", enumerators.join(", ")),
+                }
+            }
+            DeclarationNode::TypedefDeclaration(declared_type, name, _) => {
+                write!(f, "typedef {};", Declarator(declared_type, name))
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -49,12 +390,83 @@ pub enum BlockItemNode {
     DeclarationItem(DeclarationNode),
 }
 
+impl Display for BlockItemNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockItemNode::StatementItem(statement) => write!(f, "{statement}"),
+            BlockItemNode::DeclarationItem(declaration) => write!(f, "{declaration}"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum FunctionDefinitionNode {
-    Function(String, Vec<BlockItemNode>),
+    // the return type, the name, the type, name and location of every parameter, the body, and
+    // the location of the function's name
+    Function(
+        TypeNode,
+        String,
+        Vec<(TypeNode, String, SourceLocation)>,
+        Vec<BlockItemNode>,
+        SourceLocation,
+    ),
+}
+
+// what a program is made of at file scope, kept in the order it was written
+#[derive(Debug)]
+pub enum ExternalDeclarationNode {
+    Declaration(DeclarationNode),
+    Function(FunctionDefinitionNode),
 }
 
 #[derive(Debug)]
 pub enum ProgramNode {
-    Program(FunctionDefinitionNode),
+    Program(Vec<ExternalDeclarationNode>),
+}
+
+impl Display for FunctionDefinitionNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let FunctionDefinitionNode::Function(return_type, name, parameters, block_items, _) = self;
+        write!(f, "{}(", Declarator(return_type, name))?;
+        let parameters: Vec<(&TypeNode, Option<&str>)> = parameters
+            .iter()
+            .map(|(parameter_type, name, _)| (parameter_type, Some(name.as_str())))
+            .collect();
+        write_parameters(f, &parameters)?;
+        writeln!(f, ") {{")?;
+        for block_item in block_items {
+            writeln!(f, "    {block_item}")?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+// the program as C that parses back into the same tree, apart from constants being written in
+// decimal and struct, union and enum definitions being split out of the declarations they were
+// written in
+impl Display for ProgramNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ProgramNode::Program(items) = self;
+        // function definitions are set apart from whatever comes before and after them
+        let mut after_function = false;
+        for (index, item) in items.iter().enumerate() {
+            match item {
+                ExternalDeclarationNode::Declaration(declaration) => {
+                    if after_function {
+                        writeln!(f)?;
+                    }
+                    writeln!(f, "{declaration}")?;
+                    after_function = false;
+                }
+                ExternalDeclarationNode::Function(function) => {
+                    if index > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{function}")?;
+                    after_function = true;
+                }
+            }
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,341 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::{Debug, Write},
+};
+
+use tracing::error;
+
+use super::{asm_tree::AInstructionNode, tac_tree::*};
+
+static NO_EDGES: BTreeSet<NodeId> = BTreeSet::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum NodeId {
+    Entry,
+    Block(usize),
+    Exit,
+}
+
+// how an instruction affects the flow of control, which is all the graph needs to know about it
+pub enum ControlFlowKind<'a> {
+    Label(&'a str),
+    Jump(&'a str),
+    ConditionalJump(&'a str),
+    Return,
+    Other,
+}
+
+pub trait ControlFlow {
+    fn control_flow(&self) -> ControlFlowKind<'_>;
+}
+
+impl ControlFlow for TInstructionNode {
+    fn control_flow(&self) -> ControlFlowKind<'_> {
+        match self {
+            TInstructionNode::Label(label) => ControlFlowKind::Label(label),
+            TInstructionNode::Jump(target) => ControlFlowKind::Jump(target),
+            TInstructionNode::JumpIfZero(_, target)
+            | TInstructionNode::JumpIfNotZero(_, target) => {
+                ControlFlowKind::ConditionalJump(target)
+            }
+            TInstructionNode::Return(..) => ControlFlowKind::Return,
+            _ => ControlFlowKind::Other,
+        }
+    }
+}
+
+impl ControlFlow for AInstructionNode {
+    fn control_flow(&self) -> ControlFlowKind<'_> {
+        match self {
+            AInstructionNode::Label(label) => ControlFlowKind::Label(label),
+            AInstructionNode::Jmp(target) => ControlFlowKind::Jump(target),
+            AInstructionNode::JmpCC(_, target) => ControlFlowKind::ConditionalJump(target),
+            AInstructionNode::Ret | AInstructionNode::TailCall(..) => ControlFlowKind::Return,
+            _ => ControlFlowKind::Other,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BasicBlock<I> {
+    pub instructions: Vec<I>,
+    predecessors: BTreeSet<NodeId>,
+    successors: BTreeSet<NodeId>,
+}
+
+// blocks are keyed by their position in the original instruction list, so iterating over them
+// visits them in program order even after some have been removed
+#[derive(Debug)]
+pub struct ControlFlowGraph<I> {
+    pub blocks: BTreeMap<usize, BasicBlock<I>>,
+    entry_successors: BTreeSet<NodeId>,
+    exit_predecessors: BTreeSet<NodeId>,
+}
+
+// a new block starts at every label and after every instruction that transfers control
+fn partition_into_blocks<I: ControlFlow>(instructions: Vec<I>) -> Vec<Vec<I>> {
+    let mut blocks = vec![];
+    let mut current: Vec<I> = vec![];
+    for instruction in instructions {
+        match instruction.control_flow() {
+            ControlFlowKind::Label(_) => {
+                if !current.is_empty() {
+                    blocks.push(current);
+                }
+                current = vec![instruction];
+            }
+            ControlFlowKind::Jump(_)
+            | ControlFlowKind::ConditionalJump(_)
+            | ControlFlowKind::Return => {
+                current.push(instruction);
+                blocks.push(current);
+                current = vec![];
+            }
+            ControlFlowKind::Other => current.push(instruction),
+        }
+    }
+    if !current.is_empty() {
+        blocks.push(current);
+    }
+    blocks
+}
+
+fn label_node(label_blocks: &HashMap<String, usize>, label: &str) -> NodeId {
+    match label_blocks.get(label) {
+        Some(id) => NodeId::Block(*id),
+        None => {
+            error!("jump to undefined label {label}");
+            panic!("Semantic error!");
+        }
+    }
+}
+
+impl<I: ControlFlow> ControlFlowGraph<I> {
+    pub fn new(instructions: Vec<I>) -> ControlFlowGraph<I> {
+        let mut graph = ControlFlowGraph {
+            blocks: BTreeMap::new(),
+            entry_successors: BTreeSet::new(),
+            exit_predecessors: BTreeSet::new(),
+        };
+        let mut label_blocks: HashMap<String, usize> = HashMap::new();
+        for (id, instructions) in partition_into_blocks(instructions).into_iter().enumerate() {
+            if let Some(ControlFlowKind::Label(label)) =
+                instructions.first().map(ControlFlow::control_flow)
+            {
+                label_blocks.insert(label.to_owned(), id);
+            }
+            let block = BasicBlock {
+                instructions,
+                predecessors: BTreeSet::new(),
+                successors: BTreeSet::new(),
+            };
+            graph.blocks.insert(id, block);
+        }
+
+        let first_block = graph.next_node(NodeId::Entry);
+        graph.add_edge(NodeId::Entry, first_block);
+        let ids: Vec<usize> = graph.blocks.keys().copied().collect();
+        for id in ids {
+            let node = NodeId::Block(id);
+            let next_block = graph.next_node(node);
+            let (target, falls_through) = match graph.blocks[&id]
+                .instructions
+                .last()
+                .map(ControlFlow::control_flow)
+            {
+                // a return leaves the function, so it is an edge to the exit wherever it is
+                Some(ControlFlowKind::Return) => (Some(NodeId::Exit), false),
+                Some(ControlFlowKind::Jump(label)) => {
+                    (Some(label_node(&label_blocks, label)), false)
+                }
+                Some(ControlFlowKind::ConditionalJump(label)) => {
+                    (Some(label_node(&label_blocks, label)), true)
+                }
+                _ => (None, true),
+            };
+            if let Some(target) = target {
+                graph.add_edge(node, target);
+            }
+            if falls_through {
+                graph.add_edge(node, next_block);
+            }
+        }
+        graph
+    }
+
+    // flattens the graph back into an instruction list, in program order
+    pub fn into_instructions(self) -> Vec<I> {
+        self.blocks
+            .into_values()
+            .flat_map(|block| block.instructions)
+            .collect()
+    }
+}
+
+impl<I> ControlFlowGraph<I> {
+    // the block that follows a node in program order, which is where control falls through to
+    pub fn next_node(&self, node: NodeId) -> NodeId {
+        let next = match node {
+            NodeId::Entry => self.blocks.keys().next(),
+            NodeId::Block(id) => self.blocks.range(id + 1..).next().map(|(id, _)| id),
+            NodeId::Exit => None,
+        };
+        match next {
+            Some(id) => NodeId::Block(*id),
+            None => NodeId::Exit,
+        }
+    }
+
+    // the block before a node in program order, or the entry node for the first block
+    pub fn previous_node(&self, node: NodeId) -> NodeId {
+        let previous = match node {
+            NodeId::Entry => None,
+            NodeId::Block(id) => self.blocks.range(..id).next_back().map(|(id, _)| id),
+            NodeId::Exit => self.blocks.keys().next_back(),
+        };
+        match previous {
+            Some(id) => NodeId::Block(*id),
+            None => NodeId::Entry,
+        }
+    }
+
+    pub fn successors(&self, node: NodeId) -> &BTreeSet<NodeId> {
+        match node {
+            NodeId::Entry => &self.entry_successors,
+            NodeId::Block(id) => &self.blocks[&id].successors,
+            NodeId::Exit => &NO_EDGES,
+        }
+    }
+
+    pub fn predecessors(&self, node: NodeId) -> &BTreeSet<NodeId> {
+        match node {
+            NodeId::Entry => &NO_EDGES,
+            NodeId::Block(id) => &self.blocks[&id].predecessors,
+            NodeId::Exit => &self.exit_predecessors,
+        }
+    }
+
+    pub fn add_edge(&mut self, from: NodeId, to: NodeId) {
+        match from {
+            NodeId::Entry => self.entry_successors.insert(to),
+            NodeId::Block(id) => self.blocks.get_mut(&id).unwrap().successors.insert(to),
+            NodeId::Exit => unreachable!("the exit node has no successors"),
+        };
+        match to {
+            NodeId::Entry => unreachable!("the entry node has no predecessors"),
+            NodeId::Block(id) => self.blocks.get_mut(&id).unwrap().predecessors.insert(from),
+            NodeId::Exit => self.exit_predecessors.insert(from),
+        };
+    }
+
+    pub fn remove_edge(&mut self, from: NodeId, to: NodeId) {
+        match from {
+            NodeId::Entry => self.entry_successors.remove(&to),
+            NodeId::Block(id) => self.blocks.get_mut(&id).unwrap().successors.remove(&to),
+            NodeId::Exit => false,
+        };
+        match to {
+            NodeId::Entry => false,
+            NodeId::Block(id) => self.blocks.get_mut(&id).unwrap().predecessors.remove(&from),
+            NodeId::Exit => self.exit_predecessors.remove(&from),
+        };
+    }
+
+    // removes a block along with every edge into or out of it
+    pub fn remove_block(&mut self, id: usize) -> BasicBlock<I> {
+        let node = NodeId::Block(id);
+        let successors: Vec<NodeId> = self.successors(node).iter().copied().collect();
+        let predecessors: Vec<NodeId> = self.predecessors(node).iter().copied().collect();
+        for successor in successors {
+            self.remove_edge(node, successor);
+        }
+        for predecessor in predecessors {
+            self.remove_edge(predecessor, node);
+        }
+        self.blocks.remove(&id).unwrap()
+    }
+}
+
+impl<I: Debug> ControlFlowGraph<I> {
+    // renders the graph in graphviz's dot format, one record per basic block
+    pub fn to_dot(&self, name: &str) -> String {
+        let mut buffer = String::new();
+        let node_name = |node: &NodeId| match node {
+            NodeId::Entry => "entry".to_owned(),
+            NodeId::Block(id) => format!("block{id}"),
+            NodeId::Exit => "exit".to_owned(),
+        };
+        writeln!(buffer, "digraph \"{name}\" {{").unwrap();
+        writeln!(buffer, "    node [shape=box, fontname=monospace];").unwrap();
+        for (id, block) in &self.blocks {
+            let label: String = block
+                .instructions
+                .iter()
+                .map(|instruction| format!("{instruction:?}\\l").replace('"', "\\\""))
+                .collect();
+            writeln!(buffer, "    block{id} [label=\"{label}\"];").unwrap();
+        }
+        let sources = [NodeId::Entry]
+            .into_iter()
+            .chain(self.blocks.keys().map(|id| NodeId::Block(*id)));
+        for source in sources {
+            for target in self.successors(source) {
+                writeln!(
+                    buffer,
+                    "    {} -> {};",
+                    node_name(&source),
+                    node_name(target)
+                )
+                .unwrap();
+            }
+        }
+        writeln!(buffer, "}}").unwrap();
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(name: &str) -> TValNode {
+        TValNode::Var(name.to_owned())
+    }
+
+    #[test]
+    fn every_return_is_an_edge_to_the_exit() {
+        let instructions = vec![
+            TInstructionNode::JumpIfZero(var("x"), "else".to_owned()),
+            TInstructionNode::Return(TValNode::Constant(1), None),
+            TInstructionNode::Label("else".to_owned()),
+            TInstructionNode::Return(var("x"), None),
+        ];
+        let graph = ControlFlowGraph::new(instructions.clone());
+        assert_eq!(
+            graph.successors(NodeId::Block(0)),
+            &BTreeSet::from([NodeId::Block(1), NodeId::Block(2)])
+        );
+        // the first return is not the last block, so it must not fall through into the second
+        assert_eq!(
+            graph.successors(NodeId::Block(1)),
+            &BTreeSet::from([NodeId::Exit])
+        );
+        assert_eq!(
+            graph.predecessors(NodeId::Exit),
+            &BTreeSet::from([NodeId::Block(1), NodeId::Block(2)])
+        );
+        assert_eq!(graph.into_instructions(), instructions);
+    }
+
+    #[test]
+    fn the_last_block_falls_through_to_the_exit() {
+        let graph = ControlFlowGraph::new(vec![TInstructionNode::Copy(
+            TValNode::Constant(0),
+            var("x"),
+        )]);
+        assert_eq!(
+            graph.predecessors(NodeId::Exit),
+            &BTreeSet::from([NodeId::Block(0)])
+        );
+    }
+}
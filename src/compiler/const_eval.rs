@@ -0,0 +1,190 @@
+use std::fmt::{self, Display};
+
+use super::{
+    ast_tree::{BinaryOperatorNode, ExpressionNode, UnaryOperatorNode},
+    tokens::SourceLocation,
+    types::{IdentifierEntry, SymbolTable},
+};
+
+#[derive(Debug, Clone)]
+pub struct ConstEvalError {
+    pub message: String,
+    pub location: SourceLocation,
+}
+
+impl Display for ConstEvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+fn not_constant(message: String, location: SourceLocation) -> Result<i32, ConstEvalError> {
+    Err(ConstEvalError { message, location })
+}
+
+// signed overflow and division by zero are undefined behaviour, which makes an expression
+// non-constant, but only in operands that are actually evaluated; the unevaluated arms of
+// `&&`, `||` and `?:` merely have to be made of constant operands
+fn undefined_behaviour(
+    message: &str,
+    evaluated: bool,
+    location: SourceLocation,
+) -> Result<i32, ConstEvalError> {
+    if evaluated {
+        return not_constant(message.to_owned(), location);
+    }
+    Ok(0)
+}
+
+// folds an operator applied to constant operands, giving None when the result is undefined
+// (signed overflow or division by zero)
+pub fn fold_unary(operator: &UnaryOperatorNode, value: i32) -> Option<i32> {
+    match operator {
+        UnaryOperatorNode::Complement => Some(!value),
+        UnaryOperatorNode::Not => Some((value == 0) as i32),
+        UnaryOperatorNode::Negate => value.checked_neg(),
+    }
+}
+
+pub fn fold_binary(operator: &BinaryOperatorNode, v1: i32, v2: i32) -> Option<i32> {
+    use BinaryOperatorNode::*;
+    match operator {
+        Add => v1.checked_add(v2),
+        Subtract => v1.checked_sub(v2),
+        Multiply => v1.checked_mul(v2),
+        Divide => v1.checked_div(v2),
+        Remainder => v1.checked_rem(v2),
+        And => Some((v1 != 0 && v2 != 0) as i32),
+        Or => Some((v1 != 0 || v2 != 0) as i32),
+        Equal => Some((v1 == v2) as i32),
+        NotEqual => Some((v1 != v2) as i32),
+        LessThan => Some((v1 < v2) as i32),
+        LessOrEqual => Some((v1 <= v2) as i32),
+        GreaterThan => Some((v1 > v2) as i32),
+        GreaterOrEqual => Some((v1 >= v2) as i32),
+    }
+}
+
+fn evaluate_unary(
+    operator: &UnaryOperatorNode,
+    value: i32,
+    evaluated: bool,
+    location: SourceLocation,
+) -> Result<i32, ConstEvalError> {
+    match fold_unary(operator, value) {
+        Some(result) => Ok(result),
+        None => undefined_behaviour("negation overflows an int", evaluated, location),
+    }
+}
+
+fn evaluate_binary(
+    operator: &BinaryOperatorNode,
+    v1: i32,
+    v2: i32,
+    evaluated: bool,
+    location: SourceLocation,
+) -> Result<i32, ConstEvalError> {
+    match fold_binary(operator, v1, v2) {
+        Some(value) => Ok(value),
+        None if v2 == 0 => undefined_behaviour("division by zero", evaluated, location),
+        None => undefined_behaviour(
+            &format!("{operator:?} of {v1} and {v2} overflows an int"),
+            evaluated,
+            location,
+        ),
+    }
+}
+
+fn evaluate(
+    expression: &ExpressionNode,
+    symbols: &SymbolTable,
+    evaluated: bool,
+) -> Result<i32, ConstEvalError> {
+    match expression {
+        ExpressionNode::Constant(literal, _) => Ok(literal.as_int()),
+        ExpressionNode::Var(name, location) => match symbols.lookup(name) {
+            IdentifierEntry::Enumerator(value) => Ok(*value),
+            IdentifierEntry::Variable(_) => not_constant(
+                format!("variable {name} is not an integer constant"),
+                *location,
+            ),
+            IdentifierEntry::Typedef(_) => {
+                not_constant(format!("typedef name {name} used as a value"), *location)
+            }
+            IdentifierEntry::Function(_) => {
+                not_constant(format!("function {name} used as a value"), *location)
+            }
+        },
+        ExpressionNode::Unary(operator, operand, location) => {
+            let value = evaluate(operand, symbols, evaluated)?;
+            evaluate_unary(operator, value, evaluated, *location)
+        }
+        ExpressionNode::Binary(BinaryOperatorNode::And, op1, op2, _) => {
+            let v1 = evaluate(op1, symbols, evaluated)?;
+            let v2 = evaluate(op2, symbols, evaluated && v1 != 0)?;
+            Ok((v1 != 0 && v2 != 0) as i32)
+        }
+        ExpressionNode::Binary(BinaryOperatorNode::Or, op1, op2, _) => {
+            let v1 = evaluate(op1, symbols, evaluated)?;
+            let v2 = evaluate(op2, symbols, evaluated && v1 == 0)?;
+            Ok((v1 != 0 || v2 != 0) as i32)
+        }
+        ExpressionNode::Binary(operator, op1, op2, location) => {
+            let v1 = evaluate(op1, symbols, evaluated)?;
+            let v2 = evaluate(op2, symbols, evaluated)?;
+            evaluate_binary(operator, v1, v2, evaluated, *location)
+        }
+        ExpressionNode::Assignment(_, _, location) => not_constant(
+            "assignment is not allowed in a constant expression".to_owned(),
+            *location,
+        ),
+        ExpressionNode::FunctionCall(name, _, location) => not_constant(
+            format!("call to {name} is not allowed in a constant expression"),
+            *location,
+        ),
+        ExpressionNode::Dot(_, member, location) | ExpressionNode::Arrow(_, member, location) => {
+            not_constant(
+                format!("access to member {member} is not an integer constant"),
+                *location,
+            )
+        }
+        ExpressionNode::Cast(target, inner, _) => {
+            // every scalar type is a 32-bit int, so a cast never changes the value
+            symbols.cast_target_type(target);
+            evaluate(inner, symbols, evaluated)
+        }
+        ExpressionNode::Conditional(condition, then_branch, else_branch, _) => {
+            let condition = evaluate(condition, symbols, evaluated)?;
+            let then_value = evaluate(then_branch, symbols, evaluated && condition != 0)?;
+            let else_value = evaluate(else_branch, symbols, evaluated && condition == 0)?;
+            Ok(if condition != 0 {
+                then_value
+            } else {
+                else_value
+            })
+        }
+        ExpressionNode::SizeOfType(value_type, location) => {
+            let size = symbols.types.size_of(&symbols.lookup_type(value_type));
+            match i32::try_from(size) {
+                Ok(size) => Ok(size),
+                Err(_) => not_constant(format!("size {size} does not fit in an int"), *location),
+            }
+        }
+        // the operand of sizeof is never evaluated, so it does not have to be constant
+        ExpressionNode::SizeOfExpression(inner, location) => {
+            let size = symbols.size_of_expression(inner);
+            match i32::try_from(size) {
+                Ok(size) => Ok(size),
+                Err(_) => not_constant(format!("size {size} does not fit in an int"), *location),
+            }
+        }
+    }
+}
+
+// evaluates an integer constant expression, as needed wherever C requires a value at compile time
+pub fn evaluate_constant_expression(
+    expression: &ExpressionNode,
+    symbols: &SymbolTable,
+) -> Result<i32, ConstEvalError> {
+    evaluate(expression, symbols, true)
+}
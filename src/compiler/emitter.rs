@@ -1,25 +1,177 @@
-use super::asm_tree::{AFunctionDefinitionNode, AInstructionNode, AProgramNode};
+use super::{
+    asm_tree::{
+        AFunctionDefinitionNode, AInstructionNode, AOperandNode, AProgramNode, ARegisterNode,
+    },
+    optimizer::OptimizationOptions,
+};
 
 #[tracing::instrument(skip_all)]
-pub fn emit_program(a_program: AProgramNode, output: &mut String) {
-    let AProgramNode::Program(a_function) = a_program;
-    emit_function(a_function, output);
+pub fn emit_program(a_program: AProgramNode, options: &OptimizationOptions, output: &mut String) {
+    let AProgramNode::Program(a_functions) = a_program;
+    for a_function in a_functions {
+        emit_function(a_function, options.omit_frame_pointer, output);
+    }
     output.push_str("   .section .note.GNU-stack,\"\",@progbits\n");
 }
 
 fn emit_prologue(output: &mut String) {
-    output.push_str(&format!("    pushq\t%rbp\n"));
-    output.push_str(&format!("    movq\t%rsp, %rbp\n"));
+    output.push_str("    pushq\t%rbp\n");
+    output.push_str("    .cfi_def_cfa_offset 16\n");
+    output.push_str("    .cfi_offset %rbp, -16\n");
+    output.push_str("    movq\t%rsp, %rbp\n");
+    output.push_str("    .cfi_def_cfa_register %rbp\n");
+}
+
+// where the stack pointer is while the function runs, kept so that slots can be addressed
+// through %rsp and so that the call frame information tells unwinders where to find the return
+// address and saved registers
+struct Frame {
+    omit_frame_pointer: bool,
+    // how far %rsp is below the point %rbp would hold, 16 bytes below the canonical frame
+    // address; stack slot offsets are all measured from that point
+    depth: isize,
+    // the depth once the frame is allocated and registers are saved, which every label in the
+    // body is reached at
+    body_depth: isize,
+    in_epilogue: bool,
 }
 
-fn emit_function(a_function: AFunctionDefinitionNode, output: &mut String) {
+impl Frame {
+    fn new(instructions: &[AInstructionNode], omit_frame_pointer: bool) -> Frame {
+        // without the push of %rbp, only the return address is on the stack at entry
+        let depth = if omit_frame_pointer { -8 } else { 0 };
+        let mut body_depth = depth;
+        for instruction in instructions {
+            match instruction {
+                AInstructionNode::AllocateStack(size) => body_depth += *size as isize,
+                AInstructionNode::Push(_) => body_depth += 8,
+                _ => break,
+            }
+        }
+        Frame {
+            omit_frame_pointer,
+            depth,
+            body_depth,
+            in_epilogue: false,
+        }
+    }
+
+    fn rebase_operand(&self, operand: AOperandNode) -> AOperandNode {
+        match operand {
+            AOperandNode::Stack(offset) if self.omit_frame_pointer => {
+                AOperandNode::Memory(ARegisterNode::SP, offset + self.depth)
+            }
+            _ => operand,
+        }
+    }
+
+    fn rebase(&self, instruction: AInstructionNode) -> AInstructionNode {
+        use AInstructionNode::*;
+        match instruction {
+            Mov(src, dst) => Mov(self.rebase_operand(src), self.rebase_operand(dst)),
+            Movq(src, dst) => Movq(self.rebase_operand(src), self.rebase_operand(dst)),
+            LoadAddress(src, dst) => LoadAddress(self.rebase_operand(src), dst),
+            Unary(operator, dst) => Unary(operator, self.rebase_operand(dst)),
+            Binary(operator, src, dst) => {
+                Binary(operator, self.rebase_operand(src), self.rebase_operand(dst))
+            }
+            Cmp(op1, op2) => Cmp(self.rebase_operand(op1), self.rebase_operand(op2)),
+            Test(op1, op2) => Test(self.rebase_operand(op1), self.rebase_operand(op2)),
+            Lea(address, dst) => Lea(
+                address.map_operands(|operand| self.rebase_operand(operand.clone())),
+                self.rebase_operand(dst),
+            ),
+            Idiv(divisor) => Idiv(self.rebase_operand(divisor)),
+            SetCC(cc, dst) => SetCC(cc, self.rebase_operand(dst)),
+            other => other,
+        }
+    }
+
+    // with no frame pointer the canonical frame address moves along with %rsp
+    fn move_stack_pointer(&mut self, by: isize, output: &mut String) {
+        self.depth += by;
+        if self.omit_frame_pointer {
+            output.push_str(&format!("    .cfi_def_cfa_offset {}\n", self.depth + 16));
+        }
+    }
+
+    // the unwind state at the start of the epilogue is put back after it, for the code that
+    // follows the return
+    fn begin_epilogue(&mut self, output: &mut String) {
+        if !self.in_epilogue {
+            output.push_str("    .cfi_remember_state\n");
+            self.in_epilogue = true;
+        }
+    }
+
+    fn emit(&mut self, instruction: AInstructionNode, output: &mut String) {
+        match instruction {
+            AInstructionNode::AllocateStack(size) => {
+                emit_instructions(instruction, output);
+                self.move_stack_pointer(size as isize, output);
+            }
+            AInstructionNode::DeallocateStack(size) => {
+                emit_instructions(instruction, output);
+                self.move_stack_pointer(-(size as isize), output);
+            }
+            AInstructionNode::Push(reg) => {
+                emit_instructions(instruction, output);
+                self.move_stack_pointer(8, output);
+                if reg.is_callee_saved() && !self.in_epilogue {
+                    output.push_str(&format!(
+                        "    .cfi_offset %{}, {}\n",
+                        reg.quadword_name(),
+                        -(self.depth + 16)
+                    ));
+                }
+            }
+            AInstructionNode::Pop(reg) => {
+                self.begin_epilogue(output);
+                emit_instructions(instruction, output);
+                self.move_stack_pointer(-8, output);
+                output.push_str(&format!("    .cfi_restore %{}\n", reg.quadword_name()));
+            }
+            AInstructionNode::Ret | AInstructionNode::TailCall(..) => {
+                self.begin_epilogue(output);
+                if self.omit_frame_pointer {
+                    output.push_str(&format!("\taddq\t$({}), %rsp\n", self.depth + 8));
+                    output.push_str("    .cfi_def_cfa_offset 8\n");
+                } else {
+                    output.push_str("\tmovq\t%rbp, %rsp\n");
+                    output.push_str("\tpopq\t%rbp\n");
+                    output.push_str("    .cfi_def_cfa %rsp, 8\n");
+                }
+                emit_instructions(instruction, output);
+                output.push_str("    .cfi_restore_state\n");
+                self.in_epilogue = false;
+                self.depth = self.body_depth;
+            }
+            AInstructionNode::Label(_) => {
+                self.depth = self.body_depth;
+                emit_instructions(instruction, output);
+            }
+            _ => emit_instructions(self.rebase(instruction), output),
+        }
+    }
+}
+
+fn emit_function(
+    a_function: AFunctionDefinitionNode,
+    omit_frame_pointer: bool,
+    output: &mut String,
+) {
     let AFunctionDefinitionNode::Function(name, instructions) = a_function;
     output.push_str(&format!("   .globl {name}\n"));
     output.push_str(&format!("{name}:\n"));
-    emit_prologue(output);
+    output.push_str("    .cfi_startproc\n");
+    if !omit_frame_pointer {
+        emit_prologue(output);
+    }
+    let mut frame = Frame::new(&instructions, omit_frame_pointer);
     for a_instruction in instructions {
-        emit_instructions(a_instruction, output);
+        frame.emit(a_instruction, output);
     }
+    output.push_str("    .cfi_endproc\n");
 }
 
 #[tracing::instrument(skip(output), level = "debug")]
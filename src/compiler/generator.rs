@@ -1,9 +1,76 @@
+use std::{collections::HashMap, fmt::Write};
+
+use tracing::debug;
+
 use super::{
     asm_tree::*,
+    ast_tree::TypeNode,
     ast_tree::{BinaryOperatorNode, UnaryOperatorNode},
+    optimizer::{print_after_pass, should_run_pass, OptimizationOptions},
+    peephole::optimize_peephole,
+    regalloc::allocate_registers,
     tac_tree::{TFunctionDefinitionNode, TInstructionNode, TProgramNode, TValNode},
+    types::{FunctionType, FunctionTypes, StructLayout, TypeTable},
 };
 
+// System V classes of the eightbytes making up a structure passed or returned by value
+#[derive(Debug, Clone, PartialEq)]
+pub enum AClassNode {
+    Integer,
+    Sse,
+    Memory,
+}
+
+fn scalar_class(scalar_type: &TypeNode) -> AClassNode {
+    match scalar_type {
+        TypeNode::Int | TypeNode::Enum(_) => AClassNode::Integer,
+        TypeNode::Struct(_) | TypeNode::Union(_) => panic!("aggregates are not scalars"),
+        TypeNode::Typedef(_) | TypeNode::Pointer(_) => panic!("layouts only hold resolved types"),
+    }
+}
+
+fn flatten_scalars(
+    layout: &StructLayout,
+    types: &TypeTable,
+    base_offset: usize,
+    scalars: &mut Vec<(usize, TypeNode)>,
+) {
+    for member in &layout.members {
+        let offset = base_offset + member.offset;
+        match &member.member_type {
+            TypeNode::Struct(tag) | TypeNode::Union(tag) => {
+                flatten_scalars(types.layout_of(tag), types, offset, scalars)
+            }
+            scalar => scalars.push((offset, scalar.clone())),
+        }
+    }
+}
+
+// structures over 16 bytes live in memory, otherwise each eightbyte is SSE only when every
+// scalar inside it is floating point
+pub fn classify_struct(layout: &StructLayout, types: &TypeTable) -> Vec<AClassNode> {
+    let eightbytes = layout.size.div_ceil(8);
+    if layout.size > 16 {
+        return vec![AClassNode::Memory; eightbytes];
+    }
+
+    let mut scalars = vec![];
+    flatten_scalars(layout, types, 0, &mut scalars);
+    (0..eightbytes)
+        .map(|eightbyte| {
+            let all_sse = scalars
+                .iter()
+                .filter(|(offset, _)| offset / 8 == eightbyte)
+                .all(|(_, scalar)| scalar_class(scalar) == AClassNode::Sse);
+            if all_sse {
+                AClassNode::Sse
+            } else {
+                AClassNode::Integer
+            }
+        })
+        .collect()
+}
+
 fn generate_unary_operator(operator: UnaryOperatorNode) -> AUnaryOperatorNode {
     return match operator {
         UnaryOperatorNode::Complement => AUnaryOperatorNode::Not,
@@ -40,18 +107,409 @@ fn generate_operand(operand: TValNode) -> AOperandNode {
     };
 }
 
-fn generate_instruction(instruction: TInstructionNode) -> Vec<AInstructionNode> {
+// how a value is passed or returned: a scalar in a register of its own, a structure of the
+// given size either in one register per eightbyte or in memory
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Passing {
+    Scalar,
+    Registers(usize),
+    Memory(usize),
+}
+
+fn passing_of(value_type: &TypeNode, types: &TypeTable) -> Passing {
+    let (TypeNode::Struct(key) | TypeNode::Union(key)) = value_type else {
+        return Passing::Scalar;
+    };
+    let layout = types.layout_of(key);
+    let classes = classify_struct(layout, types);
+    if classes.contains(&AClassNode::Memory) {
+        Passing::Memory(layout.size)
+    } else if classes.contains(&AClassNode::Sse) {
+        unreachable!("there are no floating point members to classify as SSE")
+    } else {
+        Passing::Registers(layout.size)
+    }
+}
+
+// where a parameter is on entry, either in registers or starting at an eightbyte of the stack
+// arguments
+#[derive(Debug, Clone)]
+enum ParameterLocation {
+    Registers(Vec<ARegisterNode>),
+    Stack(usize),
+}
+
+// how every value crosses a call to a function of some type; a structure returned in memory
+// takes the first register for the address of the buffer to write it to, and a structure that
+// doesn't fit in the registers left goes on the stack whole, leaving them to later parameters
+#[derive(Debug)]
+struct CallingConvention {
+    returned: Passing,
+    parameters: Vec<(Passing, ParameterLocation)>,
+    register_count: usize,
+    stack_eightbytes: usize,
+}
+
+impl CallingConvention {
+    fn new(function_type: &FunctionType, types: &TypeTable) -> CallingConvention {
+        let returned = passing_of(&function_type.return_type, types);
+        let mut register_count = match returned {
+            Passing::Memory(_) => 1,
+            _ => 0,
+        };
+        let mut stack_eightbytes = 0;
+        let parameters = function_type
+            .parameters
+            .iter()
+            .map(|parameter| {
+                let passing = passing_of(parameter, types);
+                let (eightbytes, in_registers) = match passing {
+                    Passing::Scalar => (1, true),
+                    Passing::Registers(size) => (size.div_ceil(8), true),
+                    Passing::Memory(size) => (size.div_ceil(8), false),
+                };
+                let location =
+                    match ARGUMENT_REGISTERS.get(register_count..register_count + eightbytes) {
+                        Some(registers) if in_registers => {
+                            register_count += eightbytes;
+                            ParameterLocation::Registers(registers.to_vec())
+                        }
+                        _ => {
+                            stack_eightbytes += eightbytes;
+                            ParameterLocation::Stack(stack_eightbytes - eightbytes)
+                        }
+                    };
+                (passing, location)
+            })
+            .collect();
+        CallingConvention {
+            returned,
+            parameters,
+            register_count,
+            stack_eightbytes,
+        }
+    }
+}
+
+// a structure passed by value is always a whole variable
+fn aggregate_name(value: TValNode) -> String {
+    match value {
+        TValNode::Var(name) => name,
+        TValNode::Constant(_) => unreachable!("structures are never constants"),
+    }
+}
+
+// moves the eightbyte of a structure at `offset`; the last one of a structure that ends halfway
+// through it only has an int in its lower half, and moving all 8 bytes would run past the end
+fn move_eightbyte(
+    size: usize,
+    offset: usize,
+    src: AOperandNode,
+    dst: AOperandNode,
+) -> AInstructionNode {
+    if size - offset >= 8 {
+        AInstructionNode::Movq(src, dst)
+    } else {
+        AInstructionNode::Mov(src, dst)
+    }
+}
+
+// the moves of a value into the registers it is passed or returned in, or with `loading` unset,
+// out of them
+fn register_moves(
+    passing: Passing,
+    value: TValNode,
+    registers: &[ARegisterNode],
+    loading: bool,
+) -> Vec<AInstructionNode> {
+    let order = |value: AOperandNode, reg: ARegisterNode| match loading {
+        true => (value, AOperandNode::Reg(reg)),
+        false => (AOperandNode::Reg(reg), value),
+    };
+    match passing {
+        Passing::Scalar => {
+            let (src, dst) = order(generate_operand(value), registers[0]);
+            vec![AInstructionNode::Mov(src, dst)]
+        }
+        Passing::Registers(size) => {
+            let name = aggregate_name(value);
+            registers
+                .iter()
+                .zip((0..size).step_by(8))
+                .map(|(reg, offset)| {
+                    let (src, dst) = order(AOperandNode::PseudoMem(name.clone(), offset), *reg);
+                    move_eightbyte(size, offset, src, dst)
+                })
+                .collect()
+        }
+        Passing::Memory(_) => unreachable!("structures in memory aren't passed in registers"),
+    }
+}
+
+// arguments not passed in registers are pushed an eightbyte at a time, last one first; the stack
+// has to be 16-byte aligned at the call, so an odd number of pushes needs 8 bytes of padding
+fn generate_call(
+    name: String,
+    arguments: Vec<TValNode>,
+    dst: TValNode,
+    convention: &CallingConvention,
+) -> Vec<AInstructionNode> {
     use AInstructionNode::*;
-    return match instruction {
-        TInstructionNode::Return(val) => {
-            vec![
-                Mov(generate_operand(val), AOperandNode::Reg(ARegisterNode::AX)),
-                Ret,
-            ]
+    let mut instructions = vec![];
+    let padding = if convention.stack_eightbytes % 2 == 1 {
+        8
+    } else {
+        0
+    };
+    if padding != 0 {
+        instructions.push(AllocateStack(padding));
+    }
+    if let Passing::Memory(_) = convention.returned {
+        instructions.push(LoadAddress(
+            AOperandNode::PseudoMem(aggregate_name(dst.clone()), 0),
+            ARGUMENT_REGISTERS[0],
+        ));
+    }
+    let mut stack_arguments = vec![];
+    for (argument, (passing, location)) in arguments.into_iter().zip(&convention.parameters) {
+        match location {
+            ParameterLocation::Registers(registers) => {
+                instructions.append(&mut register_moves(*passing, argument, registers, true))
+            }
+            ParameterLocation::Stack(_) => stack_arguments.push((*passing, argument)),
+        }
+    }
+    let ax = AOperandNode::Reg(ARegisterNode::AX);
+    for (passing, argument) in stack_arguments.into_iter().rev() {
+        match passing {
+            Passing::Scalar => {
+                instructions.push(Mov(generate_operand(argument), ax.clone()));
+                instructions.push(Push(ARegisterNode::AX));
+            }
+            Passing::Registers(size) | Passing::Memory(size) => {
+                let argument = aggregate_name(argument);
+                for offset in (0..size).step_by(8).rev() {
+                    let src = AOperandNode::PseudoMem(argument.clone(), offset);
+                    instructions.push(move_eightbyte(size, offset, src, ax.clone()));
+                    instructions.push(Push(ARegisterNode::AX));
+                }
+            }
+        }
+    }
+    instructions.push(Call(name, convention.register_count));
+    let popped = 8 * convention.stack_eightbytes + padding;
+    if popped != 0 {
+        instructions.push(DeallocateStack(popped));
+    }
+    match convention.returned {
+        // the callee has already written it to the buffer
+        Passing::Memory(_) => (),
+        returned => {
+            instructions.append(&mut register_moves(returned, dst, &RETURN_REGISTERS, false))
+        }
+    }
+    instructions
+}
+
+// the constant a variable is offset by when an addition or subtraction is a lea
+fn constant_displacement(
+    operator: &BinaryOperatorNode,
+    src1: &TValNode,
+    src2: &TValNode,
+) -> Option<i32> {
+    match (operator, src1, src2) {
+        (BinaryOperatorNode::Add, TValNode::Var(_), TValNode::Constant(c))
+        | (BinaryOperatorNode::Add, TValNode::Constant(c), TValNode::Var(_)) => Some(*c),
+        (BinaryOperatorNode::Subtract, TValNode::Var(_), TValNode::Constant(c)) => {
+            Some(c.wrapping_neg())
+        }
+        _ => None,
+    }
+}
+
+// the values an instruction reads
+fn read_values(instruction: &TInstructionNode) -> Vec<&TValNode> {
+    match instruction {
+        TInstructionNode::Return(value, _)
+        | TInstructionNode::Unary(_, value, _)
+        | TInstructionNode::Copy(value, _)
+        | TInstructionNode::JumpIfZero(value, _)
+        | TInstructionNode::JumpIfNotZero(value, _)
+        | TInstructionNode::CopyToOffset(value, _, _) => vec![value],
+        TInstructionNode::Binary(_, src1, src2, _) => vec![src1, src2],
+        TInstructionNode::FunCall(_, arguments, _) => arguments.iter().collect(),
+        _ => vec![],
+    }
+}
+
+// instructions that only feed their result to the next instruction can be combined with it:
+// a comparison that decides a jump sets the flags for the jump without storing a boolean, and a
+// multiplication by 2, 4 or 8 added to something is the scaled index of a lea
+fn generate_combined(
+    instruction: &TInstructionNode,
+    next: &TInstructionNode,
+    read_counts: &HashMap<&TValNode, usize>,
+) -> Option<Vec<AInstructionNode>> {
+    use AInstructionNode::*;
+    let dst = match instruction {
+        TInstructionNode::Binary(_, _, _, dst) | TInstructionNode::Unary(_, _, dst) => dst,
+        _ => return None,
+    };
+    if read_counts.get(dst) != Some(&1) {
+        return None;
+    }
+    let (jump_if_zero, target) = match next {
+        TInstructionNode::JumpIfZero(value, target) if value == dst => (true, target),
+        TInstructionNode::JumpIfNotZero(value, target) if value == dst => (false, target),
+        _ => return generate_scaled_add(instruction, next),
+    };
+    let (comparison, cc) = match instruction {
+        TInstructionNode::Binary(operator, src1, src2, _) => {
+            let cc = generate_condition_operator(operator)?;
+            generate_comparison(cc, src1.clone(), src2.clone())
+        }
+        TInstructionNode::Unary(UnaryOperatorNode::Not, src, _) => {
+            (generate_zero_test(src.clone()), AConditionCode::E)
+        }
+        _ => return None,
+    };
+    // the condition holds when the stored boolean would have been 1
+    let cc = if jump_if_zero { cc.inverse() } else { cc };
+    Some(vec![comparison, JmpCC(cc, target.clone())])
+}
+
+fn generate_scaled_add(
+    instruction: &TInstructionNode,
+    next: &TInstructionNode,
+) -> Option<Vec<AInstructionNode>> {
+    let TInstructionNode::Binary(BinaryOperatorNode::Multiply, src1, src2, product) = instruction
+    else {
+        return None;
+    };
+    let (index, scale) = match (src1, src2) {
+        (index @ TValNode::Var(_), TValNode::Constant(scale @ (2 | 4 | 8)))
+        | (TValNode::Constant(scale @ (2 | 4 | 8)), index @ TValNode::Var(_)) => {
+            (index, u8::try_from(*scale).unwrap())
+        }
+        _ => return None,
+    };
+    let (base, dst) = match next {
+        TInstructionNode::Binary(BinaryOperatorNode::Add, base @ TValNode::Var(_), addend, dst)
+        | TInstructionNode::Binary(BinaryOperatorNode::Add, addend, base @ TValNode::Var(_), dst)
+            if addend == product && base != product =>
+        {
+            (base, dst)
+        }
+        _ => return None,
+    };
+    Some(vec![AInstructionNode::Lea(
+        AAddressNode::Indexed(
+            generate_operand(base.clone()),
+            generate_operand(index.clone()),
+            scale,
+        ),
+        generate_operand(dst.clone()),
+    )])
+}
+
+// the offset above %rbp of an eightbyte of the stack parameters, past the saved %rbp and the
+// return address
+fn stack_parameter_offset(eightbyte: usize) -> isize {
+    isize::try_from(16 + 8 * eightbyte).expect("too many parameters")
+}
+
+// a call whose result is returned straight away reuses this function's frame: the stack
+// arguments overwrite the ones this function was called with, which were copied out on entry,
+// and the callee returns directly to our caller
+fn generate_tail_call(
+    name: String,
+    arguments: Vec<TValNode>,
+    convention: &CallingConvention,
+) -> Vec<AInstructionNode> {
+    use AInstructionNode::*;
+    let mut instructions = vec![];
+    for (argument, (_, location)) in arguments.into_iter().zip(&convention.parameters) {
+        let destination = match location {
+            ParameterLocation::Registers(registers) => AOperandNode::Reg(registers[0]),
+            ParameterLocation::Stack(eightbyte) => {
+                AOperandNode::Stack(stack_parameter_offset(*eightbyte))
+            }
+        };
+        instructions.push(Mov(generate_operand(argument), destination));
+    }
+    instructions.push(TailCall(name, convention.register_count));
+    instructions
+}
+
+// testing a value against itself sets the flags the same way as comparing it with zero
+fn generate_zero_test(operand: TValNode) -> AInstructionNode {
+    let operand = generate_operand(operand);
+    AInstructionNode::Test(operand.clone(), operand)
+}
+
+// a comparison of `src1` with `src2` and the condition that holds when `src1 cc src2` does; cmp
+// only takes an immediate as its first operand, so a constant on the left swaps them round
+fn generate_comparison(
+    cc: AConditionCode,
+    src1: TValNode,
+    src2: TValNode,
+) -> (AInstructionNode, AConditionCode) {
+    use AInstructionNode::Cmp;
+    match (&src1, &src2) {
+        (TValNode::Var(_), TValNode::Constant(0)) => (generate_zero_test(src1), cc),
+        (TValNode::Constant(0), TValNode::Var(_)) => (generate_zero_test(src2), cc.swapped()),
+        (TValNode::Constant(_), TValNode::Var(_)) => (
+            Cmp(generate_operand(src1), generate_operand(src2)),
+            cc.swapped(),
+        ),
+        _ => (Cmp(generate_operand(src2), generate_operand(src1)), cc),
+    }
+}
+
+// where a function returning a structure in memory keeps the address it was given to write it to
+const RETURN_BUFFER: &str = "return.buffer";
+
+// a structure returned in memory is copied to the buffer the caller passed, whose address is
+// returned as well; one returned in registers has them loaded right before the ret, as only %rax
+// is seen to be read there
+fn generate_return(value: TValNode, returned: Passing) -> Vec<AInstructionNode> {
+    use AInstructionNode::*;
+    let mut instructions = match returned {
+        Passing::Memory(size) => {
+            let ax = ARegisterNode::AX;
+            let r10 = AOperandNode::Reg(ARegisterNode::R10);
+            let value = aggregate_name(value);
+            let mut instructions = vec![Movq(
+                AOperandNode::PseudoMem(RETURN_BUFFER.to_owned(), 0),
+                AOperandNode::Reg(ax),
+            )];
+            for offset in (0..size).step_by(4) {
+                instructions.push(Mov(
+                    AOperandNode::PseudoMem(value.clone(), offset),
+                    r10.clone(),
+                ));
+                let offset = isize::try_from(offset).expect("structure too large");
+                instructions.push(Mov(r10.clone(), AOperandNode::Memory(ax, offset)));
+            }
+            instructions
         }
+        returned => register_moves(returned, value, &RETURN_REGISTERS, true),
+    };
+    instructions.push(Ret);
+    instructions
+}
+
+fn generate_instruction(
+    instruction: TInstructionNode,
+    function_types: &FunctionTypes,
+    returned: Passing,
+) -> Vec<AInstructionNode> {
+    use AInstructionNode::*;
+    return match instruction {
+        TInstructionNode::Return(val, _) => generate_return(val, returned),
         TInstructionNode::Unary(op, src, dst) => match op {
             UnaryOperatorNode::Not => vec![
-                Cmp(AOperandNode::Imm(0), generate_operand(src)),
+                generate_zero_test(src),
                 Mov(AOperandNode::Imm(0), generate_operand(dst.clone())),
                 SetCC(AConditionCode::E, generate_operand(dst)),
             ],
@@ -62,11 +520,21 @@ fn generate_instruction(instruction: TInstructionNode) -> Vec<AInstructionNode>
         },
         TInstructionNode::Binary(op, src1, src2, dst) => {
             if let Some(cc) = generate_condition_operator(&op) {
+                let (comparison, cc) = generate_comparison(cc, src1, src2);
                 vec![
-                    Cmp(generate_operand(src2), generate_operand(src1)),
+                    comparison,
                     Mov(AOperandNode::Imm(0), generate_operand(dst.clone())),
                     SetCC(cc, generate_operand(dst)),
                 ]
+            } else if let Some(displacement) = constant_displacement(&op, &src1, &src2) {
+                // lea can add a constant and put the result somewhere else in one go
+                let base = match (src1, src2) {
+                    (base @ TValNode::Var(_), _) | (_, base) => base,
+                };
+                vec![Lea(
+                    AAddressNode::Offset(generate_operand(base), displacement),
+                    generate_operand(dst),
+                )]
             } else if let Some(op) = generate_binary_operator(&op) {
                 vec![
                     Mov(generate_operand(src1), generate_operand(dst.clone())),
@@ -88,93 +556,344 @@ fn generate_instruction(instruction: TInstructionNode) -> Vec<AInstructionNode>
         }
         TInstructionNode::Jump(target) => vec![AInstructionNode::Jmp(target)],
         TInstructionNode::JumpIfZero(condition, target) => vec![
-            Cmp(AOperandNode::Imm(0), generate_operand(condition)),
+            generate_zero_test(condition),
             JmpCC(AConditionCode::E, target),
         ],
         TInstructionNode::JumpIfNotZero(condition, target) => vec![
-            Cmp(AOperandNode::Imm(0), generate_operand(condition)),
+            generate_zero_test(condition),
             JmpCC(AConditionCode::NE, target),
         ],
         TInstructionNode::Copy(src, dst) => vec![Mov(generate_operand(src), generate_operand(dst))],
         TInstructionNode::Label(identifier) => vec![Label(identifier)],
-        _ => unimplemented!(),
+        TInstructionNode::CopyToOffset(src, dst, offset) => vec![Mov(
+            generate_operand(src),
+            AOperandNode::PseudoMem(dst, offset),
+        )],
+        TInstructionNode::CopyFromOffset(src, offset, dst) => vec![Mov(
+            AOperandNode::PseudoMem(src, offset),
+            generate_operand(dst),
+        )],
+        TInstructionNode::FunCall(name, arguments, dst) => {
+            let convention =
+                CallingConvention::new(&function_types.functions[&name], &function_types.types);
+            generate_call(name, arguments, dst, &convention)
+        }
+        TInstructionNode::Phi(..) => unreachable!("phis are removed when leaving SSA form"),
     };
 }
 
-fn generate_instructions(instructions: Vec<TInstructionNode>) -> Vec<AInstructionNode> {
+// whether the value in `result` is what the function returns once control reaches `position`;
+// it can be copied and jumped around on the way, as a conditional expression does, but nothing
+// else may happen before the return
+fn is_returned(
+    instructions: &[TInstructionNode],
+    labels: &HashMap<&str, usize>,
+    mut position: usize,
+    result: &TValNode,
+) -> bool {
+    let mut result = result.clone();
+    // each jump moves forwards or backwards, so bound the walk rather than track cycles
+    for _ in 0..instructions.len() {
+        match instructions.get(position) {
+            Some(TInstructionNode::Copy(src, dst)) if *src == result => result = dst.clone(),
+            Some(TInstructionNode::Label(_)) => (),
+            Some(TInstructionNode::Jump(target)) => position = labels[target.as_str()],
+            Some(TInstructionNode::Return(value, _)) => return *value == result,
+            _ => return false,
+        }
+        position += 1;
+    }
+    false
+}
+
+// with tail calls enabled, `stack_parameters` is how many eightbytes of this function's parameters
+// arrived on the stack, and so how many a tail call can pass in their place; a structure passed or
+// returned by value on either side rules a tail call out
+fn generate_instructions(
+    instructions: Vec<TInstructionNode>,
+    function_types: &FunctionTypes,
+    convention: &CallingConvention,
+    stack_parameters: Option<usize>,
+) -> Vec<AInstructionNode> {
     let mut instruction_buffer: Vec<AInstructionNode> = vec![];
-    for instruction in instructions {
-        instruction_buffer.append(&mut generate_instruction(instruction));
+    let mut read_counts: HashMap<&TValNode, usize> = HashMap::new();
+    for value in instructions.iter().flat_map(read_values) {
+        *read_counts.entry(value).or_default() += 1;
+    }
+    let labels: HashMap<&str, usize> = instructions
+        .iter()
+        .enumerate()
+        .filter_map(|(position, instruction)| match instruction {
+            TInstructionNode::Label(label) => Some((label.as_str(), position)),
+            _ => None,
+        })
+        .collect();
+    let mut position = 0;
+    while position < instructions.len() {
+        let instruction = &instructions[position];
+        position += 1;
+        if let Some(next) = instructions.get(position) {
+            if let Some(mut combined) = generate_combined(instruction, next, &read_counts) {
+                instruction_buffer.append(&mut combined);
+                position += 1;
+                continue;
+            }
+        }
+        if let (TInstructionNode::FunCall(name, arguments, dst), Some(slots)) =
+            (instruction, stack_parameters)
+        {
+            let callee_type = &function_types.functions[name];
+            let callee = CallingConvention::new(callee_type, &function_types.types);
+            let fits = !callee_type.passes_aggregates() && callee.stack_eightbytes <= slots;
+            if fits && is_returned(&instructions, &labels, position, dst) {
+                debug!("turning the call to {name} into a tail call");
+                instruction_buffer.append(&mut generate_tail_call(
+                    name.clone(),
+                    arguments.clone(),
+                    &callee,
+                ));
+                continue;
+            }
+        }
+        instruction_buffer.append(&mut generate_instruction(
+            instruction.clone(),
+            function_types,
+            convention.returned,
+        ));
     }
     return instruction_buffer;
 }
 
-fn generate_function(function: TFunctionDefinitionNode) -> AFunctionDefinitionNode {
-    let TFunctionDefinitionNode::Function(name, instructions) = function;
-    return AFunctionDefinitionNode::Function(name, generate_instructions(instructions));
+// parameters are copied out of the registers and stack slots the caller left them in, the stack
+// ones sitting above the saved %rbp and return address, along with the address of the buffer to
+// return a structure in
+fn generate_parameters(
+    parameters: Vec<String>,
+    convention: &CallingConvention,
+) -> Vec<AInstructionNode> {
+    use AInstructionNode::*;
+    let mut instructions = vec![];
+    if let Passing::Memory(_) = convention.returned {
+        instructions.push(Movq(
+            AOperandNode::Reg(ARGUMENT_REGISTERS[0]),
+            AOperandNode::PseudoMem(RETURN_BUFFER.to_owned(), 0),
+        ));
+    }
+    for (parameter, (passing, location)) in parameters.into_iter().zip(&convention.parameters) {
+        let eightbyte = match location {
+            ParameterLocation::Registers(registers) => {
+                let parameter = TValNode::Var(parameter);
+                instructions.append(&mut register_moves(*passing, parameter, registers, false));
+                continue;
+            }
+            ParameterLocation::Stack(eightbyte) => stack_parameter_offset(*eightbyte),
+        };
+        match passing {
+            Passing::Scalar => {
+                instructions.push(Mov(
+                    AOperandNode::Stack(eightbyte),
+                    AOperandNode::Pseudo(parameter),
+                ));
+            }
+            Passing::Registers(size) | Passing::Memory(size) => {
+                for offset in (0..*size).step_by(4) {
+                    instructions.push(Mov(
+                        AOperandNode::Stack(eightbyte + isize::try_from(offset).unwrap()),
+                        AOperandNode::PseudoMem(parameter.clone(), offset),
+                    ));
+                }
+            }
+        }
+    }
+    instructions
+}
+
+// aggregates whose whole size the instructions don't show: the buffer a callee returns a
+// structure in is only ever seen by its address
+fn returned_in_memory(
+    instructions: &[TInstructionNode],
+    function_types: &FunctionTypes,
+) -> HashMap<String, usize> {
+    let mut sizes = HashMap::new();
+    for instruction in instructions {
+        if let TInstructionNode::FunCall(name, _, TValNode::Var(dst)) = instruction {
+            let return_type = &function_types.functions[name].return_type;
+            if let Passing::Memory(size) = passing_of(return_type, &function_types.types) {
+                sizes.insert(dst.clone(), size);
+            }
+        }
+    }
+    sizes
+}
+
+fn generate_function(
+    function: TFunctionDefinitionNode,
+    function_types: &FunctionTypes,
+    options: &OptimizationOptions,
+) -> AFunctionDefinitionNode {
+    let TFunctionDefinitionNode::Function(name, parameters, instructions) = function;
+    let function_type = &function_types.functions[&name];
+    let convention = CallingConvention::new(function_type, &function_types.types);
+    let tail_calls = options.tail_calls && should_run_pass(options, "tail-calls", &name);
+    let stack_parameters = if tail_calls && !function_type.passes_aggregates() {
+        Some(convention.stack_eightbytes)
+    } else {
+        None
+    };
+    let aggregate_sizes = returned_in_memory(&instructions, function_types);
+    let mut a_instructions = generate_parameters(parameters, &convention);
+    a_instructions.append(&mut generate_instructions(
+        instructions,
+        function_types,
+        &convention,
+        stack_parameters,
+    ));
+    if tail_calls {
+        print_after_pass(options, "tail-calls", &name, || {
+            format_assembly(&a_instructions)
+        });
+    }
+    postprocess_assembly(
+        AFunctionDefinitionNode::Function(name, a_instructions),
+        aggregate_sizes,
+        options,
+    )
 }
 
 #[tracing::instrument(skip_all)]
-pub fn generate_program(program: TProgramNode) -> AProgramNode {
-    let TProgramNode::Program(function) = program;
-    let postprocessed = postprocess_assembly(AProgramNode::Program(generate_function(function)));
-    return postprocessed;
+pub fn generate_program(program: TProgramNode, options: &OptimizationOptions) -> AProgramNode {
+    let TProgramNode::Program(functions, function_types) = program;
+    let functions = functions
+        .into_iter()
+        .map(|function| generate_function(function, &function_types, options))
+        .collect();
+    AProgramNode::Program(functions)
+}
+
+fn instruction_operands(instruction: &AInstructionNode) -> Vec<&AOperandNode> {
+    match instruction {
+        AInstructionNode::Mov(op1, op2)
+        | AInstructionNode::Movq(op1, op2)
+        | AInstructionNode::Binary(_, op1, op2)
+        | AInstructionNode::Cmp(op1, op2)
+        | AInstructionNode::Test(op1, op2) => vec![op1, op2],
+        AInstructionNode::Lea(address, dst) => {
+            let mut operands = address.operands();
+            operands.push(dst);
+            operands
+        }
+        AInstructionNode::Unary(_, operand)
+        | AInstructionNode::Idiv(operand)
+        | AInstructionNode::SetCC(_, operand)
+        | AInstructionNode::LoadAddress(operand, _) => vec![operand],
+        _ => vec![],
+    }
 }
 
-fn map_pseudoregister_name(identifier: &String, max_allocation: &mut isize) -> isize {
-    let split: Vec<&str> = identifier.split(".").collect();
-    // assume that all pseudoregisters are identified as "temp.{n}"
-    assert_eq!(split.len(), 2);
-    let count: isize = str::parse(split[1]).expect("Could not parse pseudoregister number");
-    let current_max = *max_allocation;
-    *max_allocation = std::cmp::max(current_max, (count + 1) * 4);
-    return -((count + 1) * 4);
+// stack slots handed out to pseudoregisters, where an aggregate is given enough bytes to cover
+// the furthest offset it is accessed at, or the size it is known to have
+#[derive(Debug, Default)]
+struct StackFrame {
+    offsets: HashMap<String, isize>,
+    aggregate_sizes: HashMap<String, usize>,
+    max_allocation: isize,
 }
 
-fn pseudoreg_to_stack(operand: &AOperandNode, mut max_allocation: &mut isize) -> AOperandNode {
-    return match operand {
-        AOperandNode::Pseudo(name) => {
-            AOperandNode::Stack(map_pseudoregister_name(name, &mut max_allocation))
+impl StackFrame {
+    fn new(
+        instructions: &[AInstructionNode],
+        aggregate_sizes: HashMap<String, usize>,
+    ) -> StackFrame {
+        let mut frame = StackFrame {
+            aggregate_sizes,
+            ..Default::default()
+        };
+        for instruction in instructions {
+            let width = match instruction {
+                AInstructionNode::Movq(..) => 8,
+                _ => 4,
+            };
+            for operand in instruction_operands(instruction) {
+                if let AOperandNode::PseudoMem(name, offset) = operand {
+                    let size = frame.aggregate_sizes.entry(name.to_owned()).or_insert(0);
+                    *size = std::cmp::max(*size, offset + width);
+                }
+            }
         }
+        frame
+    }
+
+    fn slot_of(&mut self, identifier: &str) -> isize {
+        if let Some(offset) = self.offsets.get(identifier) {
+            return *offset;
+        }
+        let size = self.aggregate_sizes.get(identifier).copied().unwrap_or(4);
+        self.max_allocation += isize::try_from(size).expect("aggregate too large for stack");
+        self.offsets
+            .insert(identifier.to_owned(), -self.max_allocation);
+        -self.max_allocation
+    }
+}
+
+fn pseudoreg_to_stack(operand: &AOperandNode, frame: &mut StackFrame) -> AOperandNode {
+    match operand {
+        AOperandNode::Pseudo(name) => AOperandNode::Stack(frame.slot_of(name)),
+        AOperandNode::PseudoMem(name, offset) => AOperandNode::Stack(
+            frame.slot_of(name) + isize::try_from(*offset).expect("member offset too large"),
+        ),
         _ => operand.clone(),
-    };
+    }
 }
 
 fn replace_instruction_pseudoregs(
     instruction: &AInstructionNode,
-    mut max_allocation: &mut isize,
+    frame: &mut StackFrame,
 ) -> AInstructionNode {
     return match instruction {
         AInstructionNode::Mov(op1, op2) => AInstructionNode::Mov(
-            pseudoreg_to_stack(op1, &mut max_allocation),
-            pseudoreg_to_stack(op2, &mut max_allocation),
+            pseudoreg_to_stack(op1, frame),
+            pseudoreg_to_stack(op2, frame),
         ),
-        AInstructionNode::Unary(operator, operand) => AInstructionNode::Unary(
-            operator.clone(),
-            pseudoreg_to_stack(operand, max_allocation),
+        AInstructionNode::Movq(op1, op2) => AInstructionNode::Movq(
+            pseudoreg_to_stack(op1, frame),
+            pseudoreg_to_stack(op2, frame),
         ),
+        AInstructionNode::LoadAddress(src, dst) => {
+            AInstructionNode::LoadAddress(pseudoreg_to_stack(src, frame), *dst)
+        }
+        AInstructionNode::Unary(operator, operand) => {
+            AInstructionNode::Unary(operator.clone(), pseudoreg_to_stack(operand, frame))
+        }
         AInstructionNode::Binary(operator, operand1, operand2) => AInstructionNode::Binary(
             operator.clone(),
-            pseudoreg_to_stack(operand1, max_allocation),
-            pseudoreg_to_stack(operand2, max_allocation),
+            pseudoreg_to_stack(operand1, frame),
+            pseudoreg_to_stack(operand2, frame),
         ),
         AInstructionNode::Idiv(operand) => {
-            AInstructionNode::Idiv(pseudoreg_to_stack(operand, max_allocation))
+            AInstructionNode::Idiv(pseudoreg_to_stack(operand, frame))
         }
         AInstructionNode::Cmp(op1, op2) => AInstructionNode::Cmp(
-            pseudoreg_to_stack(op1, max_allocation),
-            pseudoreg_to_stack(op2, max_allocation),
+            pseudoreg_to_stack(op1, frame),
+            pseudoreg_to_stack(op2, frame),
+        ),
+        AInstructionNode::Test(op1, op2) => AInstructionNode::Test(
+            pseudoreg_to_stack(op1, frame),
+            pseudoreg_to_stack(op2, frame),
+        ),
+        AInstructionNode::Lea(address, dst) => AInstructionNode::Lea(
+            address.map_operands(|operand| pseudoreg_to_stack(operand, frame)),
+            pseudoreg_to_stack(dst, frame),
         ),
         AInstructionNode::SetCC(cc, operand) => {
-            AInstructionNode::SetCC(cc.clone(), pseudoreg_to_stack(operand, max_allocation))
+            AInstructionNode::SetCC(cc.clone(), pseudoreg_to_stack(operand, frame))
         }
         _ => instruction.clone(),
     };
 }
 
-fn replace_pseudoregs(instructions: &mut Vec<AInstructionNode>, mut max_allocation: &mut isize) {
+fn replace_pseudoregs(instructions: &mut Vec<AInstructionNode>, frame: &mut StackFrame) {
     for idx in 0..instructions.len() {
-        instructions[idx] = replace_instruction_pseudoregs(&instructions[idx], &mut max_allocation)
+        instructions[idx] = replace_instruction_pseudoregs(&instructions[idx], frame)
     }
 }
 
@@ -205,6 +924,35 @@ fn replace_invalid_moves(instruction: &AInstructionNode) -> Vec<AInstructionNode
             ],
             _ => vec![instruction.clone()],
         },
+        // test can't read memory twice, but a slot compared with zero can take an immediate
+        AInstructionNode::Test(Stack(src), Stack(_)) => {
+            vec![AInstructionNode::Cmp(Imm(0), Stack(*src))]
+        }
+        AInstructionNode::Test(Imm(c), Imm(_)) => vec![
+            AInstructionNode::Mov(Imm(*c), Reg(ARegisterNode::R11)),
+            AInstructionNode::Test(Reg(ARegisterNode::R11), Reg(ARegisterNode::R11)),
+        ],
+        // lea reads registers and writes one, so anything else goes through the scratch ones
+        AInstructionNode::Lea(address, dst) => {
+            let mut scratch = [ARegisterNode::R10, ARegisterNode::R11].into_iter();
+            let mut fixed = vec![];
+            let address = address.map_operands(|operand| match operand {
+                Reg(_) => operand.clone(),
+                _ => {
+                    let reg = scratch.next().unwrap();
+                    fixed.push(AInstructionNode::Mov(operand.clone(), Reg(reg)));
+                    Reg(reg)
+                }
+            });
+            match dst {
+                Reg(_) => fixed.push(AInstructionNode::Lea(address, dst.clone())),
+                _ => {
+                    fixed.push(AInstructionNode::Lea(address, Reg(ARegisterNode::R11)));
+                    fixed.push(AInstructionNode::Mov(Reg(ARegisterNode::R11), dst.clone()));
+                }
+            }
+            fixed
+        }
         AInstructionNode::Cmp(Stack(src), Stack(dst)) => {
             vec![
                 AInstructionNode::Mov(Stack(*src), Reg(ARegisterNode::R10)),
@@ -224,13 +972,33 @@ fn replace_invalid_moves(instruction: &AInstructionNode) -> Vec<AInstructionNode
 fn validate_moves(
     instructions: &mut Vec<AInstructionNode>,
     max_allocation: isize,
+    callee_saved: &[ARegisterNode],
+    omit_frame_pointer: bool,
 ) -> Vec<AInstructionNode> {
     let mut new_instructions: Vec<AInstructionNode> = vec![];
-    new_instructions.push(AInstructionNode::AllocateStack(
-        usize::try_from(max_allocation).expect("failed to convert max allocation to isize"),
-    ));
+    // callee-saved registers are pushed below the locals, and the two together keep the stack
+    // 16-byte aligned
+    let saved_size = 8 * callee_saved.len();
+    let allocation =
+        usize::try_from(max_allocation).expect("failed to convert max allocation to isize");
+    let allocation = if omit_frame_pointer {
+        // slots keep their offsets from where %rbp would point, so the 8 bytes %rbp would have
+        // been saved in are allocated too, leaving the stack misaligned by the return address
+        (allocation + 16 + saved_size).next_multiple_of(16) - saved_size - 8
+    } else {
+        (allocation + saved_size).next_multiple_of(16) - saved_size
+    };
+    new_instructions.push(AInstructionNode::AllocateStack(allocation));
+    for reg in callee_saved {
+        new_instructions.push(AInstructionNode::Push(*reg));
+    }
     for idx in 0..instructions.len() {
         let instruction = &instructions[idx];
+        if let AInstructionNode::Ret | AInstructionNode::TailCall(..) = instruction {
+            for reg in callee_saved.iter().rev() {
+                new_instructions.push(AInstructionNode::Pop(*reg));
+            }
+        }
         let replaced_moves = replace_invalid_moves(instruction);
         let mut to_insert = replaced_moves.iter();
         while let Some(new_instruction) = to_insert.next() {
@@ -241,11 +1009,233 @@ fn validate_moves(
 }
 
 #[tracing::instrument(skip_all)]
-fn postprocess_assembly(program: AProgramNode) -> AProgramNode {
-    let AProgramNode::Program(function) = program;
+fn postprocess_assembly(
+    function: AFunctionDefinitionNode,
+    aggregate_sizes: HashMap<String, usize>,
+    options: &OptimizationOptions,
+) -> AFunctionDefinitionNode {
     let AFunctionDefinitionNode::Function(name, mut instructions) = function;
-    let mut max_allocation: isize = 0;
-    replace_pseudoregs(&mut instructions, &mut max_allocation);
-    let instructions = validate_moves(&mut instructions, max_allocation);
-    return AProgramNode::Program(AFunctionDefinitionNode::Function(name, instructions));
+    let mut callee_saved = vec![];
+    if options.allocate_registers && should_run_pass(options, "allocate-registers", &name) {
+        (instructions, callee_saved) = allocate_registers(instructions, options.omit_frame_pointer);
+        print_after_pass(options, "allocate-registers", &name, || {
+            format_assembly(&instructions)
+        });
+    }
+    let mut frame = StackFrame::new(&instructions, aggregate_sizes);
+    replace_pseudoregs(&mut instructions, &mut frame);
+    let mut instructions = validate_moves(
+        &mut instructions,
+        frame.max_allocation,
+        &callee_saved,
+        options.omit_frame_pointer,
+    );
+    if options.peephole && should_run_pass(options, "peephole", &name) {
+        instructions = optimize_peephole(instructions);
+        print_after_pass(options, "peephole", &name, || {
+            format_assembly(&instructions)
+        });
+    }
+    AFunctionDefinitionNode::Function(name, instructions)
+}
+
+// a listing of assembly that may still refer to pseudoregisters, which can't be emitted and so
+// are shown as they are held
+fn format_assembly(instructions: &[AInstructionNode]) -> String {
+    let mut buffer = String::new();
+    for instruction in instructions {
+        let mut line = String::new();
+        match write!(line, "{instruction}") {
+            Ok(()) => buffer.push_str(&line),
+            Err(_) => writeln!(buffer, "\t{instruction:?}").unwrap(),
+        }
+    }
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{parser::tests::parse_source, tacker::tack_program};
+    use super::*;
+
+    #[test]
+    fn mutually_recursive_calls_in_tail_position_become_jumps() {
+        let program = tack_program(parse_source(
+            "int is_odd(int n);\n\
+             int is_even(int n) { return n == 0 ? 1 : is_odd(n - 1); }\n\
+             int is_odd(int n) { return n == 0 ? 0 : is_even(n - 1); }\n",
+        ));
+        let options = OptimizationOptions {
+            tail_calls: true,
+            ..Default::default()
+        };
+        let AProgramNode::Program(functions) = generate_program(program, &options);
+        for (function, callee) in functions.iter().zip(["is_odd", "is_even"]) {
+            let AFunctionDefinitionNode::Function(name, instructions) = function;
+            let tail_calls = instructions
+                .iter()
+                .filter(|instruction| matches!(instruction, AInstructionNode::TailCall(target, 1) if target == callee))
+                .count();
+            assert_eq!(tail_calls, 1, "{name} should jump to {callee}");
+            assert!(
+                !instructions
+                    .iter()
+                    .any(|instruction| matches!(instruction, AInstructionNode::Call(..))),
+                "{name} should not call anything"
+            );
+        }
+    }
+
+    // the 12-byte structure takes two registers, moving all of the first eightbyte and only the
+    // int in the second, and the 24-byte one is returned through a buffer whose address goes in
+    // the first register, pushing the structure along by one
+    #[test]
+    fn structures_are_passed_in_registers_and_returned_through_memory() {
+        let program = tack_program(parse_source(
+            "struct small { int a; int b; int c; };\n\
+             struct large { int a; int b; int c; int d; int e; int f; };\n\
+             int sum(struct small s);\n\
+             struct large widen(struct small s);\n\
+             int main(void) {\n\
+                 struct small s;\n\
+                 s.a = 1;\n\
+                 s.b = 2;\n\
+                 s.c = 3;\n\
+                 struct large l = widen(s);\n\
+                 return sum(s) + l.f;\n\
+             }\n",
+        ));
+        let AProgramNode::Program(functions) =
+            generate_program(program, &OptimizationOptions::default());
+        let AFunctionDefinitionNode::Function(_, instructions) = &functions[0];
+        let call_to = |callee: &str| {
+            instructions
+                .iter()
+                .position(|instruction| matches!(instruction, AInstructionNode::Call(name, _) if name == callee))
+                .unwrap()
+        };
+        let (widen, sum) = (call_to("widen"), call_to("sum"));
+        let loads = |instructions: &[AInstructionNode]| -> Vec<(bool, ARegisterNode)> {
+            instructions
+                .iter()
+                .filter_map(|instruction| match instruction {
+                    AInstructionNode::Movq(_, AOperandNode::Reg(reg)) => Some((true, *reg)),
+                    AInstructionNode::Mov(_, AOperandNode::Reg(reg)) => Some((false, *reg)),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert!(matches!(
+            instructions[widen - 3],
+            AInstructionNode::LoadAddress(_, ARegisterNode::DI)
+        ));
+        assert_eq!(
+            loads(&instructions[widen - 2..widen]),
+            [(true, ARegisterNode::SI), (false, ARegisterNode::DX)]
+        );
+        assert_eq!(
+            instructions[widen],
+            AInstructionNode::Call("widen".into(), 3)
+        );
+        assert_eq!(
+            loads(&instructions[sum - 2..sum]),
+            [(true, ARegisterNode::DI), (false, ARegisterNode::SI)]
+        );
+    }
+
+    const EIGHT_INTS: &str = "int a, int b, int c, int d, int e, int f, int x, int y";
+
+    fn generate_tail_calling(source: &str, allocate_registers: bool) -> AProgramNode {
+        let options = OptimizationOptions {
+            tail_calls: true,
+            allocate_registers,
+            peephole: allocate_registers,
+            ..Default::default()
+        };
+        generate_program(tack_program(parse_source(source)), &options)
+    }
+
+    // follows which parameter each register and stack slot holds through the moves up to the
+    // jump, starting from where the caller left them
+    fn parameters_passed_on(instructions: &[AInstructionNode]) -> Vec<&'static str> {
+        let mut held: Vec<(AOperandNode, &str)> = ARGUMENT_REGISTERS
+            .iter()
+            .map(|register| AOperandNode::Reg(*register))
+            .chain([AOperandNode::Stack(16), AOperandNode::Stack(24)])
+            .zip(["a", "b", "c", "d", "e", "f", "x", "y"])
+            .collect();
+        let holding = |held: &[(AOperandNode, &'static str)], operand: &AOperandNode| {
+            held.iter()
+                .rev()
+                .find(|(location, _)| location == operand)
+                .map_or("?", |(_, parameter)| *parameter)
+        };
+        for instruction in instructions {
+            match instruction {
+                AInstructionNode::Mov(src, dst) | AInstructionNode::Movq(src, dst) => {
+                    let parameter = holding(&held, src);
+                    held.push((dst.clone(), parameter));
+                }
+                AInstructionNode::TailCall(..) => break,
+                _ => (),
+            }
+        }
+        ARGUMENT_REGISTERS
+            .iter()
+            .map(|register| AOperandNode::Reg(*register))
+            .chain([AOperandNode::Stack(16), AOperandNode::Stack(24)])
+            .map(|location| holding(&held, &location))
+            .collect()
+    }
+
+    // the arguments for the stack go in the slots this function's own stack parameters came in,
+    // so every one of those has to be read before any is written
+    #[test]
+    fn stack_arguments_are_read_before_their_slots_are_overwritten() {
+        let source = format!(
+            "int g({EIGHT_INTS});\n\
+             int f({EIGHT_INTS}) {{ return g(b, a, c, d, e, f, y, x); }}\n"
+        );
+        for allocate_registers in [false, true] {
+            let AProgramNode::Program(functions) =
+                generate_tail_calling(&source, allocate_registers);
+            let AFunctionDefinitionNode::Function(_, instructions) = &functions[0];
+            assert!(
+                instructions.contains(&AInstructionNode::TailCall("g".into(), 6)),
+                "{instructions:?}"
+            );
+            assert_eq!(
+                parameters_passed_on(instructions),
+                ["b", "a", "c", "d", "e", "f", "y", "x"],
+                "with allocate_registers {allocate_registers}"
+            );
+        }
+    }
+
+    // a callee taking more stack arguments than the caller was given has nowhere to put them
+    #[test]
+    fn calls_needing_more_stack_than_was_passed_in_are_left_as_calls() {
+        for parameters in ["int a", "int a, int b, int c, int d, int e, int f, int x"] {
+            let AProgramNode::Program(functions) = generate_tail_calling(
+                &format!(
+                    "int g({EIGHT_INTS});\n\
+                     int f({parameters}) {{ return g(a, a, a, a, a, a, a, a); }}\n"
+                ),
+                false,
+            );
+            let AFunctionDefinitionNode::Function(_, instructions) = &functions[0];
+            assert!(
+                instructions
+                    .iter()
+                    .any(|instruction| matches!(instruction, AInstructionNode::Call(name, _) if name == "g")),
+                "f({parameters}) should call g"
+            );
+            assert!(
+                !instructions
+                    .iter()
+                    .any(|instruction| matches!(instruction, AInstructionNode::TailCall(..))),
+                "f({parameters}) should not jump to g"
+            );
+        }
+    }
 }
@@ -7,7 +7,8 @@ use super::tokens::*;
 #[derive(Debug)]
 struct Tokenizer<'a> {
     remaining_chars: &'a str,
-    tokens: Vec<Token>,
+    tokens: Vec<(Token, SourceLocation)>,
+    location: SourceLocation,
 }
 
 impl<'a> Tokenizer<'a> {
@@ -15,6 +16,7 @@ impl<'a> Tokenizer<'a> {
         Tokenizer {
             remaining_chars: src,
             tokens: Vec::new(),
+            location: SourceLocation { line: 1, column: 1 },
         }
     }
 
@@ -33,10 +35,14 @@ impl<'a> Tokenizer<'a> {
     fn handle_constant(&mut self) -> (usize, Token) {
         let matches = CONSTANT_PATTERN.find(self.remaining_chars);
         if let Some(constant) = matches {
-            (
-                constant.len(),
-                Token::Constant(String::from(constant.as_str())),
-            )
+            match IntegerLiteral::try_from(constant.as_str()) {
+                Ok(literal) => (constant.len(), Token::Constant(literal)),
+                Err(e) => {
+                    let location = self.location;
+                    error!("{location}: {e}");
+                    panic!("Syntax error!");
+                }
+            }
         } else {
             panic!("something weird happened while handling constant")
         }
@@ -76,7 +82,7 @@ impl<'a> Tokenizer<'a> {
         }
 
         let comment_end = match comment_type {
-            CommentToken::BlockComment => self.remaining_chars.find("*/"),
+            CommentToken::BlockComment => self.remaining_chars.find("*/").map(|end| end + 2),
             CommentToken::LineComment => self.remaining_chars.find("\n"),
             CommentToken::PendingComment => panic!(
                 "found PendingComment while handling comment, was expecting any other comment type"
@@ -125,7 +131,32 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
+    // a linemarker left by the preprocessor gives the line number of the line after it
+    fn handle_linemarker(&mut self) -> bool {
+        let Some(captures) = LINEMARKER_PATTERN.captures(self.remaining_chars) else {
+            return false;
+        };
+        let line: usize = str::parse(&captures[1]).expect("Could not parse linemarker line");
+        let mut len = captures[0].len();
+        if self.remaining_chars[len..].starts_with('\n') {
+            len += 1;
+        }
+        self.advance_chars(len);
+        self.location = SourceLocation { line, column: 1 };
+        true
+    }
+
     fn advance_chars(&mut self, idx: usize) -> usize {
+        if let Some(consumed) = self.remaining_chars.get(..idx) {
+            for c in consumed.chars() {
+                if c == '\n' {
+                    self.location.line += 1;
+                    self.location.column = 1;
+                } else {
+                    self.location.column += 1;
+                }
+            }
+        }
         match self.remaining_chars.get(idx..) {
             Some(new_src) => {
                 self.remaining_chars = new_src;
@@ -136,26 +167,30 @@ impl<'a> Tokenizer<'a> {
     }
 
     #[tracing::instrument]
-    fn tokenize(&mut self) -> Vec<Token> {
+    fn tokenize(&mut self) -> Vec<(Token, SourceLocation)> {
         while self.remaining_chars.len() > 0 {
+            if self.location.column == 1 && self.handle_linemarker() {
+                continue;
+            }
+            let location = self.location;
             let (advance, token) = self.next_token().unwrap();
-            self.tokens.push(token);
+            self.tokens.push((token, location));
             self.advance_chars(advance);
         }
         return self.tokens.clone();
     }
 }
 
-fn postprocess_tokens(tokens: Vec<Token>) -> Vec<Token> {
+fn postprocess_tokens(tokens: Vec<(Token, SourceLocation)>) -> Vec<(Token, SourceLocation)> {
     return tokens
         .into_iter()
-        .filter(|token| !matches!(token, Token::Symbol(SymbolToken::Whitespace)))
-        .filter(|token| !matches!(token, Token::Comment(_)))
+        .filter(|(token, _)| !matches!(token, Token::Symbol(SymbolToken::Whitespace)))
+        .filter(|(token, _)| !matches!(token, Token::Comment(_)))
         .collect();
 }
 
 #[tracing::instrument]
-pub fn lex(code: String) -> Vec<Token> {
+pub fn lex(code: String) -> Vec<(Token, SourceLocation)> {
     let tokens = Tokenizer::new(&code).tokenize();
     return postprocess_tokens(tokens);
 }
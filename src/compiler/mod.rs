@@ -1,10 +1,17 @@
 mod asm_tree;
 mod ast_tree;
+mod cfg;
+mod const_eval;
+mod peephole;
+mod regalloc;
 mod tac_tree;
-mod tokens;
+mod types;
 
+pub mod ast_dump;
 pub mod emitter;
 pub mod generator;
 pub mod lexer;
+pub mod optimizer;
 pub mod parser;
 pub mod tacker;
+pub mod tokens;
@@ -0,0 +1,85 @@
+use tracing::debug;
+
+use super::{
+    super::{
+        const_eval::{fold_binary, fold_unary},
+        tac_tree::*,
+    },
+    remarks::{describe_instruction, Remarks},
+};
+
+// a conditional jump on a constant either always jumps or never does
+fn resolve_branch(
+    instruction: &TInstructionNode,
+    taken: bool,
+    target: &str,
+    remarks: &mut Remarks,
+) -> Option<TInstructionNode> {
+    if taken {
+        remarks.passed(
+            std::slice::from_ref(instruction),
+            String::from("replaced a branch on a constant that is always taken with a jump"),
+        );
+        return Some(TInstructionNode::Jump(target.to_owned()));
+    }
+    remarks.passed(
+        std::slice::from_ref(instruction),
+        String::from("removed a branch on a constant that is never taken"),
+    );
+    None
+}
+
+// replaces operations on constants with their results; operations whose result is undefined
+// (signed overflow, division by zero) are left for the program to perform at runtime
+fn fold_instruction(
+    instruction: TInstructionNode,
+    remarks: &mut Remarks,
+) -> Option<TInstructionNode> {
+    let folded = match &instruction {
+        TInstructionNode::Unary(operator, TValNode::Constant(value), dst) => {
+            fold_unary(operator, *value).map(|result| (result, dst))
+        }
+        TInstructionNode::Binary(operator, TValNode::Constant(v1), TValNode::Constant(v2), dst) => {
+            fold_binary(operator, *v1, *v2).map(|result| (result, dst))
+        }
+        TInstructionNode::JumpIfZero(TValNode::Constant(value), target) => {
+            return resolve_branch(&instruction, *value == 0, target, remarks);
+        }
+        TInstructionNode::JumpIfNotZero(TValNode::Constant(value), target) => {
+            return resolve_branch(&instruction, *value != 0, target, remarks);
+        }
+        _ => return Some(instruction),
+    };
+    let description = describe_instruction(&instruction);
+    match folded {
+        Some((result, dst)) => {
+            remarks.passed(
+                std::slice::from_ref(&instruction),
+                format!("folded `{description}` to {result}"),
+            );
+            Some(TInstructionNode::Copy(
+                TValNode::Constant(result),
+                dst.clone(),
+            ))
+        }
+        None => {
+            remarks.missed(
+                std::slice::from_ref(&instruction),
+                format!("did not fold `{description}`, as its result is undefined"),
+            );
+            Some(instruction)
+        }
+    }
+}
+
+pub fn fold_constants(
+    instructions: Vec<TInstructionNode>,
+    remarks: &mut Remarks,
+) -> Vec<TInstructionNode> {
+    let folded: Vec<TInstructionNode> = instructions
+        .into_iter()
+        .filter_map(|instruction| fold_instruction(instruction, remarks))
+        .collect();
+    debug!("{} instructions after constant folding", folded.len());
+    folded
+}
@@ -0,0 +1,199 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use tracing::debug;
+
+use super::{
+    super::{
+        cfg::{ControlFlowGraph, NodeId},
+        tac_tree::*,
+    },
+    remarks::{describe_instruction, Remarks},
+};
+
+// each copy is a (source, destination) pair, recording that the destination holds the same
+// value as the source
+type Copies = HashSet<(TValNode, TValNode)>;
+
+fn kill(copies: &mut Copies, variable: &TValNode) {
+    copies.retain(|(src, dst)| src != variable && dst != variable);
+}
+
+fn transfer(instruction: &TInstructionNode, copies: &mut Copies) {
+    match instruction {
+        TInstructionNode::Copy(src, dst) => {
+            // copying a value back into a variable that already holds it changes nothing
+            if src == dst || copies.contains(&(dst.clone(), src.clone())) {
+                return;
+            }
+            kill(copies, dst);
+            copies.insert((src.clone(), dst.clone()));
+        }
+        TInstructionNode::Unary(_, _, dst)
+        | TInstructionNode::Binary(_, _, _, dst)
+        | TInstructionNode::CopyFromOffset(_, _, dst)
+        | TInstructionNode::FunCall(_, _, dst)
+        | TInstructionNode::Phi(dst, _) => kill(copies, dst),
+        TInstructionNode::CopyToOffset(_, name, _) => kill(copies, &TValNode::Var(name.clone())),
+        TInstructionNode::Return(..)
+        | TInstructionNode::Jump(_)
+        | TInstructionNode::JumpIfZero(..)
+        | TInstructionNode::JumpIfNotZero(..)
+        | TInstructionNode::Label(_) => (),
+    }
+}
+
+// the copies that reach a block are the ones that reach it along every incoming edge
+fn meet(
+    graph: &ControlFlowGraph<TInstructionNode>,
+    node: NodeId,
+    outgoing: &HashMap<usize, Copies>,
+    all_copies: &Copies,
+) -> Copies {
+    // nothing is known about a block that can never run
+    if graph.predecessors(node).is_empty() {
+        return Copies::new();
+    }
+    let mut incoming = all_copies.clone();
+    for predecessor in graph.predecessors(node) {
+        match predecessor {
+            NodeId::Entry => return Copies::new(),
+            NodeId::Block(id) => incoming.retain(|copy| outgoing[id].contains(copy)),
+            NodeId::Exit => unreachable!("the exit node has no successors"),
+        }
+    }
+    incoming
+}
+
+fn collect_copies(graph: &ControlFlowGraph<TInstructionNode>) -> Copies {
+    graph
+        .blocks
+        .values()
+        .flat_map(|block| &block.instructions)
+        .filter_map(|instruction| match instruction {
+            TInstructionNode::Copy(src, dst) => Some((src.clone(), dst.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+// forward dataflow analysis, giving the copies that reach the end of each block
+fn find_reaching_copies(
+    graph: &ControlFlowGraph<TInstructionNode>,
+    all_copies: &Copies,
+) -> HashMap<usize, Copies> {
+    let mut outgoing: HashMap<usize, Copies> = graph
+        .blocks
+        .keys()
+        .map(|id| (*id, all_copies.clone()))
+        .collect();
+
+    let mut worklist: VecDeque<usize> = graph.blocks.keys().copied().collect();
+    while let Some(id) = worklist.pop_front() {
+        let mut copies = meet(graph, NodeId::Block(id), &outgoing, all_copies);
+        for instruction in &graph.blocks[&id].instructions {
+            transfer(instruction, &mut copies);
+        }
+        if copies != outgoing[&id] {
+            outgoing.insert(id, copies);
+            for successor in graph.successors(NodeId::Block(id)) {
+                if let NodeId::Block(successor_id) = successor {
+                    if !worklist.contains(successor_id) {
+                        worklist.push_back(*successor_id);
+                    }
+                }
+            }
+        }
+    }
+    outgoing
+}
+
+fn replace_operand(operand: &TValNode, copies: &Copies) -> TValNode {
+    if let TValNode::Var(_) = operand {
+        for (src, dst) in copies {
+            if dst == operand {
+                return src.clone();
+            }
+        }
+    }
+    operand.clone()
+}
+
+fn rewrite_instruction(
+    instruction: &TInstructionNode,
+    copies: &Copies,
+    remarks: &mut Remarks,
+) -> Option<TInstructionNode> {
+    let replaced = match instruction {
+        TInstructionNode::Copy(src, dst) => {
+            if src == dst
+                || copies.contains(&(src.clone(), dst.clone()))
+                || copies.contains(&(dst.clone(), src.clone()))
+            {
+                debug!("removing redundant copy from {src:?} to {dst:?}");
+                remarks.passed(
+                    std::slice::from_ref(instruction),
+                    format!(
+                        "removed `{}`, as the variable already holds that value",
+                        describe_instruction(instruction)
+                    ),
+                );
+                return None;
+            }
+            TInstructionNode::Copy(replace_operand(src, copies), dst.clone())
+        }
+        TInstructionNode::Unary(operator, src, dst) => {
+            TInstructionNode::Unary(operator.clone(), replace_operand(src, copies), dst.clone())
+        }
+        TInstructionNode::Binary(operator, src1, src2, dst) => TInstructionNode::Binary(
+            operator.clone(),
+            replace_operand(src1, copies),
+            replace_operand(src2, copies),
+            dst.clone(),
+        ),
+        TInstructionNode::Return(value, location) => {
+            TInstructionNode::Return(replace_operand(value, copies), *location)
+        }
+        TInstructionNode::JumpIfZero(condition, target) => {
+            TInstructionNode::JumpIfZero(replace_operand(condition, copies), target.clone())
+        }
+        TInstructionNode::JumpIfNotZero(condition, target) => {
+            TInstructionNode::JumpIfNotZero(replace_operand(condition, copies), target.clone())
+        }
+        TInstructionNode::CopyToOffset(src, name, offset) => {
+            TInstructionNode::CopyToOffset(replace_operand(src, copies), name.clone(), *offset)
+        }
+        TInstructionNode::FunCall(name, arguments, dst) => TInstructionNode::FunCall(
+            name.clone(),
+            arguments
+                .iter()
+                .map(|argument| replace_operand(argument, copies))
+                .collect(),
+            dst.clone(),
+        ),
+        other => other.clone(),
+    };
+    Some(replaced)
+}
+
+pub fn propagate_copies(
+    instructions: Vec<TInstructionNode>,
+    remarks: &mut Remarks,
+) -> Vec<TInstructionNode> {
+    let mut graph = ControlFlowGraph::new(instructions);
+    let all_copies = collect_copies(&graph);
+    let outgoing = find_reaching_copies(&graph, &all_copies);
+    let ids: Vec<usize> = graph.blocks.keys().copied().collect();
+    for id in ids {
+        let mut copies = meet(&graph, NodeId::Block(id), &outgoing, &all_copies);
+        let block = graph.blocks.get_mut(&id).unwrap();
+        let mut rewritten = vec![];
+        for instruction in &block.instructions {
+            if let Some(replaced) = rewrite_instruction(instruction, &copies, remarks) {
+                rewritten.push(replaced);
+            }
+            transfer(instruction, &mut copies);
+        }
+        block.instructions = rewritten;
+    }
+    graph.into_instructions()
+}
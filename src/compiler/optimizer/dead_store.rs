@@ -0,0 +1,172 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use tracing::debug;
+
+use super::{
+    super::{
+        cfg::{ControlFlowGraph, NodeId},
+        tac_tree::*,
+    },
+    remarks::{describe_instruction, is_temporary, original_name, Remarks},
+};
+
+type LiveVariables = HashSet<String>;
+
+fn mark_live(live: &mut LiveVariables, value: &TValNode) {
+    if let TValNode::Var(name) = value {
+        live.insert(name.clone());
+    }
+}
+
+fn mark_dead(live: &mut LiveVariables, value: &TValNode) {
+    if let TValNode::Var(name) = value {
+        live.remove(name);
+    }
+}
+
+// steps backwards over an instruction, turning the variables live after it into those live before
+fn transfer(instruction: &TInstructionNode, live: &mut LiveVariables) {
+    match instruction {
+        TInstructionNode::Copy(src, dst) => {
+            mark_dead(live, dst);
+            mark_live(live, src);
+        }
+        TInstructionNode::Unary(_, src, dst) => {
+            mark_dead(live, dst);
+            mark_live(live, src);
+        }
+        TInstructionNode::Binary(_, src1, src2, dst) => {
+            mark_dead(live, dst);
+            mark_live(live, src1);
+            mark_live(live, src2);
+        }
+        TInstructionNode::CopyFromOffset(name, _, dst) => {
+            mark_dead(live, dst);
+            live.insert(name.clone());
+        }
+        TInstructionNode::FunCall(_, arguments, dst) => {
+            mark_dead(live, dst);
+            for argument in arguments {
+                mark_live(live, argument);
+            }
+        }
+        TInstructionNode::Phi(dst, incoming) => {
+            mark_dead(live, dst);
+            for (_, value) in incoming {
+                mark_live(live, value);
+            }
+        }
+        // writing part of an aggregate leaves the rest of it as it was
+        TInstructionNode::CopyToOffset(src, _, _) => mark_live(live, src),
+        TInstructionNode::Return(value, _)
+        | TInstructionNode::JumpIfZero(value, _)
+        | TInstructionNode::JumpIfNotZero(value, _) => mark_live(live, value),
+        TInstructionNode::Jump(_) | TInstructionNode::Label(_) => (),
+    }
+}
+
+// the variables live at the end of a block are the ones live at the start of any successor;
+// nothing outlives the function, so nothing is live at the exit
+fn meet(
+    graph: &ControlFlowGraph<TInstructionNode>,
+    node: NodeId,
+    incoming: &HashMap<usize, LiveVariables>,
+) -> LiveVariables {
+    let mut live = LiveVariables::new();
+    for successor in graph.successors(node) {
+        if let NodeId::Block(id) = successor {
+            live.extend(incoming[id].iter().cloned());
+        }
+    }
+    live
+}
+
+// backward dataflow analysis, giving the variables live at the start of each block
+fn find_live_variables(
+    graph: &ControlFlowGraph<TInstructionNode>,
+) -> HashMap<usize, LiveVariables> {
+    let mut incoming: HashMap<usize, LiveVariables> = graph
+        .blocks
+        .keys()
+        .map(|id| (*id, LiveVariables::new()))
+        .collect();
+
+    let mut worklist: VecDeque<usize> = graph.blocks.keys().rev().copied().collect();
+    while let Some(id) = worklist.pop_front() {
+        let mut live = meet(graph, NodeId::Block(id), &incoming);
+        for instruction in graph.blocks[&id].instructions.iter().rev() {
+            transfer(instruction, &mut live);
+        }
+        if live != incoming[&id] {
+            incoming.insert(id, live);
+            for predecessor in graph.predecessors(NodeId::Block(id)) {
+                if let NodeId::Block(predecessor_id) = predecessor {
+                    if !worklist.contains(predecessor_id) {
+                        worklist.push_back(*predecessor_id);
+                    }
+                }
+            }
+        }
+    }
+    incoming
+}
+
+// an instruction is a dead store if all it does is write a variable nobody reads afterwards;
+// a call might do more than produce its result, so it always stays. returns the variable
+fn dead_store<'a>(instruction: &'a TInstructionNode, live: &LiveVariables) -> Option<&'a str> {
+    let destination = match instruction {
+        TInstructionNode::Copy(_, TValNode::Var(dst))
+        | TInstructionNode::Unary(_, _, TValNode::Var(dst))
+        | TInstructionNode::Binary(_, _, _, TValNode::Var(dst))
+        | TInstructionNode::CopyFromOffset(_, _, TValNode::Var(dst))
+        | TInstructionNode::Phi(TValNode::Var(dst), _)
+        | TInstructionNode::CopyToOffset(_, dst, _) => dst,
+        _ => return None,
+    };
+    if live.contains(destination) {
+        return None;
+    }
+    Some(destination)
+}
+
+pub fn eliminate_dead_stores(
+    instructions: Vec<TInstructionNode>,
+    remarks: &mut Remarks,
+) -> Vec<TInstructionNode> {
+    let mut graph = ControlFlowGraph::new(instructions);
+    let incoming = find_live_variables(&graph);
+    let ids: Vec<usize> = graph.blocks.keys().copied().collect();
+    for id in ids {
+        let mut live = meet(&graph, NodeId::Block(id), &incoming);
+        let block = graph.blocks.get_mut(&id).unwrap();
+        let mut kept = vec![];
+        for instruction in block.instructions.drain(..).rev() {
+            if let Some(variable) = dead_store(&instruction, &live) {
+                debug!("removing dead store {instruction:?}");
+                // copies into temporaries are just how expressions pass their results along
+                let message = match (&instruction, is_temporary(variable)) {
+                    (TInstructionNode::Unary(..) | TInstructionNode::Binary(..), true) => {
+                        Some(format!(
+                            "removed `{}`, whose result is never used",
+                            describe_instruction(&instruction)
+                        ))
+                    }
+                    (_, true) => None,
+                    (_, false) => Some(format!(
+                        "removed a store to `{}`, which is never read afterwards",
+                        original_name(variable)
+                    )),
+                };
+                if let Some(message) = message {
+                    remarks.passed(std::slice::from_ref(&instruction), message);
+                }
+                continue;
+            }
+            transfer(&instruction, &mut live);
+            kept.push(instruction);
+        }
+        kept.reverse();
+        block.instructions = kept;
+    }
+    graph.into_instructions()
+}
@@ -0,0 +1,144 @@
+use std::collections::{BTreeSet, HashMap};
+
+use super::super::cfg::{ControlFlowGraph, NodeId};
+
+// the dominator tree of the nodes reachable from the entry, found with the iterative algorithm
+// of Cooper, Harvey and Kennedy
+#[derive(Debug)]
+pub struct Dominators {
+    immediate_dominators: HashMap<NodeId, NodeId>,
+    children: HashMap<NodeId, Vec<NodeId>>,
+    reverse_postorder: Vec<NodeId>,
+}
+
+pub fn reverse_postorder<I>(graph: &ControlFlowGraph<I>) -> Vec<NodeId> {
+    let mut visited: BTreeSet<NodeId> = BTreeSet::new();
+    let mut postorder = vec![];
+    // each stack entry is a node and whether its successors have already been pushed
+    let mut stack = vec![(NodeId::Entry, false)];
+    while let Some((node, expanded)) = stack.pop() {
+        if expanded {
+            postorder.push(node);
+            continue;
+        }
+        if !visited.insert(node) {
+            continue;
+        }
+        stack.push((node, true));
+        for successor in graph.successors(node).iter().rev() {
+            if !visited.contains(successor) {
+                stack.push((*successor, false));
+            }
+        }
+    }
+    postorder.reverse();
+    postorder
+}
+
+impl Dominators {
+    pub fn new<I>(graph: &ControlFlowGraph<I>) -> Dominators {
+        let reverse_postorder = reverse_postorder(graph);
+        let order: HashMap<NodeId, usize> = reverse_postorder
+            .iter()
+            .enumerate()
+            .map(|(index, node)| (*node, index))
+            .collect();
+        let mut immediate_dominators: HashMap<NodeId, NodeId> = HashMap::new();
+        immediate_dominators.insert(NodeId::Entry, NodeId::Entry);
+
+        let intersect = |idoms: &HashMap<NodeId, NodeId>, mut a: NodeId, mut b: NodeId| {
+            while a != b {
+                while order[&a] > order[&b] {
+                    a = idoms[&a];
+                }
+                while order[&b] > order[&a] {
+                    b = idoms[&b];
+                }
+            }
+            a
+        };
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for node in reverse_postorder.iter().skip(1) {
+                let mut new_idom = None;
+                for predecessor in graph.predecessors(*node) {
+                    if !immediate_dominators.contains_key(predecessor) {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => *predecessor,
+                        Some(current) => intersect(&immediate_dominators, *predecessor, current),
+                    });
+                }
+                let new_idom = new_idom.expect("reachable nodes have a processed predecessor");
+                if immediate_dominators.get(node) != Some(&new_idom) {
+                    immediate_dominators.insert(*node, new_idom);
+                    changed = true;
+                }
+            }
+        }
+
+        let mut children: HashMap<NodeId, Vec<NodeId>> = HashMap::new();
+        for node in reverse_postorder.iter().skip(1) {
+            children
+                .entry(immediate_dominators[node])
+                .or_default()
+                .push(*node);
+        }
+        Dominators {
+            immediate_dominators,
+            children,
+            reverse_postorder,
+        }
+    }
+
+    pub fn children(&self, node: NodeId) -> &[NodeId] {
+        self.children.get(&node).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn is_reachable(&self, node: NodeId) -> bool {
+        self.immediate_dominators.contains_key(&node)
+    }
+
+    // every node dominates itself; the entry, as its own immediate dominator, ends the walk up
+    pub fn dominates(&self, dominator: NodeId, mut node: NodeId) -> bool {
+        if !self.is_reachable(node) {
+            return false;
+        }
+        loop {
+            if node == dominator {
+                return true;
+            }
+            if node == NodeId::Entry {
+                return false;
+            }
+            node = self.immediate_dominators[&node];
+        }
+    }
+
+    // the dominance frontier of a node is where its dominance ends: the nodes it does not
+    // strictly dominate that have a predecessor it does dominate
+    pub fn frontiers<I>(&self, graph: &ControlFlowGraph<I>) -> HashMap<NodeId, BTreeSet<NodeId>> {
+        let mut frontiers: HashMap<NodeId, BTreeSet<NodeId>> = HashMap::new();
+        for node in &self.reverse_postorder {
+            let predecessors = graph.predecessors(*node);
+            if predecessors.len() < 2 {
+                continue;
+            }
+            let idom = self.immediate_dominators[node];
+            for predecessor in predecessors {
+                if !self.is_reachable(*predecessor) {
+                    continue;
+                }
+                let mut runner = *predecessor;
+                while runner != idom {
+                    frontiers.entry(runner).or_default().insert(*node);
+                    runner = self.immediate_dominators[&runner];
+                }
+            }
+        }
+        frontiers
+    }
+}
@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+
+use tracing::debug;
+
+use super::{
+    super::{
+        ast_tree::BinaryOperatorNode,
+        cfg::{ControlFlowGraph, NodeId},
+        tac_tree::*,
+        tacker::make_temporary_var,
+    },
+    dominators::Dominators,
+    loops::{find_loops, NaturalLoop},
+    remarks::{describe_instruction, Remarks},
+    ssa::{construct_ssa, destination, destruct_ssa},
+};
+
+// a variable that changes by the same constant on every trip around a loop: `phi` is its value
+// on entry to the header, `init` its value on the way in from the preheader and `next` the
+// value carried around to the next iteration
+struct InductionVariable {
+    phi: String,
+    init: TValNode,
+    next: String,
+    step: i32,
+}
+
+fn block_ids(natural_loop: &NaturalLoop) -> Vec<usize> {
+    natural_loop
+        .body
+        .iter()
+        .filter_map(|node| match node {
+            NodeId::Block(id) => Some(*id),
+            _ => None,
+        })
+        .collect()
+}
+
+// where a block's phis end, which is where any new phi goes
+fn end_of_phis(instructions: &[TInstructionNode]) -> usize {
+    instructions
+        .iter()
+        .position(|instruction| {
+            !matches!(
+                instruction,
+                TInstructionNode::Label(_) | TInstructionNode::Phi(..)
+            )
+        })
+        .unwrap_or(instructions.len())
+}
+
+// what `next` adds to `phi` when it is defined as `phi + c`, `c + phi` or `phi - c`
+fn step_of(instruction: &TInstructionNode, phi: &str) -> Option<i32> {
+    let is_phi = |value: &TValNode| matches!(value, TValNode::Var(name) if name == phi);
+    match instruction {
+        TInstructionNode::Binary(BinaryOperatorNode::Add, src, TValNode::Constant(c), _)
+        | TInstructionNode::Binary(BinaryOperatorNode::Add, TValNode::Constant(c), src, _)
+            if is_phi(src) =>
+        {
+            Some(*c)
+        }
+        TInstructionNode::Binary(BinaryOperatorNode::Subtract, src, TValNode::Constant(c), _)
+            if is_phi(src) =>
+        {
+            Some(c.wrapping_neg())
+        }
+        _ => None,
+    }
+}
+
+// the phis in the header taking one value from the preheader and the same variable, stepped by
+// a constant inside the loop, from every edge that comes back around
+fn find_induction_variables(
+    graph: &ControlFlowGraph<TInstructionNode>,
+    natural_loop: &NaturalLoop,
+    preheader: usize,
+    definitions: &HashMap<String, TInstructionNode>,
+) -> Vec<InductionVariable> {
+    let NodeId::Block(header) = natural_loop.header else {
+        return vec![];
+    };
+    let mut variables = vec![];
+    for instruction in &graph.blocks[&header].instructions {
+        let TInstructionNode::Phi(TValNode::Var(phi), incoming) = instruction else {
+            continue;
+        };
+        let mut init = None;
+        let mut next = None;
+        let mut consistent = true;
+        for (predecessor, value) in incoming {
+            if *predecessor == NodeId::Block(preheader) {
+                init = Some(value.clone());
+                continue;
+            }
+            match (value, &next) {
+                (TValNode::Var(name), None) => next = Some(name.clone()),
+                (TValNode::Var(name), Some(previous)) if name == previous => {}
+                _ => consistent = false,
+            }
+        }
+        let (Some(init), Some(next), true) = (init, next, consistent) else {
+            continue;
+        };
+        let Some(step) = definitions.get(&next).and_then(|def| step_of(def, phi)) else {
+            continue;
+        };
+        variables.push(InductionVariable {
+            phi: phi.clone(),
+            init,
+            next,
+            step,
+        });
+    }
+    variables
+}
+
+// the constant an induction variable is multiplied by, if that is what the instruction does
+fn scale_of(instruction: &TInstructionNode, phi: &str) -> Option<i32> {
+    let is_phi = |value: &TValNode| matches!(value, TValNode::Var(name) if name == phi);
+    match instruction {
+        TInstructionNode::Binary(BinaryOperatorNode::Multiply, src, TValNode::Constant(k), _)
+        | TInstructionNode::Binary(BinaryOperatorNode::Multiply, TValNode::Constant(k), src, _)
+            if is_phi(src) =>
+        {
+            Some(*k)
+        }
+        _ => None,
+    }
+}
+
+// replaces each multiplication of an induction variable by a constant inside a loop with a
+// variable of its own, started at the initial product in the preheader and advanced by the
+// step times the constant alongside the induction variable; the arithmetic wraps the same way
+// either way, so the two stay equal however far the loop runs
+pub fn reduce_strength(
+    instructions: Vec<TInstructionNode>,
+    remarks: &mut Remarks,
+) -> Vec<TInstructionNode> {
+    let mut graph = construct_ssa(instructions.clone());
+    let dominators = Dominators::new(&graph);
+    let mut loops = find_loops(&graph, &dominators);
+    if loops.is_empty() {
+        return instructions;
+    }
+    loops.sort_by_key(|natural_loop| natural_loop.body.len());
+
+    for natural_loop in loops {
+        let NodeId::Block(header) = natural_loop.header else {
+            continue;
+        };
+        let header_instructions = graph.blocks[&header].instructions.clone();
+        let Some(preheader) = natural_loop.preheader else {
+            debug!(
+                "not reducing strength in the loop at {:?}, which has no preheader",
+                natural_loop.header
+            );
+            remarks.missed(
+                &header_instructions,
+                String::from("did not reduce strength in the loop, which has no preheader"),
+            );
+            continue;
+        };
+        let blocks = block_ids(&natural_loop);
+        let mut definitions: HashMap<String, TInstructionNode> = HashMap::new();
+        for id in &blocks {
+            for instruction in &graph.blocks[id].instructions {
+                if let Some(TValNode::Var(name)) = destination(instruction) {
+                    definitions.insert(name.clone(), instruction.clone());
+                }
+            }
+        }
+        let latches: Vec<NodeId> = graph
+            .predecessors(natural_loop.header)
+            .iter()
+            .filter(|predecessor| natural_loop.body.contains(predecessor))
+            .copied()
+            .collect();
+
+        let mut initial_products = vec![];
+        let mut reduced = 0;
+        for variable in find_induction_variables(&graph, &natural_loop, preheader, &definitions) {
+            let mut products = vec![];
+            for id in &blocks {
+                for (index, instruction) in graph.blocks[id].instructions.iter().enumerate() {
+                    if let Some(scale) = scale_of(instruction, &variable.phi) {
+                        products.push((*id, index, scale));
+                    }
+                }
+            }
+
+            // every product is rewritten in place before anything is inserted, so the positions
+            // found above still hold
+            let mut phis = vec![];
+            let mut steps = vec![];
+            for (id, index, scale) in products {
+                let instruction = &mut graph.blocks.get_mut(&id).unwrap().instructions[index];
+                debug!(
+                    "reducing {instruction:?} in the loop at {:?}",
+                    natural_loop.header
+                );
+                remarks.passed(
+                    std::slice::from_ref(instruction),
+                    format!(
+                        "replaced `{}` with an addition carried around the loop",
+                        describe_instruction(instruction)
+                    ),
+                );
+                let initial = TValNode::Var(make_temporary_var());
+                let current = TValNode::Var(make_temporary_var());
+                let next = TValNode::Var(make_temporary_var());
+                let dst = destination(instruction).unwrap().clone();
+                *instruction = TInstructionNode::Copy(current.clone(), dst);
+
+                initial_products.push(TInstructionNode::Binary(
+                    BinaryOperatorNode::Multiply,
+                    variable.init.clone(),
+                    TValNode::Constant(scale),
+                    initial.clone(),
+                ));
+                let mut incoming = vec![(NodeId::Block(preheader), initial)];
+                incoming.extend(latches.iter().map(|latch| (*latch, next.clone())));
+                phis.push(TInstructionNode::Phi(current.clone(), incoming));
+                steps.push(TInstructionNode::Binary(
+                    BinaryOperatorNode::Add,
+                    current,
+                    TValNode::Constant(variable.step.wrapping_mul(scale)),
+                    next,
+                ));
+                reduced += 1;
+            }
+
+            let header_instructions = &mut graph.blocks.get_mut(&header).unwrap().instructions;
+            let position = end_of_phis(header_instructions);
+            header_instructions.splice(position..position, phis);
+            insert_after_definition(&mut graph, &blocks, &variable.next, steps);
+        }
+        if reduced == 0 {
+            remarks.missed(
+                &header_instructions,
+                String::from(
+                    "found no multiplication of an induction variable by a constant in the loop",
+                ),
+            );
+        }
+
+        // the preheader only leads to the header, so anything it ends with is a jump there
+        let preheader_instructions = &mut graph.blocks.get_mut(&preheader).unwrap().instructions;
+        let jump = match preheader_instructions.last() {
+            Some(TInstructionNode::Jump(_)) => preheader_instructions.pop(),
+            _ => None,
+        };
+        preheader_instructions.extend(initial_products);
+        preheader_instructions.extend(jump);
+    }
+    destruct_ssa(graph)
+}
+
+// the new variables are advanced right where the induction variable is, which dominates every edge
+// back to the header
+fn insert_after_definition(
+    graph: &mut ControlFlowGraph<TInstructionNode>,
+    blocks: &[usize],
+    name: &str,
+    added: Vec<TInstructionNode>,
+) {
+    for id in blocks {
+        let instructions = &mut graph.blocks.get_mut(id).unwrap().instructions;
+        let position = instructions.iter().position(
+            |candidate| matches!(destination(candidate), Some(TValNode::Var(dst)) if dst == name),
+        );
+        if let Some(position) = position {
+            instructions.splice(position + 1..position + 1, added);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::interpreter::run, *};
+
+    fn var(name: &str) -> TValNode {
+        TValNode::Var(name.to_owned())
+    }
+
+    fn binary(
+        operator: BinaryOperatorNode,
+        src1: TValNode,
+        src2: TValNode,
+        dst: &str,
+    ) -> TInstructionNode {
+        TInstructionNode::Binary(operator, src1, src2, var(dst))
+    }
+
+    // `for (i = start; i < n; i = step(i)) sum = sum + multiply(i);`
+    fn summing_loop(
+        start: i32,
+        multiply: TInstructionNode,
+        step: TInstructionNode,
+    ) -> Vec<TInstructionNode> {
+        vec![
+            TInstructionNode::Copy(TValNode::Constant(start), var("i")),
+            TInstructionNode::Copy(TValNode::Constant(0), var("sum")),
+            TInstructionNode::Label("loop".to_owned()),
+            binary(BinaryOperatorNode::LessThan, var("i"), var("n"), "t"),
+            TInstructionNode::JumpIfZero(var("t"), "end".to_owned()),
+            multiply,
+            binary(BinaryOperatorNode::Add, var("sum"), var("j"), "sum"),
+            step,
+            TInstructionNode::Jump("loop".to_owned()),
+            TInstructionNode::Label("end".to_owned()),
+            TInstructionNode::Return(var("sum"), None),
+        ]
+    }
+
+    fn multiplies_in_loop(instructions: &[TInstructionNode]) -> usize {
+        let header = instructions
+            .iter()
+            .position(|instruction| *instruction == TInstructionNode::Label("loop".to_owned()))
+            .unwrap();
+        instructions[header..]
+            .iter()
+            .filter(|instruction| {
+                matches!(
+                    instruction,
+                    TInstructionNode::Binary(BinaryOperatorNode::Multiply, ..)
+                )
+            })
+            .count()
+    }
+
+    fn assert_reduced(instructions: Vec<TInstructionNode>, inputs: &[i32]) {
+        let mut remarks = Remarks::default();
+        let reduced = reduce_strength(instructions.clone(), &mut remarks);
+        assert_eq!(multiplies_in_loop(&instructions), 1);
+        assert_eq!(multiplies_in_loop(&reduced), 0, "{reduced:#?}");
+        for n in inputs {
+            assert_eq!(
+                run(&reduced, &[("n", *n)]),
+                run(&instructions, &[("n", *n)]),
+                "n = {n}"
+            );
+        }
+    }
+
+    #[test]
+    fn multiplying_the_counter_becomes_an_addition() {
+        let instructions = summing_loop(
+            0,
+            binary(
+                BinaryOperatorNode::Multiply,
+                var("i"),
+                TValNode::Constant(4),
+                "j",
+            ),
+            binary(
+                BinaryOperatorNode::Add,
+                var("i"),
+                TValNode::Constant(1),
+                "i",
+            ),
+        );
+        assert_reduced(instructions, &[-3, 0, 1, 2, 10, 1000]);
+    }
+
+    #[test]
+    fn the_constant_and_the_step_can_be_either_way_round_or_negative() {
+        let instructions = summing_loop(
+            -50,
+            binary(
+                BinaryOperatorNode::Multiply,
+                TValNode::Constant(-3),
+                var("i"),
+                "j",
+            ),
+            binary(
+                BinaryOperatorNode::Add,
+                TValNode::Constant(7),
+                var("i"),
+                "i",
+            ),
+        );
+        assert_reduced(instructions, &[-50, -49, 0, 13, 200]);
+
+        // counting down past n, which never happens with i < n, so the loop checks i > n instead
+        let mut instructions = summing_loop(
+            40,
+            binary(
+                BinaryOperatorNode::Multiply,
+                var("i"),
+                TValNode::Constant(5),
+                "j",
+            ),
+            binary(
+                BinaryOperatorNode::Subtract,
+                var("i"),
+                TValNode::Constant(3),
+                "i",
+            ),
+        );
+        instructions[3] = binary(BinaryOperatorNode::GreaterThan, var("i"), var("n"), "t");
+        assert_reduced(instructions, &[-20, 0, 39, 40, 41]);
+    }
+
+    #[test]
+    fn products_that_overflow_wrap_the_same_way() {
+        let instructions = summing_loop(
+            0,
+            binary(
+                BinaryOperatorNode::Multiply,
+                var("i"),
+                TValNode::Constant(1_000_000_007),
+                "j",
+            ),
+            binary(
+                BinaryOperatorNode::Add,
+                var("i"),
+                TValNode::Constant(3),
+                "i",
+            ),
+        );
+        assert_reduced(instructions, &[1, 10, 100, 1000]);
+    }
+
+    #[test]
+    fn a_variable_stepped_by_a_variable_is_left_alone() {
+        let instructions = summing_loop(
+            0,
+            binary(
+                BinaryOperatorNode::Multiply,
+                var("i"),
+                TValNode::Constant(4),
+                "j",
+            ),
+            binary(BinaryOperatorNode::Add, var("i"), var("n"), "i"),
+        );
+        let mut remarks = Remarks::default();
+        let reduced = reduce_strength(instructions.clone(), &mut remarks);
+        assert_eq!(multiplies_in_loop(&reduced), 1);
+        assert_eq!(run(&reduced, &[("n", 5)]), run(&instructions, &[("n", 5)]));
+    }
+}
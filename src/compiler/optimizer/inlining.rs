@@ -0,0 +1,329 @@
+use std::collections::{HashMap, HashSet};
+
+use tracing::debug;
+
+use super::{
+    super::{
+        tac_tree::*,
+        tacker::{make_label_name, make_temporary_var, record_source_location},
+        types::FunctionTypes,
+    },
+    instrumentation::should_run_pass,
+    remarks::{is_temporary, locate_name, original_name, Remarks},
+    OptimizationOptions,
+};
+
+fn callees(instructions: &[TInstructionNode]) -> HashSet<&str> {
+    instructions
+        .iter()
+        .filter_map(|instruction| match instruction {
+            TInstructionNode::FunCall(name, _, _) => Some(name.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+// the functions that can end up calling themselves, directly or through others; inlining one of
+// those would never finish
+fn recursive_functions(call_graph: &HashMap<&str, HashSet<&str>>) -> HashSet<String> {
+    let mut recursive = HashSet::new();
+    for function in call_graph.keys() {
+        let mut visited: HashSet<&str> = HashSet::new();
+        let mut worklist: Vec<&str> = call_graph[function].iter().copied().collect();
+        while let Some(callee) = worklist.pop() {
+            if callee == *function {
+                recursive.insert(function.to_string());
+                break;
+            }
+            if visited.insert(callee) {
+                worklist.extend(call_graph.get(callee).into_iter().flatten().copied());
+            }
+        }
+    }
+    recursive
+}
+
+// every function after the ones it calls, unless they call each other, so a callee has had its
+// own calls inlined by the time it is copied into its callers
+fn bottom_up_order(call_graph: &HashMap<&str, HashSet<&str>>, names: &[&str]) -> Vec<String> {
+    let mut visited: HashSet<&str> = HashSet::new();
+    let mut order = vec![];
+    for name in names {
+        // each stack entry is a function and whether its callees have already been pushed
+        let mut stack = vec![(*name, false)];
+        while let Some((function, expanded)) = stack.pop() {
+            if expanded {
+                order.push(function.to_owned());
+                continue;
+            }
+            if !visited.insert(function) {
+                continue;
+            }
+            stack.push((function, true));
+            let mut callees: Vec<&str> = call_graph[function].iter().copied().collect();
+            callees.sort();
+            for callee in callees {
+                // functions only declared here are defined elsewhere and have no calls to follow
+                if !visited.contains(callee) && call_graph.contains_key(callee) {
+                    stack.push((callee, false));
+                }
+            }
+        }
+    }
+    order
+}
+
+// labels don't survive to the generated code, so they aren't counted
+fn inline_cost(instructions: &[TInstructionNode]) -> usize {
+    instructions
+        .iter()
+        .filter(|instruction| !matches!(instruction, TInstructionNode::Label(_)))
+        .count()
+}
+
+// gives every variable and label in a copy of a function body a name of its own, so that copies
+// inlined into the same caller stay apart from each other and from the caller
+#[derive(Default)]
+struct Renamer {
+    variables: HashMap<String, String>,
+    labels: HashMap<String, String>,
+}
+
+// a renamed variable or label stands for the same source code as the one it copies
+fn inherit_source_location(original: &str, renamed: String) -> String {
+    if let Some(location) = locate_name(original) {
+        record_source_location(&renamed, location);
+    }
+    renamed
+}
+
+impl Renamer {
+    // a copy of a temporary is just another temporary, but a copy of one of the callee's own
+    // variables keeps its name in front, so that remarks about the inlined code still use it
+    fn variable(&mut self, name: &str) -> String {
+        self.variables
+            .entry(name.to_owned())
+            .or_insert_with(|| {
+                let temporary = make_temporary_var();
+                let renamed = match is_temporary(name) {
+                    true => temporary,
+                    false => format!(
+                        "{}.inlined.{}",
+                        original_name(name),
+                        &temporary["tmp.".len()..]
+                    ),
+                };
+                inherit_source_location(name, renamed)
+            })
+            .clone()
+    }
+
+    fn value(&mut self, value: &TValNode) -> TValNode {
+        match value {
+            TValNode::Var(name) => TValNode::Var(self.variable(name)),
+            TValNode::Constant(_) => value.clone(),
+        }
+    }
+
+    fn label(&mut self, label: &str) -> String {
+        self.labels
+            .entry(label.to_owned())
+            .or_insert_with(|| inherit_source_location(label, make_label_name(label, "")))
+            .clone()
+    }
+}
+
+// the callee's body with its parameters assigned from the arguments, and each return turned into
+// a copy to the call's destination followed by a jump past the end of the body
+fn inline_call(
+    parameters: &[String],
+    body: &[TInstructionNode],
+    arguments: &[TValNode],
+    dst: &TValNode,
+) -> Vec<TInstructionNode> {
+    let mut renamer = Renamer::default();
+    let return_label = make_label_name("inline_return", "");
+    let mut inlined = vec![];
+    for (parameter, argument) in parameters.iter().zip(arguments) {
+        inlined.push(TInstructionNode::Copy(
+            argument.clone(),
+            TValNode::Var(renamer.variable(parameter)),
+        ));
+    }
+    for instruction in body {
+        use TInstructionNode::*;
+        match instruction {
+            Return(value, _) => {
+                inlined.push(Copy(renamer.value(value), dst.clone()));
+                inlined.push(Jump(return_label.clone()));
+            }
+            Unary(operator, src, dst) => inlined.push(Unary(
+                operator.clone(),
+                renamer.value(src),
+                renamer.value(dst),
+            )),
+            Binary(operator, src1, src2, dst) => inlined.push(Binary(
+                operator.clone(),
+                renamer.value(src1),
+                renamer.value(src2),
+                renamer.value(dst),
+            )),
+            Copy(src, dst) => inlined.push(Copy(renamer.value(src), renamer.value(dst))),
+            Jump(target) => inlined.push(Jump(renamer.label(target))),
+            JumpIfZero(condition, target) => {
+                inlined.push(JumpIfZero(renamer.value(condition), renamer.label(target)))
+            }
+            JumpIfNotZero(condition, target) => inlined.push(JumpIfNotZero(
+                renamer.value(condition),
+                renamer.label(target),
+            )),
+            Label(label) => inlined.push(Label(renamer.label(label))),
+            CopyToOffset(src, name, offset) => inlined.push(CopyToOffset(
+                renamer.value(src),
+                renamer.variable(name),
+                *offset,
+            )),
+            CopyFromOffset(name, offset, dst) => inlined.push(CopyFromOffset(
+                renamer.variable(name),
+                *offset,
+                renamer.value(dst),
+            )),
+            FunCall(name, arguments, dst) => inlined.push(FunCall(
+                name.clone(),
+                arguments
+                    .iter()
+                    .map(|argument| renamer.value(argument))
+                    .collect(),
+                renamer.value(dst),
+            )),
+            Phi(..) => unreachable!("phis are removed when leaving SSA form"),
+        }
+    }
+    inlined.push(TInstructionNode::Label(return_label));
+    inlined
+}
+
+// replaces calls to small non-recursive functions with copies of their bodies, returning the
+// names of the functions that changed
+pub fn inline_functions(
+    functions: Vec<TFunctionDefinitionNode>,
+    function_types: &FunctionTypes,
+    options: &OptimizationOptions,
+    remarks: &mut Remarks,
+) -> (Vec<TFunctionDefinitionNode>, HashSet<String>) {
+    let threshold = options.inline_threshold;
+    let names: Vec<String> = functions
+        .iter()
+        .map(|TFunctionDefinitionNode::Function(name, _, _)| name.clone())
+        .collect();
+    let (recursive, order) = {
+        let call_graph: HashMap<&str, HashSet<&str>> = functions
+            .iter()
+            .map(|TFunctionDefinitionNode::Function(name, _, instructions)| {
+                (name.as_str(), callees(instructions))
+            })
+            .collect();
+        let name_refs: Vec<&str> = names.iter().map(String::as_str).collect();
+        (
+            recursive_functions(&call_graph),
+            bottom_up_order(&call_graph, &name_refs),
+        )
+    };
+
+    let mut definitions: HashMap<String, (Vec<String>, Vec<TInstructionNode>)> = functions
+        .into_iter()
+        .map(
+            |TFunctionDefinitionNode::Function(name, parameters, instructions)| {
+                (name, (parameters, instructions))
+            },
+        )
+        .collect();
+    let mut changed = HashSet::new();
+    for caller in order {
+        let (parameters, instructions) = definitions.remove(&caller).unwrap();
+        remarks.start("inline", &caller);
+        let mut rewritten = vec![];
+        for instruction in instructions {
+            let TInstructionNode::FunCall(callee, arguments, dst) = &instruction else {
+                rewritten.push(instruction);
+                continue;
+            };
+            // a structure passed or returned by value isn't a value a copy can move
+            let passes_aggregates = function_types.functions[callee].passes_aggregates();
+            let reason = match definitions.get(callee) {
+                _ if recursive.contains(callee) => Some(String::from("it is recursive")),
+                _ if passes_aggregates => {
+                    Some(String::from("it passes or returns a structure by value"))
+                }
+                Some((_, body)) if inline_cost(body) > threshold => Some(format!(
+                    "its {} instructions are over the threshold of {threshold}",
+                    inline_cost(body)
+                )),
+                Some(_) => None,
+                None => Some(String::from("its body is not available")),
+            };
+            if let Some(reason) = reason {
+                remarks.missed(
+                    std::slice::from_ref(&instruction),
+                    format!("did not inline `{callee}` into `{caller}`, as {reason}"),
+                );
+                rewritten.push(instruction);
+                continue;
+            }
+            // each call site counts as a pass application of its own
+            if !should_run_pass(options, "inline", &caller) {
+                rewritten.push(instruction);
+                continue;
+            }
+            debug!("inlining {callee} into {caller}");
+            remarks.passed(
+                std::slice::from_ref(&instruction),
+                format!("inlined `{callee}` into `{caller}`"),
+            );
+            let (callee_parameters, body) = &definitions[callee];
+            rewritten.extend(inline_call(callee_parameters, body, arguments, dst));
+            changed.insert(caller.clone());
+        }
+        definitions.insert(caller, (parameters, rewritten));
+    }
+
+    let functions = names
+        .into_iter()
+        .map(|name| {
+            let (parameters, instructions) = definitions.remove(&name).unwrap();
+            TFunctionDefinitionNode::Function(name, parameters, instructions)
+        })
+        .collect();
+    (functions, changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::{parser::tests::parse_source, tacker::tack_program};
+    use super::super::{optimize_program, OptimizationOptions};
+    use super::*;
+
+    // the parameter is copied under a name of its own, but remarks still call it by the name it
+    // has in the callee
+    #[test]
+    fn remarks_about_inlined_code_use_the_callee_names() {
+        let program = tack_program(parse_source(
+            "int square(int x) { return x * x; }\n\
+             int main(void) { return square(12); }\n",
+        ));
+        let options = OptimizationOptions {
+            inline_threshold: 20,
+            propagate_constants: true,
+            ..Default::default()
+        };
+        let mut remarks = Remarks::default();
+        optimize_program(program, &options, &mut remarks);
+        let folded: Vec<&str> = remarks
+            .remarks
+            .iter()
+            .filter(|remark| remark.function == "main" && remark.message.starts_with("folded"))
+            .map(|remark| remark.message.as_str())
+            .collect();
+        assert_eq!(folded, ["folded `x * x` to 144"]);
+    }
+}
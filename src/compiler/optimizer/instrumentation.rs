@@ -0,0 +1,83 @@
+use std::{fmt::Write, sync::Mutex};
+
+use tracing::error;
+
+use super::{
+    super::{cfg::ControlFlowGraph, tac_tree::*},
+    ssa::{construct_ssa, format_ssa},
+    OptimizationOptions,
+};
+
+// every pass application so far, counted across all functions and both the TACKY and the
+// assembly passes, so that a single number picks out a single transformation
+static PASS_APPLICATIONS: Mutex<usize> = Mutex::new(0);
+
+// under --opt-bisect-limit=N, only the first N pass applications run and the rest are skipped;
+// each one is logged with its number, so the first bad one can be found by bisecting on N
+pub fn should_run_pass(options: &OptimizationOptions, pass: &str, function: &str) -> bool {
+    let Some(limit) = options.opt_bisect_limit else {
+        return true;
+    };
+    let application = match PASS_APPLICATIONS.lock() {
+        Ok(mut counter) => {
+            *counter += 1;
+            *counter
+        }
+        Err(e) => {
+            error!("pass application counter mutex was poisoned: {e:?}");
+            panic!("Concurrency panic!");
+        }
+    };
+    let run = application <= limit;
+    let verdict = if run { "running" } else { "NOT running" };
+    eprintln!("BISECT: {verdict} pass ({application}) {pass} on {function}");
+    run
+}
+
+// prints a function as a pass left it, if --print-after asked for that pass; the listing is
+// only built when it is printed
+pub fn print_after_pass(
+    options: &OptimizationOptions,
+    pass: &str,
+    function: &str,
+    listing: impl FnOnce() -> String,
+) {
+    if options.print_after_all || options.print_after.iter().any(|name| name == pass) {
+        eprint!("*** {function} after {pass} ***\n{}", listing());
+    }
+}
+
+pub fn format_tacky(instructions: &[TInstructionNode]) -> String {
+    let mut buffer = String::new();
+    for instruction in instructions {
+        writeln!(buffer, "    {instruction:?}").unwrap();
+    }
+    buffer
+}
+
+// the whole program as --tacky prints it, with each function in SSA form if asked for
+pub fn format_program(program: &TProgramNode, ssa: bool) -> String {
+    let TProgramNode::Program(functions, _) = program;
+    let mut buffer = String::new();
+    for TFunctionDefinitionNode::Function(name, parameters, instructions) in functions {
+        if ssa {
+            buffer.push_str(&format_ssa(name, &construct_ssa(instructions.clone())));
+        } else {
+            writeln!(buffer, "function {name}({}):", parameters.join(", ")).unwrap();
+            buffer.push_str(&format_tacky(instructions));
+        }
+    }
+    buffer
+}
+
+// each function's control flow graph in graphviz's dot format, paired with the function's name
+pub fn format_cfgs(program: &TProgramNode) -> Vec<(String, String)> {
+    let TProgramNode::Program(functions, _) = program;
+    functions
+        .iter()
+        .map(|TFunctionDefinitionNode::Function(name, _, instructions)| {
+            let graph = ControlFlowGraph::new(instructions.clone());
+            (name.clone(), graph.to_dot(name))
+        })
+        .collect()
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+
+use super::super::{
+    ast_tree::{BinaryOperatorNode, UnaryOperatorNode},
+    tac_tree::*,
+};
+
+// runs a function body without calls or aggregates, for checking that a pass kept its meaning;
+// arithmetic wraps like the machine's
+pub fn run(instructions: &[TInstructionNode], arguments: &[(&str, i32)]) -> i32 {
+    let mut variables: HashMap<String, i32> = arguments
+        .iter()
+        .map(|(name, value)| (name.to_string(), *value))
+        .collect();
+    let labels: HashMap<&String, usize> = instructions
+        .iter()
+        .enumerate()
+        .filter_map(|(index, instruction)| match instruction {
+            TInstructionNode::Label(label) => Some((label, index)),
+            _ => None,
+        })
+        .collect();
+    let mut index = 0;
+    loop {
+        let value = |value: &TValNode| match value {
+            TValNode::Constant(c) => *c,
+            TValNode::Var(name) => variables[name],
+        };
+        let name = |value: &TValNode| match value {
+            TValNode::Var(name) => name.clone(),
+            TValNode::Constant(_) => panic!("assigning to a constant"),
+        };
+        match &instructions[index] {
+            TInstructionNode::Return(src, _) => return value(src),
+            TInstructionNode::Copy(src, dst) => {
+                let result = value(src);
+                variables.insert(name(dst), result);
+            }
+            TInstructionNode::Unary(operator, src, dst) => {
+                let result = match operator {
+                    UnaryOperatorNode::Complement => !value(src),
+                    UnaryOperatorNode::Negate => value(src).wrapping_neg(),
+                    UnaryOperatorNode::Not => (value(src) == 0) as i32,
+                };
+                variables.insert(name(dst), result);
+            }
+            TInstructionNode::Binary(operator, src1, src2, dst) => {
+                let (v1, v2) = (value(src1), value(src2));
+                let result = match operator {
+                    BinaryOperatorNode::Add => v1.wrapping_add(v2),
+                    BinaryOperatorNode::Subtract => v1.wrapping_sub(v2),
+                    BinaryOperatorNode::Multiply => v1.wrapping_mul(v2),
+                    BinaryOperatorNode::Divide => v1 / v2,
+                    BinaryOperatorNode::Remainder => v1 % v2,
+                    BinaryOperatorNode::And => (v1 != 0 && v2 != 0) as i32,
+                    BinaryOperatorNode::Or => (v1 != 0 || v2 != 0) as i32,
+                    BinaryOperatorNode::Equal => (v1 == v2) as i32,
+                    BinaryOperatorNode::NotEqual => (v1 != v2) as i32,
+                    BinaryOperatorNode::LessThan => (v1 < v2) as i32,
+                    BinaryOperatorNode::LessOrEqual => (v1 <= v2) as i32,
+                    BinaryOperatorNode::GreaterThan => (v1 > v2) as i32,
+                    BinaryOperatorNode::GreaterOrEqual => (v1 >= v2) as i32,
+                };
+                variables.insert(name(dst), result);
+            }
+            TInstructionNode::Jump(target) => {
+                index = labels[target];
+                continue;
+            }
+            TInstructionNode::JumpIfZero(condition, target) if value(condition) == 0 => {
+                index = labels[target];
+                continue;
+            }
+            TInstructionNode::JumpIfNotZero(condition, target) if value(condition) != 0 => {
+                index = labels[target];
+                continue;
+            }
+            TInstructionNode::JumpIfZero(..)
+            | TInstructionNode::JumpIfNotZero(..)
+            | TInstructionNode::Label(_) => {}
+            other => panic!("can't interpret {other:?}"),
+        }
+        index += 1;
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::HashSet;
+
+use tracing::debug;
+
+use super::{
+    super::{ast_tree::BinaryOperatorNode, cfg::NodeId, tac_tree::*},
+    dominators::Dominators,
+    loops::find_loops,
+    remarks::{describe_instruction, Remarks},
+    ssa::{construct_ssa, destination, destruct_ssa},
+};
+
+// an operation that computes the same value on every iteration of a loop
+fn is_invariant(instruction: &TInstructionNode, defined_in_loop: &HashSet<String>) -> bool {
+    let invariant = |value: &TValNode| match value {
+        TValNode::Var(name) => !defined_in_loop.contains(name),
+        TValNode::Constant(_) => true,
+    };
+    match instruction {
+        TInstructionNode::Unary(_, src, _) => invariant(src),
+        TInstructionNode::Binary(_, src1, src2, _) => invariant(src1) && invariant(src2),
+        _ => false,
+    }
+}
+
+// an invariant operation that can run on every entry to a loop instead of once per iteration;
+// division is left where it is, since the loop might never have reached it with a zero divisor
+fn is_hoistable(instruction: &TInstructionNode, defined_in_loop: &HashSet<String>) -> bool {
+    let divides = matches!(
+        instruction,
+        TInstructionNode::Binary(
+            BinaryOperatorNode::Divide | BinaryOperatorNode::Remainder,
+            ..
+        )
+    );
+    !divides && is_invariant(instruction, defined_in_loop)
+}
+
+// moves computations whose operands don't change inside a loop into its preheader, on SSA form
+// so that each hoisted result still has a single definition dominating all of its uses
+pub fn hoist_loop_invariants(
+    instructions: Vec<TInstructionNode>,
+    remarks: &mut Remarks,
+) -> Vec<TInstructionNode> {
+    let mut graph = construct_ssa(instructions.clone());
+    let dominators = Dominators::new(&graph);
+    let mut loops = find_loops(&graph, &dominators);
+    if loops.is_empty() {
+        return instructions;
+    }
+    // inner loops first, so what they hoist can carry on out of the loops around them
+    loops.sort_by_key(|natural_loop| natural_loop.body.len());
+
+    for natural_loop in loops {
+        // remarks about the loop as a whole are placed at its header
+        let header = match natural_loop.header {
+            NodeId::Block(id) => graph.blocks[&id].instructions.clone(),
+            _ => vec![],
+        };
+        let Some(preheader) = natural_loop.preheader else {
+            debug!(
+                "not hoisting from the loop at {:?}, which has no preheader",
+                natural_loop.header
+            );
+            remarks.missed(
+                &header,
+                String::from("did not hoist anything out of the loop, which has no preheader"),
+            );
+            continue;
+        };
+        let blocks: Vec<usize> = natural_loop
+            .body
+            .iter()
+            .filter_map(|node| match node {
+                NodeId::Block(id) => Some(*id),
+                _ => None,
+            })
+            .collect();
+        let mut defined_in_loop: HashSet<String> = HashSet::new();
+        for id in &blocks {
+            for instruction in &graph.blocks[id].instructions {
+                if let Some(TValNode::Var(name)) = destination(instruction) {
+                    defined_in_loop.insert(name.clone());
+                }
+            }
+        }
+
+        // hoisting one computation can make the ones using its result invariant as well
+        let mut hoisted = vec![];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for id in &blocks {
+                let block = graph.blocks.get_mut(id).unwrap();
+                let mut kept = vec![];
+                for instruction in std::mem::take(&mut block.instructions) {
+                    if !is_hoistable(&instruction, &defined_in_loop) {
+                        kept.push(instruction);
+                        continue;
+                    }
+                    if let Some(TValNode::Var(name)) = destination(&instruction) {
+                        defined_in_loop.remove(name);
+                    }
+                    debug!(
+                        "hoisting {instruction:?} out of the loop at {:?}",
+                        natural_loop.header
+                    );
+                    remarks.passed(
+                        std::slice::from_ref(&instruction),
+                        format!(
+                            "hoisted `{}` out of the loop",
+                            describe_instruction(&instruction)
+                        ),
+                    );
+                    hoisted.push(instruction);
+                    changed = true;
+                }
+                block.instructions = kept;
+            }
+        }
+        for id in &blocks {
+            for instruction in &graph.blocks[id].instructions {
+                if is_invariant(instruction, &defined_in_loop) {
+                    remarks.missed(
+                        std::slice::from_ref(instruction),
+                        format!(
+                            "did not hoist `{}` out of the loop, as it might divide by zero",
+                            describe_instruction(instruction)
+                        ),
+                    );
+                }
+            }
+        }
+        if hoisted.is_empty() {
+            remarks.missed(
+                &header,
+                String::from("found nothing in the loop that is the same on every iteration"),
+            );
+        }
+
+        // the preheader only leads to the header, so anything it ends with is a jump there
+        let preheader_instructions = &mut graph.blocks.get_mut(&preheader).unwrap().instructions;
+        let jump = match preheader_instructions.last() {
+            Some(TInstructionNode::Jump(_)) => preheader_instructions.pop(),
+            _ => None,
+        };
+        preheader_instructions.extend(hoisted);
+        preheader_instructions.extend(jump);
+    }
+    destruct_ssa(graph)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::interpreter::run, *};
+
+    fn var(name: &str) -> TValNode {
+        TValNode::Var(name.to_owned())
+    }
+
+    fn binary(
+        operator: BinaryOperatorNode,
+        src1: TValNode,
+        src2: TValNode,
+        dst: &str,
+    ) -> TInstructionNode {
+        TInstructionNode::Binary(operator, src1, src2, var(dst))
+    }
+
+    // `for (i = 0; i < n; i = i + 1) { body; sum = sum + x; }`, with `a` and `b` parameters
+    fn summing_loop(body: Vec<TInstructionNode>) -> Vec<TInstructionNode> {
+        let mut instructions = vec![
+            TInstructionNode::Copy(TValNode::Constant(0), var("i")),
+            TInstructionNode::Copy(TValNode::Constant(0), var("sum")),
+            TInstructionNode::Label("loop".to_owned()),
+            binary(BinaryOperatorNode::LessThan, var("i"), var("n"), "t"),
+            TInstructionNode::JumpIfZero(var("t"), "end".to_owned()),
+        ];
+        instructions.extend(body);
+        instructions.extend([
+            binary(BinaryOperatorNode::Add, var("sum"), var("x"), "sum"),
+            binary(
+                BinaryOperatorNode::Add,
+                var("i"),
+                TValNode::Constant(1),
+                "i",
+            ),
+            TInstructionNode::Jump("loop".to_owned()),
+            TInstructionNode::Label("end".to_owned()),
+            TInstructionNode::Return(var("sum"), None),
+        ]);
+        instructions
+    }
+
+    // the operators computed after the loop's label, in order
+    fn operators_in_loop(instructions: &[TInstructionNode]) -> Vec<BinaryOperatorNode> {
+        let header = instructions
+            .iter()
+            .position(|instruction| *instruction == TInstructionNode::Label("loop".to_owned()))
+            .unwrap();
+        instructions[header..]
+            .iter()
+            .filter_map(|instruction| match instruction {
+                TInstructionNode::Binary(operator, ..) => Some(operator.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn assert_same_results(before: &[TInstructionNode], after: &[TInstructionNode]) {
+        for (n, a, b) in [(0, 3, 4), (1, -2, 7), (10, 5, 5), (3, 0, 1)] {
+            let arguments = [("n", n), ("a", a), ("b", b)];
+            assert_eq!(run(after, &arguments), run(before, &arguments));
+        }
+    }
+
+    #[test]
+    fn computations_on_values_from_outside_move_in_front_of_the_loop() {
+        // y depends on the loop only through x, so it comes out once x has
+        let instructions = summing_loop(vec![
+            binary(BinaryOperatorNode::Multiply, var("a"), var("b"), "y"),
+            binary(
+                BinaryOperatorNode::Add,
+                var("y"),
+                TValNode::Constant(1),
+                "x",
+            ),
+        ]);
+        let mut remarks = Remarks::default();
+        let hoisted = hoist_loop_invariants(instructions.clone(), &mut remarks);
+        assert_eq!(
+            operators_in_loop(&hoisted),
+            vec![
+                BinaryOperatorNode::LessThan,
+                BinaryOperatorNode::Add,
+                BinaryOperatorNode::Add
+            ]
+        );
+        assert_same_results(&instructions, &hoisted);
+    }
+
+    #[test]
+    fn computations_on_values_from_inside_stay_in_the_loop() {
+        let instructions = summing_loop(vec![binary(
+            BinaryOperatorNode::Multiply,
+            var("a"),
+            var("i"),
+            "x",
+        )]);
+        let mut remarks = Remarks::default();
+        let hoisted = hoist_loop_invariants(instructions.clone(), &mut remarks);
+        assert_eq!(
+            operators_in_loop(&hoisted),
+            operators_in_loop(&instructions)
+        );
+        assert_same_results(&instructions, &hoisted);
+    }
+
+    #[test]
+    fn division_stays_behind_the_loop_condition() {
+        let instructions = summing_loop(vec![binary(
+            BinaryOperatorNode::Divide,
+            var("a"),
+            var("b"),
+            "x",
+        )]);
+        let mut remarks = Remarks::default();
+        let hoisted = hoist_loop_invariants(instructions.clone(), &mut remarks);
+        assert!(operators_in_loop(&hoisted).contains(&BinaryOperatorNode::Divide));
+        // the loop never runs, so neither must the division by zero
+        assert_eq!(run(&hoisted, &[("n", 0), ("a", 1), ("b", 0)]), 0);
+        assert_same_results(&instructions, &hoisted);
+    }
+}
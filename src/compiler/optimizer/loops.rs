@@ -0,0 +1,123 @@
+use std::collections::BTreeSet;
+
+use super::{
+    super::cfg::{ControlFlowGraph, NodeId},
+    dominators::Dominators,
+};
+
+// a loop with a single entry: the header, which dominates every block in the body
+#[derive(Debug)]
+pub struct NaturalLoop {
+    pub header: NodeId,
+    // includes the header
+    pub body: BTreeSet<NodeId>,
+    // the block outside the loop that is the header's only other way in and only leads there;
+    // code placed at its end runs exactly once before the loop is entered
+    pub preheader: Option<usize>,
+}
+
+// the back edges of a graph are the ones whose target dominates their source; each of those
+// closes a natural loop, and loops sharing a header are merged into one
+pub fn find_loops<I>(graph: &ControlFlowGraph<I>, dominators: &Dominators) -> Vec<NaturalLoop> {
+    let mut loops: Vec<NaturalLoop> = vec![];
+    for id in graph.blocks.keys() {
+        let tail = NodeId::Block(*id);
+        if !dominators.is_reachable(tail) {
+            continue;
+        }
+        for header in graph.successors(tail) {
+            if !dominators.dominates(*header, tail) {
+                continue;
+            }
+            // the body is everything that reaches the tail without going through the header
+            let mut body = BTreeSet::from([*header]);
+            let mut worklist = vec![tail];
+            while let Some(node) = worklist.pop() {
+                if body.insert(node) {
+                    worklist.extend(graph.predecessors(node).iter().copied());
+                }
+            }
+            match loops.iter_mut().find(|found| found.header == *header) {
+                Some(found) => found.body.extend(body),
+                None => loops.push(NaturalLoop {
+                    header: *header,
+                    body,
+                    preheader: None,
+                }),
+            }
+        }
+    }
+
+    for natural_loop in &mut loops {
+        let outside: Vec<NodeId> = graph
+            .predecessors(natural_loop.header)
+            .iter()
+            .filter(|predecessor| !natural_loop.body.contains(predecessor))
+            .copied()
+            .collect();
+        if let [NodeId::Block(id)] = outside.as_slice() {
+            if graph.successors(NodeId::Block(*id)).len() == 1 {
+                natural_loop.preheader = Some(*id);
+            }
+        }
+    }
+    loops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::super::tac_tree::*, *};
+
+    fn var(name: &str) -> TValNode {
+        TValNode::Var(name.to_owned())
+    }
+
+    #[test]
+    fn a_back_edge_closes_a_loop_entered_from_its_preheader() {
+        let instructions = vec![
+            TInstructionNode::Copy(TValNode::Constant(0), var("i")),
+            TInstructionNode::Label("loop".to_owned()),
+            TInstructionNode::JumpIfZero(var("i"), "end".to_owned()),
+            TInstructionNode::Copy(TValNode::Constant(1), var("i")),
+            TInstructionNode::Jump("loop".to_owned()),
+            TInstructionNode::Label("end".to_owned()),
+            TInstructionNode::Return(var("i"), None),
+        ];
+        let graph = ControlFlowGraph::new(instructions);
+        let dominators = Dominators::new(&graph);
+        let loops = find_loops(&graph, &dominators);
+        assert_eq!(loops.len(), 1);
+        assert_eq!(loops[0].header, NodeId::Block(1));
+        assert_eq!(
+            loops[0].body,
+            BTreeSet::from([NodeId::Block(1), NodeId::Block(2)])
+        );
+        assert_eq!(loops[0].preheader, Some(0));
+    }
+
+    #[test]
+    fn a_header_entered_from_two_places_has_no_preheader() {
+        // the loop is also entered by jumping straight to its header
+        let instructions = vec![
+            TInstructionNode::JumpIfNotZero(var("i"), "loop".to_owned()),
+            TInstructionNode::Copy(TValNode::Constant(0), var("i")),
+            TInstructionNode::Label("loop".to_owned()),
+            TInstructionNode::JumpIfZero(var("i"), "end".to_owned()),
+            TInstructionNode::Jump("loop".to_owned()),
+            TInstructionNode::Label("end".to_owned()),
+            TInstructionNode::Return(var("i"), None),
+        ];
+        let graph = ControlFlowGraph::new(instructions);
+        let dominators = Dominators::new(&graph);
+        let loops = find_loops(&graph, &dominators);
+        assert_eq!(loops.len(), 1);
+        assert_eq!(loops[0].preheader, None);
+    }
+
+    #[test]
+    fn straight_line_code_has_no_loops() {
+        let graph = ControlFlowGraph::new(vec![TInstructionNode::Return(var("i"), None)]);
+        let dominators = Dominators::new(&graph);
+        assert!(find_loops(&graph, &dominators).is_empty());
+    }
+}
@@ -0,0 +1,291 @@
+mod constant_folding;
+mod copy_propagation;
+mod dead_store;
+mod dominators;
+mod induction_variables;
+mod inlining;
+mod instrumentation;
+#[cfg(test)]
+mod interpreter;
+mod loop_invariants;
+mod loops;
+mod remarks;
+mod sccp;
+mod ssa;
+mod unreachable_code;
+mod value_numbering;
+
+use tracing::{debug, info_span, warn};
+
+use super::tac_tree::*;
+use constant_folding::fold_constants;
+use copy_propagation::propagate_copies;
+use dead_store::eliminate_dead_stores;
+use induction_variables::reduce_strength;
+use inlining::inline_functions;
+use instrumentation::format_tacky;
+pub use instrumentation::{format_cfgs, format_program, print_after_pass, should_run_pass};
+use loop_invariants::hoist_loop_invariants;
+pub use remarks::{RemarkKind, Remarks};
+use sccp::propagate_constants;
+use ssa::{construct_ssa, destruct_ssa, format_ssa};
+use unreachable_code::eliminate_unreachable_code;
+use value_numbering::number_values;
+
+// each pass can expose work for the others, so the pipeline is rerun until nothing changes; the
+// passes only ever shrink or simplify the code, this is just a guard against a pass that doesn't
+static MAX_ITERATIONS: usize = 100;
+
+pub static DEFAULT_INLINE_THRESHOLD: usize = 20;
+
+// every pass that --print-after and --opt-bisect-limit know about, the TACKY passes followed by
+// the ones that work on the assembly
+pub const PASS_NAMES: [&str; 12] = [
+    "fold-constants",
+    "eliminate-unreachable-code",
+    "propagate-copies",
+    "eliminate-dead-stores",
+    "sccp",
+    "gvn",
+    "licm",
+    "strength-reduce",
+    "inline",
+    "tail-calls",
+    "allocate-registers",
+    "peephole",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pass {
+    FoldConstants,
+    EliminateUnreachableCode,
+    PropagateCopies,
+    EliminateDeadStores,
+    PropagateConstants,
+    NumberValues,
+    HoistLoopInvariants,
+    ReduceStrength,
+}
+
+impl Pass {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pass::FoldConstants => "fold-constants",
+            Pass::EliminateUnreachableCode => "eliminate-unreachable-code",
+            Pass::PropagateCopies => "propagate-copies",
+            Pass::EliminateDeadStores => "eliminate-dead-stores",
+            Pass::PropagateConstants => "sccp",
+            Pass::NumberValues => "gvn",
+            Pass::HoistLoopInvariants => "licm",
+            Pass::ReduceStrength => "strength-reduce",
+        }
+    }
+
+    fn run(
+        &self,
+        function: &str,
+        instructions: Vec<TInstructionNode>,
+        options: &OptimizationOptions,
+        remarks: &mut Remarks,
+    ) -> Vec<TInstructionNode> {
+        if !should_run_pass(options, self.name(), function) {
+            return instructions;
+        }
+        remarks.start(self.name(), function);
+        let instructions = match self {
+            Pass::FoldConstants => fold_constants(instructions, remarks),
+            Pass::EliminateUnreachableCode => eliminate_unreachable_code(instructions, remarks),
+            Pass::PropagateCopies => propagate_copies(instructions, remarks),
+            Pass::EliminateDeadStores => eliminate_dead_stores(instructions, remarks),
+            Pass::PropagateConstants => propagate_constants(instructions, remarks),
+            Pass::NumberValues => number_values(instructions, remarks),
+            Pass::HoistLoopInvariants => hoist_loop_invariants(instructions, remarks),
+            Pass::ReduceStrength => reduce_strength(instructions, remarks),
+        };
+        print_after_pass(options, self.name(), function, || {
+            format_tacky(&instructions)
+        });
+        instructions
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct OptimizationOptions {
+    pub fold_constants: bool,
+    pub eliminate_unreachable_code: bool,
+    pub propagate_copies: bool,
+    pub eliminate_dead_stores: bool,
+    // sparse conditional constant propagation, which works on SSA form
+    pub propagate_constants: bool,
+    // global value numbering, reusing the results of repeated computations
+    pub number_values: bool,
+    // loop-invariant code motion
+    pub hoist_loop_invariants: bool,
+    // turning multiplications of induction variables into additions
+    pub reduce_strength: bool,
+    // the most instructions a function can have and still be inlined, where 0 turns inlining off
+    pub inline_threshold: usize,
+    // not TACKY passes, but chosen by the same -O levels
+    pub allocate_registers: bool,
+    pub peephole: bool,
+    pub tail_calls: bool,
+    // address stack slots through %rsp, leaving %rbp free for the register allocator
+    pub omit_frame_pointer: bool,
+    // take the optimised code through SSA form and back, logging the SSA form on the way
+    pub ssa: bool,
+    // the passes to print each function after, by name
+    pub print_after: Vec<String>,
+    pub print_after_all: bool,
+    // how many pass applications run before the rest are skipped, for bisecting miscompiles
+    pub opt_bisect_limit: Option<usize>,
+}
+
+impl OptimizationOptions {
+    // the passes enabled by each -O level, before individual flags are added on top
+    pub fn from_level(level: u8) -> OptimizationOptions {
+        OptimizationOptions {
+            fold_constants: level >= 1,
+            eliminate_unreachable_code: level >= 1,
+            propagate_copies: level >= 1,
+            eliminate_dead_stores: level >= 1,
+            propagate_constants: level >= 2,
+            number_values: level >= 2,
+            hoist_loop_invariants: level >= 2,
+            reduce_strength: level >= 2,
+            inline_threshold: if level >= 2 {
+                DEFAULT_INLINE_THRESHOLD
+            } else {
+                0
+            },
+            allocate_registers: level >= 1,
+            peephole: level >= 1,
+            tail_calls: level >= 2,
+            omit_frame_pointer: false,
+            ssa: false,
+            print_after: vec![],
+            print_after_all: false,
+            opt_bisect_limit: None,
+        }
+    }
+
+    // enabled passes in the order they run on each iteration
+    fn passes(&self) -> Vec<Pass> {
+        let mut passes = vec![];
+        if self.fold_constants {
+            passes.push(Pass::FoldConstants);
+        }
+        if self.eliminate_unreachable_code {
+            passes.push(Pass::EliminateUnreachableCode);
+        }
+        if self.propagate_copies {
+            passes.push(Pass::PropagateCopies);
+        }
+        if self.eliminate_dead_stores {
+            passes.push(Pass::EliminateDeadStores);
+        }
+        passes
+    }
+
+    // enabled passes that go through SSA form, run once before the others; renaming variables
+    // every time would stop the other passes from ever reaching a fixpoint
+    fn ssa_passes(&self) -> Vec<Pass> {
+        let mut passes = vec![];
+        if self.propagate_constants {
+            passes.push(Pass::PropagateConstants);
+        }
+        if self.number_values {
+            passes.push(Pass::NumberValues);
+        }
+        if self.hoist_loop_invariants {
+            passes.push(Pass::HoistLoopInvariants);
+        }
+        if self.reduce_strength {
+            passes.push(Pass::ReduceStrength);
+        }
+        passes
+    }
+}
+
+// the SSA form is only logged for now, then converted straight back
+fn through_ssa(function: TFunctionDefinitionNode) -> TFunctionDefinitionNode {
+    let TFunctionDefinitionNode::Function(name, parameters, instructions) = function;
+    let graph = construct_ssa(instructions);
+    debug!("{name} in SSA form:\n{}", format_ssa(&name, &graph));
+    TFunctionDefinitionNode::Function(name, parameters, destruct_ssa(graph))
+}
+
+fn optimize_function(
+    function: TFunctionDefinitionNode,
+    options: &OptimizationOptions,
+    remarks: &mut Remarks,
+) -> TFunctionDefinitionNode {
+    let TFunctionDefinitionNode::Function(name, parameters, mut instructions) = function;
+    for pass in options.ssa_passes() {
+        let _span = info_span!("pass", name = pass.name(), function = name.as_str()).entered();
+        instructions = pass.run(&name, instructions, options, remarks);
+    }
+    let instructions = run_passes(&name, instructions, options, remarks);
+    TFunctionDefinitionNode::Function(name, parameters, instructions)
+}
+
+fn run_passes(
+    name: &str,
+    mut instructions: Vec<TInstructionNode>,
+    options: &OptimizationOptions,
+    remarks: &mut Remarks,
+) -> Vec<TInstructionNode> {
+    let passes = options.passes();
+    if passes.is_empty() {
+        return instructions;
+    }
+    for iteration in 1..=MAX_ITERATIONS {
+        let previous = instructions.clone();
+        for pass in &passes {
+            let _span =
+                info_span!("pass", name = pass.name(), function = name, iteration).entered();
+            instructions = pass.run(name, instructions, options, remarks);
+        }
+        if instructions == previous {
+            debug!("optimisation of {name} reached a fixpoint after {iteration} iterations");
+            return instructions;
+        }
+    }
+    warn!("optimisation of {name} did not reach a fixpoint after {MAX_ITERATIONS} iterations");
+    instructions
+}
+
+// what the passes did is collected in remarks, for whoever asked to see it
+#[tracing::instrument(skip_all)]
+pub fn optimize_program(
+    program: TProgramNode,
+    options: &OptimizationOptions,
+    remarks: &mut Remarks,
+) -> TProgramNode {
+    let TProgramNode::Program(functions, function_types) = program;
+    let mut functions: Vec<TFunctionDefinitionNode> = functions
+        .into_iter()
+        .map(|function| optimize_function(function, options, remarks))
+        .collect();
+    // inlining exposes constant arguments and unused results to the other passes, so the
+    // functions it changed are cleaned up again
+    if options.inline_threshold > 0 {
+        let changed;
+        (functions, changed) = inline_functions(functions, &function_types, options, remarks);
+        functions = functions
+            .into_iter()
+            .map(|function| match &function {
+                TFunctionDefinitionNode::Function(name, _, instructions)
+                    if changed.contains(name) =>
+                {
+                    print_after_pass(options, "inline", name, || format_tacky(instructions));
+                    optimize_function(function, options, remarks)
+                }
+                _ => function,
+            })
+            .collect();
+    }
+    if options.ssa {
+        functions = functions.into_iter().map(through_ssa).collect();
+    }
+    TProgramNode::Program(functions, function_types)
+}
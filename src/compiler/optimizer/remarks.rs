@@ -0,0 +1,205 @@
+use std::fmt::Write;
+
+use super::super::{tac_tree::*, tacker::source_location, tokens::SourceLocation};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RemarkKind {
+    // the pass changed the code
+    Passed,
+    // the pass looked at the code and left it as it was
+    Missed,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Remark {
+    pub kind: RemarkKind,
+    pub pass: &'static str,
+    pub function: String,
+    pub location: Option<SourceLocation>,
+    pub message: String,
+}
+
+// what each pass reported doing, collected while the optimiser runs; the pass and function being
+// optimised are set by whoever runs the pass, so the passes themselves only say what they did
+#[derive(Debug, Default)]
+pub struct Remarks {
+    pass: &'static str,
+    function: String,
+    pub remarks: Vec<Remark>,
+}
+
+// variables in SSA form carry a version after their name, inlined copies of user variables carry
+// a suffix of their own, and user variables never contain a dot, so this recovers the name the
+// tacker or the source gave a variable
+pub fn original_name(name: &str) -> &str {
+    let mut parts = name.splitn(3, '.');
+    let first = parts.next().unwrap_or(name);
+    match (first, parts.next()) {
+        ("tmp", Some(counter)) => &name[..first.len() + 1 + counter.len()],
+        _ => first,
+    }
+}
+
+// temporaries hold the intermediate results of expressions, and mean nothing to the reader
+pub fn is_temporary(name: &str) -> bool {
+    name.starts_with("tmp.")
+}
+
+pub fn locate_name(name: &str) -> Option<SourceLocation> {
+    source_location(name).or_else(|| source_location(original_name(name)))
+}
+
+// the source location of a return statement, or else of the first thing the instruction writes,
+// reads or jumps to that has one
+fn locate_instruction(instruction: &TInstructionNode) -> Option<SourceLocation> {
+    use TInstructionNode::*;
+    if let Return(_, Some(location)) = instruction {
+        return Some(*location);
+    }
+    let values: Vec<&TValNode> = match instruction {
+        Return(value, _) | JumpIfZero(value, _) | JumpIfNotZero(value, _) => vec![value],
+        Unary(_, src, dst) | Copy(src, dst) => vec![dst, src],
+        Binary(_, src1, src2, dst) => vec![dst, src1, src2],
+        CopyToOffset(src, _, _) => vec![src],
+        CopyFromOffset(_, _, dst) => vec![dst],
+        FunCall(_, arguments, dst) => std::iter::once(dst).chain(arguments).collect(),
+        Phi(dst, incoming) => std::iter::once(dst)
+            .chain(incoming.iter().map(|(_, value)| value))
+            .collect(),
+        Jump(_) | Label(_) => vec![],
+    };
+    let others: Vec<&str> = match instruction {
+        Jump(name)
+        | Label(name)
+        | JumpIfZero(_, name)
+        | JumpIfNotZero(_, name)
+        | CopyToOffset(_, name, _)
+        | CopyFromOffset(name, _, _) => vec![name],
+        _ => vec![],
+    };
+    values
+        .into_iter()
+        .filter_map(|value| match value {
+            TValNode::Var(name) => Some(name.as_str()),
+            TValNode::Constant(_) => None,
+        })
+        .chain(others)
+        .find_map(locate_name)
+}
+
+pub fn locate(instructions: &[TInstructionNode]) -> Option<SourceLocation> {
+    instructions.iter().find_map(locate_instruction)
+}
+
+// how a value is written in remarks, under the name it has in the source
+pub fn describe_value(value: &TValNode) -> String {
+    match value {
+        TValNode::Constant(c) => format!("{c}"),
+        TValNode::Var(name) => original_name(name).to_owned(),
+    }
+}
+
+// the computation an instruction performs, written as C
+pub fn describe_instruction(instruction: &TInstructionNode) -> String {
+    match instruction {
+        TInstructionNode::Unary(operator, src, _) => {
+            format!("{operator}{}", describe_value(src))
+        }
+        TInstructionNode::Binary(operator, src1, src2, _) => format!(
+            "{} {operator} {}",
+            describe_value(src1),
+            describe_value(src2)
+        ),
+        TInstructionNode::Copy(src, dst) => {
+            format!("{} = {}", describe_value(dst), describe_value(src))
+        }
+        TInstructionNode::FunCall(name, arguments, _) => {
+            let arguments: Vec<String> = arguments.iter().map(describe_value).collect();
+            format!("{name}({})", arguments.join(", "))
+        }
+        other => format!("{other:?}"),
+    }
+}
+
+impl Remarks {
+    pub fn start(&mut self, pass: &'static str, function: &str) {
+        self.pass = pass;
+        self.function = function.to_owned();
+    }
+
+    // the location of a remark about some instructions is that of the first one that has any;
+    // a pass that runs to a fixpoint makes the same missed remark every iteration, so repeats are
+    // dropped
+    fn add(&mut self, kind: RemarkKind, instructions: &[TInstructionNode], message: String) {
+        let remark = Remark {
+            kind,
+            pass: self.pass,
+            function: self.function.clone(),
+            location: locate(instructions),
+            message,
+        };
+        if !self.remarks.contains(&remark) {
+            self.remarks.push(remark);
+        }
+    }
+
+    pub fn passed(&mut self, instructions: &[TInstructionNode], message: String) {
+        self.add(RemarkKind::Passed, instructions, message);
+    }
+
+    pub fn missed(&mut self, instructions: &[TInstructionNode], message: String) {
+        self.add(RemarkKind::Missed, instructions, message);
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+impl Remark {
+    // the way clang reports remarks, naming the option that asks for them
+    pub fn format_text(&self, file: &str) -> String {
+        let flag = match self.kind {
+            RemarkKind::Passed => "pass",
+            RemarkKind::Missed => "pass-missed",
+        };
+        match self.location {
+            Some(location) => format!(
+                "{file}:{location}: remark: {} [-R{flag}={}]",
+                self.message, self.pass
+            ),
+            None => format!(
+                "{file}: remark: {} in {} [-R{flag}={}]",
+                self.message, self.function, self.pass
+            ),
+        }
+    }
+
+    pub fn format_json(&self) -> String {
+        let kind = match self.kind {
+            RemarkKind::Passed => "passed",
+            RemarkKind::Missed => "missed",
+        };
+        let (line, column) = match self.location {
+            Some(location) => (format!("{}", location.line), format!("{}", location.column)),
+            None => (String::from("null"), String::from("null")),
+        };
+        format!(
+            "{{\"kind\": \"{kind}\", \"pass\": {}, \"function\": {}, \"line\": {line}, \"column\": {column}, \"message\": {}}}",
+            json_string(self.pass),
+            json_string(&self.function),
+            json_string(&self.message)
+        )
+    }
+}
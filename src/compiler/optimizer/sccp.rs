@@ -0,0 +1,321 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+
+use tracing::debug;
+
+use super::{
+    super::{
+        cfg::{ControlFlowGraph, NodeId},
+        const_eval::{fold_binary, fold_unary},
+        tac_tree::*,
+    },
+    remarks::{describe_instruction, describe_value, is_temporary, Remarks},
+    ssa::{construct_ssa, destination, destruct_ssa, operands, operands_mut},
+};
+
+// what is known about a variable: nothing yet, because no definition of it has been found to
+// run; a single constant value; or that it can take more than one value
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Lattice {
+    Undetermined,
+    Constant(i32),
+    Overdefined,
+}
+
+fn meet(a: Lattice, b: Lattice) -> Lattice {
+    match (a, b) {
+        (Lattice::Undetermined, other) | (other, Lattice::Undetermined) => other,
+        (Lattice::Constant(x), Lattice::Constant(y)) if x == y => Lattice::Constant(x),
+        _ => Lattice::Overdefined,
+    }
+}
+
+struct Analysis<'a> {
+    graph: &'a ControlFlowGraph<TInstructionNode>,
+    label_blocks: HashMap<String, NodeId>,
+    // variables that are assigned somewhere in the function; any other variable is a parameter
+    // or never initialised, and could hold anything
+    defined: HashSet<String>,
+    values: HashMap<String, Lattice>,
+    executable_edges: HashSet<(NodeId, NodeId)>,
+    executable_blocks: BTreeSet<usize>,
+}
+
+impl Analysis<'_> {
+    fn value_of(&self, value: &TValNode) -> Lattice {
+        match value {
+            TValNode::Constant(c) => Lattice::Constant(*c),
+            TValNode::Var(name) if !self.defined.contains(name) => Lattice::Overdefined,
+            TValNode::Var(name) => *self.values.get(name).unwrap_or(&Lattice::Undetermined),
+        }
+    }
+
+    fn evaluate(&self, node: NodeId, instruction: &TInstructionNode) -> Lattice {
+        match instruction {
+            TInstructionNode::Copy(src, _) => self.value_of(src),
+            TInstructionNode::Unary(operator, src, _) => match self.value_of(src) {
+                // an operation that would be undefined is left for the program to perform
+                Lattice::Constant(value) => match fold_unary(operator, value) {
+                    Some(result) => Lattice::Constant(result),
+                    None => Lattice::Overdefined,
+                },
+                other => other,
+            },
+            TInstructionNode::Binary(operator, src1, src2, _) => {
+                match (self.value_of(src1), self.value_of(src2)) {
+                    (Lattice::Constant(v1), Lattice::Constant(v2)) => {
+                        match fold_binary(operator, v1, v2) {
+                            Some(result) => Lattice::Constant(result),
+                            None => Lattice::Overdefined,
+                        }
+                    }
+                    (Lattice::Overdefined, _) | (_, Lattice::Overdefined) => Lattice::Overdefined,
+                    _ => Lattice::Undetermined,
+                }
+            }
+            // only the arguments along edges known to run count towards a phi
+            TInstructionNode::Phi(_, incoming) => incoming
+                .iter()
+                .filter(|(predecessor, _)| self.executable_edges.contains(&(*predecessor, node)))
+                .fold(Lattice::Undetermined, |value, (_, argument)| {
+                    meet(value, self.value_of(argument))
+                }),
+            // aggregates aren't tracked, and what a call returns isn't known
+            _ => Lattice::Overdefined,
+        }
+    }
+
+    // the edges out of a block that can be taken, given what is known about its last instruction
+    fn feasible_successors(&self, id: usize) -> Vec<NodeId> {
+        let node = NodeId::Block(id);
+        let fallthrough = self.graph.next_node(node);
+        match self.graph.blocks[&id].instructions.last() {
+            Some(TInstructionNode::Return(..)) => vec![NodeId::Exit],
+            Some(TInstructionNode::Jump(target)) => vec![self.label_blocks[target]],
+            Some(TInstructionNode::JumpIfZero(condition, target)) => {
+                match self.value_of(condition) {
+                    Lattice::Undetermined => vec![],
+                    Lattice::Constant(0) => vec![self.label_blocks[target]],
+                    Lattice::Constant(_) => vec![fallthrough],
+                    Lattice::Overdefined => vec![self.label_blocks[target], fallthrough],
+                }
+            }
+            Some(TInstructionNode::JumpIfNotZero(condition, target)) => {
+                match self.value_of(condition) {
+                    Lattice::Undetermined => vec![],
+                    Lattice::Constant(0) => vec![fallthrough],
+                    Lattice::Constant(_) => vec![self.label_blocks[target]],
+                    Lattice::Overdefined => vec![self.label_blocks[target], fallthrough],
+                }
+            }
+            _ => vec![fallthrough],
+        }
+    }
+
+    // finds which edges can run and what every variable holds by following both together from
+    // the entry, so that a branch known to go one way never feeds the other arm's values forward
+    fn run(&mut self) {
+        let mut users: HashMap<String, BTreeSet<usize>> = HashMap::new();
+        for (id, block) in &self.graph.blocks {
+            for instruction in &block.instructions {
+                let mut used: Vec<&TValNode> = operands(instruction);
+                if let TInstructionNode::Phi(_, incoming) = instruction {
+                    used.extend(incoming.iter().map(|(_, value)| value));
+                }
+                for value in used {
+                    if let TValNode::Var(name) = value {
+                        users.entry(name.clone()).or_default().insert(*id);
+                    }
+                }
+            }
+        }
+
+        let mut edge_worklist: VecDeque<(NodeId, NodeId)> = VecDeque::new();
+        let mut block_worklist: VecDeque<usize> = VecDeque::new();
+        edge_worklist.push_back((NodeId::Entry, self.graph.next_node(NodeId::Entry)));
+        loop {
+            if let Some((from, to)) = edge_worklist.pop_front() {
+                if !self.executable_edges.insert((from, to)) {
+                    continue;
+                }
+                // phis see a new argument every time another edge into their block runs
+                if let NodeId::Block(id) = to {
+                    self.executable_blocks.insert(id);
+                    block_worklist.push_back(id);
+                }
+                continue;
+            }
+            let Some(id) = block_worklist.pop_front() else {
+                break;
+            };
+            let node = NodeId::Block(id);
+            for instruction in &self.graph.blocks[&id].instructions {
+                let Some(TValNode::Var(name)) = destination(instruction) else {
+                    continue;
+                };
+                let value = self.evaluate(node, instruction);
+                let previous = *self.values.get(name).unwrap_or(&Lattice::Undetermined);
+                if value != previous {
+                    self.values.insert(name.clone(), value);
+                    for user in users.get(name).into_iter().flatten() {
+                        if self.executable_blocks.contains(user) && !block_worklist.contains(user) {
+                            block_worklist.push_back(*user);
+                        }
+                    }
+                }
+            }
+            for successor in self.feasible_successors(id) {
+                edge_worklist.push_back((node, successor));
+            }
+        }
+    }
+}
+
+fn rewrite_block(
+    instructions: Vec<TInstructionNode>,
+    node: NodeId,
+    analysis: &Analysis,
+    remarks: &mut Remarks,
+) -> Vec<TInstructionNode> {
+    let mut rewritten = vec![];
+    for mut instruction in instructions {
+        // a variable known to be constant no longer needs assigning, as every use is replaced
+        if let Some(dst) = destination(&instruction) {
+            let is_call = matches!(instruction, TInstructionNode::FunCall(..));
+            if let (Lattice::Constant(value), false) = (analysis.value_of(dst), is_call) {
+                let message = match (&instruction, dst) {
+                    (TInstructionNode::Unary(..) | TInstructionNode::Binary(..), _) => Some(
+                        format!("folded `{}` to {value}", describe_instruction(&instruction)),
+                    ),
+                    (_, TValNode::Var(name)) if !is_temporary(name) => Some(format!(
+                        "propagated the constant {value} held by `{}` to its uses",
+                        describe_value(dst)
+                    )),
+                    _ => None,
+                };
+                if let Some(message) = message {
+                    remarks.passed(std::slice::from_ref(&instruction), message);
+                }
+                continue;
+            }
+        }
+        for operand in operands_mut(&mut instruction) {
+            if let Lattice::Constant(value) = analysis.value_of(operand) {
+                *operand = TValNode::Constant(value);
+            }
+        }
+        if let TInstructionNode::Phi(_, incoming) = &mut instruction {
+            incoming.retain(|(predecessor, _)| {
+                analysis.executable_edges.contains(&(*predecessor, node))
+            });
+            for (_, value) in incoming.iter_mut() {
+                if let Lattice::Constant(constant) = analysis.value_of(value) {
+                    *value = TValNode::Constant(constant);
+                }
+            }
+        }
+        // branches that can only go one way become a jump, or nothing at all
+        let resolved = match &instruction {
+            TInstructionNode::JumpIfZero(TValNode::Constant(value), target) => {
+                Some((*value == 0, target))
+            }
+            TInstructionNode::JumpIfNotZero(TValNode::Constant(value), target) => {
+                Some((*value != 0, target))
+            }
+            _ => None,
+        };
+        match resolved {
+            Some((true, target)) => {
+                debug!("resolving branch to {target} as always taken");
+                remarks.passed(
+                    std::slice::from_ref(&instruction),
+                    String::from("replaced a branch that is always taken with a jump"),
+                );
+                rewritten.push(TInstructionNode::Jump(target.clone()));
+            }
+            Some((false, target)) => {
+                debug!("resolving branch to {target} as never taken");
+                remarks.passed(
+                    std::slice::from_ref(&instruction),
+                    String::from("removed a branch that is never taken"),
+                );
+            }
+            None => rewritten.push(instruction),
+        }
+    }
+    rewritten
+}
+
+// sparse conditional constant propagation, on SSA form
+pub fn propagate_constants(
+    instructions: Vec<TInstructionNode>,
+    remarks: &mut Remarks,
+) -> Vec<TInstructionNode> {
+    let mut graph = construct_ssa(instructions);
+    let (values, executable_edges, executable_blocks) = {
+        let mut analysis = Analysis {
+            graph: &graph,
+            label_blocks: HashMap::new(),
+            defined: HashSet::new(),
+            values: HashMap::new(),
+            executable_edges: HashSet::new(),
+            executable_blocks: BTreeSet::new(),
+        };
+        for (id, block) in &graph.blocks {
+            if let Some(TInstructionNode::Label(label)) = block.instructions.first() {
+                analysis
+                    .label_blocks
+                    .insert(label.clone(), NodeId::Block(*id));
+            }
+            for instruction in &block.instructions {
+                if let Some(TValNode::Var(name)) = destination(instruction) {
+                    analysis.defined.insert(name.clone());
+                }
+            }
+        }
+        analysis.run();
+        (
+            analysis.values,
+            analysis.executable_edges,
+            analysis.executable_blocks,
+        )
+    };
+
+    let unreachable: Vec<usize> = graph
+        .blocks
+        .keys()
+        .filter(|id| !executable_blocks.contains(id))
+        .copied()
+        .collect();
+    for id in unreachable {
+        debug!("removing block {id}, which no feasible path reaches");
+        let removed = graph.remove_block(id);
+        remarks.passed(
+            &removed.instructions,
+            String::from("removed a block that no feasible path reaches"),
+        );
+    }
+
+    let analysis = Analysis {
+        graph: &graph,
+        label_blocks: HashMap::new(),
+        defined: values.keys().cloned().collect(),
+        values,
+        executable_edges,
+        executable_blocks,
+    };
+    let rewritten: Vec<(usize, Vec<TInstructionNode>)> = graph
+        .blocks
+        .iter()
+        .map(|(id, block)| {
+            let node = NodeId::Block(*id);
+            (
+                *id,
+                rewrite_block(block.instructions.clone(), node, &analysis, remarks),
+            )
+        })
+        .collect();
+    for (id, instructions) in rewritten {
+        graph.blocks.get_mut(&id).unwrap().instructions = instructions;
+    }
+    destruct_ssa(graph)
+}
@@ -0,0 +1,455 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Write,
+};
+
+use tracing::debug;
+
+use super::{
+    super::{
+        cfg::{ControlFlow, ControlFlowGraph, ControlFlowKind, NodeId},
+        tac_tree::*,
+        tacker::{make_label_name, make_temporary_var},
+    },
+    dominators::Dominators,
+    unreachable_code::remove_unreachable_blocks,
+};
+
+// the variable an instruction assigns, if any; aggregates are otherwise only written through
+// CopyToOffset, see aggregate_names
+pub fn destination(instruction: &TInstructionNode) -> Option<&TValNode> {
+    match instruction {
+        TInstructionNode::Copy(_, dst)
+        | TInstructionNode::Unary(_, _, dst)
+        | TInstructionNode::Binary(_, _, _, dst)
+        | TInstructionNode::CopyFromOffset(_, _, dst)
+        | TInstructionNode::FunCall(_, _, dst)
+        | TInstructionNode::Phi(dst, _) => Some(dst),
+        _ => None,
+    }
+}
+
+fn destination_mut(instruction: &mut TInstructionNode) -> Option<&mut TValNode> {
+    match instruction {
+        TInstructionNode::Copy(_, dst)
+        | TInstructionNode::Unary(_, _, dst)
+        | TInstructionNode::Binary(_, _, _, dst)
+        | TInstructionNode::CopyFromOffset(_, _, dst)
+        | TInstructionNode::FunCall(_, _, dst)
+        | TInstructionNode::Phi(dst, _) => Some(dst),
+        _ => None,
+    }
+}
+
+// the values an instruction reads, other than the incoming values of a phi, which are read on
+// the edges into its block rather than by the block itself
+pub fn operands(instruction: &TInstructionNode) -> Vec<&TValNode> {
+    match instruction {
+        TInstructionNode::Return(value, _)
+        | TInstructionNode::Copy(value, _)
+        | TInstructionNode::Unary(_, value, _)
+        | TInstructionNode::JumpIfZero(value, _)
+        | TInstructionNode::JumpIfNotZero(value, _)
+        | TInstructionNode::CopyToOffset(value, _, _) => vec![value],
+        TInstructionNode::Binary(_, src1, src2, _) => vec![src1, src2],
+        TInstructionNode::FunCall(_, arguments, _) => arguments.iter().collect(),
+        TInstructionNode::Jump(_)
+        | TInstructionNode::Label(_)
+        | TInstructionNode::CopyFromOffset(..)
+        | TInstructionNode::Phi(..) => vec![],
+    }
+}
+
+pub fn operands_mut(instruction: &mut TInstructionNode) -> Vec<&mut TValNode> {
+    match instruction {
+        TInstructionNode::Return(value, _)
+        | TInstructionNode::Copy(value, _)
+        | TInstructionNode::Unary(_, value, _)
+        | TInstructionNode::JumpIfZero(value, _)
+        | TInstructionNode::JumpIfNotZero(value, _)
+        | TInstructionNode::CopyToOffset(value, _, _) => vec![value],
+        TInstructionNode::Binary(_, src1, src2, _) => vec![src1, src2],
+        TInstructionNode::FunCall(_, arguments, _) => arguments.iter_mut().collect(),
+        TInstructionNode::Jump(_)
+        | TInstructionNode::Label(_)
+        | TInstructionNode::CopyFromOffset(..)
+        | TInstructionNode::Phi(..) => vec![],
+    }
+}
+
+fn variable_name(value: &TValNode) -> Option<&String> {
+    match value {
+        TValNode::Var(name) => Some(name),
+        TValNode::Constant(_) => None,
+    }
+}
+
+// phis go after a block's label, if it has one
+fn phi_position(instructions: &[TInstructionNode]) -> usize {
+    match instructions.first() {
+        Some(TInstructionNode::Label(_)) => 1,
+        _ => 0,
+    }
+}
+
+// the variables some part of which is read or written by offset; a call can still assign one
+// of these as a whole, but CopyFromOffset and CopyToOffset refer to them by name, so they keep
+// theirs and are never renamed
+fn aggregate_names(graph: &ControlFlowGraph<TInstructionNode>) -> HashSet<String> {
+    graph
+        .blocks
+        .values()
+        .flat_map(|block| &block.instructions)
+        .filter_map(|instruction| match instruction {
+            TInstructionNode::CopyToOffset(_, name, _)
+            | TInstructionNode::CopyFromOffset(name, _, _) => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+// only variables read in some block before being assigned there can need a phi, since every
+// other variable is dead on entry to every block (semi-pruned SSA)
+fn variables_live_across_blocks(graph: &ControlFlowGraph<TInstructionNode>) -> BTreeSet<String> {
+    let mut live_across = BTreeSet::new();
+    for block in graph.blocks.values() {
+        let mut defined: BTreeSet<String> = BTreeSet::new();
+        for instruction in &block.instructions {
+            for operand in operands(instruction) {
+                if let Some(name) = variable_name(operand) {
+                    if !defined.contains(name) {
+                        live_across.insert(name.clone());
+                    }
+                }
+            }
+            if let Some(name) = destination(instruction).and_then(variable_name) {
+                defined.insert(name.clone());
+            }
+        }
+    }
+    live_across
+}
+
+// places a phi for each variable at the iterated dominance frontier of the blocks assigning it,
+// returning the variable behind each block's phis in the order they were inserted
+fn insert_phis(
+    graph: &mut ControlFlowGraph<TInstructionNode>,
+    dominators: &Dominators,
+    aggregates: &HashSet<String>,
+) -> HashMap<usize, Vec<String>> {
+    let frontiers = dominators.frontiers(graph);
+    let mut definitions: BTreeMap<String, BTreeSet<NodeId>> = BTreeMap::new();
+    for (id, block) in &graph.blocks {
+        for instruction in &block.instructions {
+            let defined = destination(instruction).and_then(variable_name);
+            if let Some(name) = defined.filter(|name| !aggregates.contains(*name)) {
+                definitions
+                    .entry(name.clone())
+                    .or_default()
+                    .insert(NodeId::Block(*id));
+            }
+        }
+    }
+
+    let live_across = variables_live_across_blocks(graph);
+    let mut phi_variables: HashMap<usize, Vec<String>> = HashMap::new();
+    for (name, mut defining_blocks) in definitions {
+        if !live_across.contains(&name) {
+            continue;
+        }
+        let mut has_phi: BTreeSet<NodeId> = BTreeSet::new();
+        let mut worklist: Vec<NodeId> = defining_blocks.iter().copied().collect();
+        while let Some(node) = worklist.pop() {
+            let Some(frontier) = frontiers.get(&node) else {
+                continue;
+            };
+            for frontier_node in frontier {
+                let NodeId::Block(id) = frontier_node else {
+                    continue;
+                };
+                if !has_phi.insert(*frontier_node) {
+                    continue;
+                }
+                phi_variables.entry(*id).or_default().push(name.clone());
+                if defining_blocks.insert(*frontier_node) {
+                    worklist.push(*frontier_node);
+                }
+            }
+        }
+    }
+
+    for (id, names) in &phi_variables {
+        let instructions = &mut graph.blocks.get_mut(id).unwrap().instructions;
+        let position = phi_position(instructions);
+        for (index, name) in names.iter().enumerate() {
+            let phi = TInstructionNode::Phi(TValNode::Var(name.clone()), vec![]);
+            instructions.insert(position + index, phi);
+        }
+    }
+    phi_variables
+}
+
+struct Renamer {
+    // the SSA names currently in scope for each original variable, innermost last
+    stacks: HashMap<String, Vec<String>>,
+    versions: HashMap<String, usize>,
+    phi_variables: HashMap<usize, Vec<String>>,
+    aggregates: HashSet<String>,
+}
+
+impl Renamer {
+    // a use with no reaching definition keeps the original name, which is never assigned and so
+    // stands for an undefined value
+    fn current_name(&self, value: &TValNode) -> TValNode {
+        match variable_name(value) {
+            Some(name) => self.current_version(name),
+            None => value.clone(),
+        }
+    }
+
+    fn current_version(&self, name: &str) -> TValNode {
+        match self.stacks.get(name).and_then(|stack| stack.last()) {
+            Some(current) => TValNode::Var(current.clone()),
+            None => TValNode::Var(name.to_owned()),
+        }
+    }
+
+    fn new_name(&mut self, name: &str) -> String {
+        let version = self.versions.entry(name.to_owned()).or_insert(0);
+        *version += 1;
+        let renamed = format!("{name}.{version}");
+        self.stacks
+            .entry(name.to_owned())
+            .or_default()
+            .push(renamed.clone());
+        renamed
+    }
+
+    fn rename_block(
+        &mut self,
+        graph: &mut ControlFlowGraph<TInstructionNode>,
+        dominators: &Dominators,
+        node: NodeId,
+    ) {
+        let mut defined: Vec<String> = vec![];
+        if let NodeId::Block(id) = node {
+            for instruction in &mut graph.blocks.get_mut(&id).unwrap().instructions {
+                for operand in operands_mut(instruction) {
+                    *operand = self.current_name(operand);
+                }
+                if let Some(dst) = destination_mut(instruction) {
+                    if let TValNode::Var(name) = dst {
+                        if self.aggregates.contains(name) {
+                            continue;
+                        }
+                        let original = name.clone();
+                        *dst = TValNode::Var(self.new_name(&original));
+                        defined.push(original);
+                    }
+                }
+            }
+        }
+
+        let successors: Vec<NodeId> = graph.successors(node).iter().copied().collect();
+        for successor in successors {
+            let NodeId::Block(successor_id) = successor else {
+                continue;
+            };
+            let Some(names) = self.phi_variables.get(&successor_id) else {
+                continue;
+            };
+            let incoming: Vec<TValNode> = names
+                .iter()
+                .map(|name| self.current_version(name))
+                .collect();
+            let instructions = &mut graph.blocks.get_mut(&successor_id).unwrap().instructions;
+            let position = phi_position(instructions);
+            for (index, value) in incoming.into_iter().enumerate() {
+                if let TInstructionNode::Phi(_, arguments) = &mut instructions[position + index] {
+                    arguments.push((node, value));
+                }
+            }
+        }
+
+        for child in dominators.children(node).to_vec() {
+            self.rename_block(graph, dominators, child);
+        }
+        for name in defined {
+            self.stacks.get_mut(&name).unwrap().pop();
+        }
+    }
+}
+
+// converts a function into SSA form, where every variable is assigned exactly once
+pub fn construct_ssa(instructions: Vec<TInstructionNode>) -> ControlFlowGraph<TInstructionNode> {
+    let mut graph = ControlFlowGraph::new(instructions);
+    remove_unreachable_blocks(&mut graph);
+    let dominators = Dominators::new(&graph);
+    let aggregates = aggregate_names(&graph);
+    let phi_variables = insert_phis(&mut graph, &dominators, &aggregates);
+    let mut renamer = Renamer {
+        stacks: HashMap::new(),
+        versions: HashMap::new(),
+        phi_variables,
+        aggregates,
+    };
+    renamer.rename_block(&mut graph, &dominators, NodeId::Entry);
+    graph
+}
+
+// the copies along one edge happen in parallel, so a copy must not overwrite a variable that a
+// later copy still reads; when one would, everything goes through fresh temporaries first
+fn sequentialize_copies(copies: &[(TValNode, TValNode)]) -> Vec<TInstructionNode> {
+    let overlapping = copies
+        .iter()
+        .any(|(_, dst)| copies.iter().any(|(src, _)| src == dst));
+    if !overlapping {
+        return copies
+            .iter()
+            .map(|(src, dst)| TInstructionNode::Copy(src.clone(), dst.clone()))
+            .collect();
+    }
+    let temporaries: Vec<TValNode> = copies
+        .iter()
+        .map(|_| TValNode::Var(make_temporary_var()))
+        .collect();
+    let mut sequence = vec![];
+    for ((src, _), temporary) in copies.iter().zip(&temporaries) {
+        sequence.push(TInstructionNode::Copy(src.clone(), temporary.clone()));
+    }
+    for ((_, dst), temporary) in copies.iter().zip(temporaries) {
+        sequence.push(TInstructionNode::Copy(temporary, dst.clone()));
+    }
+    sequence
+}
+
+fn retarget_jump(instruction: TInstructionNode, target: String) -> TInstructionNode {
+    match instruction {
+        TInstructionNode::JumpIfZero(condition, _) => {
+            TInstructionNode::JumpIfZero(condition, target)
+        }
+        TInstructionNode::JumpIfNotZero(condition, _) => {
+            TInstructionNode::JumpIfNotZero(condition, target)
+        }
+        other => unreachable!("{other:?} is not a conditional jump"),
+    }
+}
+
+// replaces phis with copies at the end of each predecessor; an edge from a conditional jump
+// gets its copies in a new block of its own, so they only run when that edge is taken
+pub fn destruct_ssa(mut graph: ControlFlowGraph<TInstructionNode>) -> Vec<TInstructionNode> {
+    let mut edge_copies: HashMap<(NodeId, NodeId), Vec<(TValNode, TValNode)>> = HashMap::new();
+    let mut label_blocks: HashMap<String, NodeId> = HashMap::new();
+    for (id, block) in &graph.blocks {
+        if let Some(TInstructionNode::Label(label)) = block.instructions.first() {
+            label_blocks.insert(label.clone(), NodeId::Block(*id));
+        }
+        for instruction in &block.instructions {
+            if let TInstructionNode::Phi(dst, incoming) = instruction {
+                for (predecessor, value) in incoming {
+                    edge_copies
+                        .entry((*predecessor, NodeId::Block(*id)))
+                        .or_default()
+                        .push((value.clone(), dst.clone()));
+                }
+            }
+        }
+    }
+    let copies_along = |from: NodeId, to: NodeId| match edge_copies.get(&(from, to)) {
+        Some(copies) => sequentialize_copies(copies),
+        None => vec![],
+    };
+
+    // values arriving from the entry are the parameters, copied before anything else runs
+    let mut output = copies_along(NodeId::Entry, graph.next_node(NodeId::Entry));
+    let mut edge_blocks: Vec<TInstructionNode> = vec![];
+    let ids: Vec<usize> = graph.blocks.keys().copied().collect();
+    for id in ids {
+        let node = NodeId::Block(id);
+        let next_node = graph.next_node(node);
+        let block = graph.blocks.remove(&id).unwrap();
+        let mut instructions: Vec<TInstructionNode> = block
+            .instructions
+            .into_iter()
+            .filter(|instruction| !matches!(instruction, TInstructionNode::Phi(..)))
+            .collect();
+        let last_kind = instructions
+            .last()
+            .map(|instruction| match instruction.control_flow() {
+                ControlFlowKind::Jump(target) => ControlFlowKind::Jump(target),
+                ControlFlowKind::ConditionalJump(target) => {
+                    ControlFlowKind::ConditionalJump(target)
+                }
+                ControlFlowKind::Return => ControlFlowKind::Return,
+                _ => ControlFlowKind::Other,
+            });
+        match last_kind {
+            Some(ControlFlowKind::Return) => output.extend(instructions),
+            Some(ControlFlowKind::Jump(target)) => {
+                let target_node = label_blocks[target];
+                let jump = instructions.pop().unwrap();
+                output.extend(instructions);
+                output.extend(copies_along(node, target_node));
+                output.push(jump);
+            }
+            Some(ControlFlowKind::ConditionalJump(target)) => {
+                let target = target.to_owned();
+                let target_node = label_blocks[&target];
+                let jump = instructions.pop().unwrap();
+                output.extend(instructions);
+                let copies = copies_along(node, target_node);
+                if copies.is_empty() {
+                    output.push(jump);
+                } else {
+                    let edge_label = make_label_name("ssa_edge", "");
+                    output.push(retarget_jump(jump, edge_label.clone()));
+                    edge_blocks.push(TInstructionNode::Label(edge_label));
+                    edge_blocks.extend(copies);
+                    edge_blocks.push(TInstructionNode::Jump(target));
+                }
+                output.extend(copies_along(node, next_node));
+            }
+            _ => {
+                output.extend(instructions);
+                output.extend(copies_along(node, next_node));
+            }
+        }
+    }
+
+    // the new edge blocks go after everything else, jumping around them if the function can
+    // still fall off its end
+    if !edge_blocks.is_empty() {
+        let falls_through = !matches!(
+            output.last(),
+            Some(TInstructionNode::Return(..) | TInstructionNode::Jump(_))
+        );
+        if falls_through {
+            let end_label = make_label_name("ssa_end", "");
+            output.push(TInstructionNode::Jump(end_label.clone()));
+            output.extend(edge_blocks);
+            output.push(TInstructionNode::Label(end_label));
+        } else {
+            output.extend(edge_blocks);
+        }
+    }
+    debug!("{} instructions after leaving SSA form", output.len());
+    output
+}
+
+// a listing of the SSA form, one block at a time, for inspecting what the optimiser works on
+pub fn format_ssa(name: &str, graph: &ControlFlowGraph<TInstructionNode>) -> String {
+    let mut buffer = String::new();
+    writeln!(buffer, "function {name}:").unwrap();
+    for (id, block) in &graph.blocks {
+        let predecessors: Vec<String> = graph
+            .predecessors(NodeId::Block(*id))
+            .iter()
+            .map(|predecessor| format!("{predecessor:?}"))
+            .collect();
+        writeln!(buffer, "  block {id} (from {}):", predecessors.join(", ")).unwrap();
+        for instruction in &block.instructions {
+            writeln!(buffer, "    {instruction:?}").unwrap();
+        }
+    }
+    buffer
+}
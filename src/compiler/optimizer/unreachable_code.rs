@@ -0,0 +1,155 @@
+use std::collections::{HashMap, HashSet};
+
+use tracing::debug;
+
+use super::{
+    super::{
+        cfg::{ControlFlow, ControlFlowGraph, ControlFlowKind, NodeId},
+        tac_tree::*,
+    },
+    remarks::{locate, Remarks},
+};
+
+// removes the blocks no path from the entry reaches, returning their ids and what they held
+pub fn remove_unreachable_blocks(
+    graph: &mut ControlFlowGraph<TInstructionNode>,
+) -> Vec<(usize, Vec<TInstructionNode>)> {
+    let mut reachable: HashSet<NodeId> = HashSet::new();
+    let mut worklist = vec![NodeId::Entry];
+    while let Some(node) = worklist.pop() {
+        if reachable.insert(node) {
+            worklist.extend(graph.successors(node).iter().copied());
+        }
+    }
+    let unreachable: Vec<usize> = graph
+        .blocks
+        .keys()
+        .filter(|id| !reachable.contains(&NodeId::Block(**id)))
+        .copied()
+        .collect();
+    let mut removed = vec![];
+    for id in unreachable {
+        debug!("removing unreachable block {id}");
+        removed.push((id, graph.remove_block(id).instructions));
+    }
+    removed
+}
+
+// says which return or jump a block of dead code follows, when that is what made it dead
+fn describe_unreachable(graph: &ControlFlowGraph<TInstructionNode>, id: usize) -> Option<String> {
+    let NodeId::Block(previous) = graph.previous_node(NodeId::Block(id)) else {
+        return None;
+    };
+    let last = graph.blocks[&previous].instructions.last()?;
+    let kind = match last {
+        TInstructionNode::Return(..) => "return",
+        TInstructionNode::Jump(_) => "jump",
+        _ => return None,
+    };
+    Some(match locate(std::slice::from_ref(last)) {
+        Some(location) => format!("after the {kind} at line {}", location.line),
+        None => format!("after a {kind}"),
+    })
+}
+
+// a jump whose every target is the block it would fall through to anyway does nothing
+fn remove_redundant_jumps(graph: &mut ControlFlowGraph<TInstructionNode>) {
+    let ids: Vec<usize> = graph.blocks.keys().copied().collect();
+    for id in ids {
+        let node = NodeId::Block(id);
+        let next_node = graph.next_node(node);
+        let ends_in_jump = matches!(
+            graph.blocks[&id]
+                .instructions
+                .last()
+                .map(ControlFlow::control_flow),
+            Some(ControlFlowKind::Jump(_) | ControlFlowKind::ConditionalJump(_))
+        );
+        if ends_in_jump
+            && graph
+                .successors(node)
+                .iter()
+                .all(|successor| *successor == next_node)
+        {
+            debug!("removing redundant jump at the end of block {id}");
+            graph.blocks.get_mut(&id).unwrap().instructions.pop();
+        }
+    }
+}
+
+// a label that can only be reached by falling through from the block before it is never jumped to
+fn remove_useless_labels(graph: &mut ControlFlowGraph<TInstructionNode>) {
+    let ids: Vec<usize> = graph.blocks.keys().copied().collect();
+    for id in ids {
+        let node = NodeId::Block(id);
+        let previous_node = graph.previous_node(node);
+        let starts_with_label = matches!(
+            graph.blocks[&id].instructions.first(),
+            Some(TInstructionNode::Label(_))
+        );
+        if starts_with_label
+            && graph
+                .predecessors(node)
+                .iter()
+                .all(|predecessor| *predecessor == previous_node)
+        {
+            debug!("removing useless label at the start of block {id}");
+            graph.blocks.get_mut(&id).unwrap().instructions.remove(0);
+        }
+    }
+}
+
+pub fn eliminate_unreachable_code(
+    instructions: Vec<TInstructionNode>,
+    remarks: &mut Remarks,
+) -> Vec<TInstructionNode> {
+    let mut graph = ControlFlowGraph::new(instructions);
+    // the blocks are described before any are removed, while the code in front of them is there
+    let descriptions: HashMap<usize, Option<String>> = graph
+        .blocks
+        .keys()
+        .map(|id| (*id, describe_unreachable(&graph, *id)))
+        .collect();
+    for (id, removed) in remove_unreachable_blocks(&mut graph) {
+        let message = match &descriptions[&id] {
+            Some(description) => format!("removed unreachable block {description}"),
+            None => String::from("removed unreachable block"),
+        };
+        remarks.passed(&removed, message);
+    }
+    remove_redundant_jumps(&mut graph);
+    remove_useless_labels(&mut graph);
+    graph.into_instructions()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::{parser::tests::parse_source, tacker::tack_program};
+    use super::*;
+
+    // neither the variable nor the constant returned has a location, so the line comes from the
+    // return statement itself
+    #[test]
+    fn code_after_a_return_is_reported_with_the_line_of_the_return() {
+        let TProgramNode::Program(functions, _) = tack_program(parse_source(
+            "int main(void) {\n    int x = 1;\n    return x;\n    return 2;\n}\n",
+        ));
+        let TFunctionDefinitionNode::Function(_, _, instructions) =
+            functions.into_iter().next().unwrap();
+        let mut remarks = Remarks::default();
+        eliminate_unreachable_code(instructions, &mut remarks);
+        let messages: Vec<&str> = remarks
+            .remarks
+            .iter()
+            .map(|remark| remark.message.as_str())
+            .collect();
+        assert_eq!(
+            messages,
+            ["removed unreachable block after the return at line 3"]
+        );
+        assert_eq!(
+            remarks.remarks[0].location.map(|location| location.line),
+            Some(4)
+        );
+    }
+}
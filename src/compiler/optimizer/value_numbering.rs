@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+
+use tracing::debug;
+
+use super::{
+    super::{
+        ast_tree::{BinaryOperatorNode, UnaryOperatorNode},
+        cfg::{ControlFlowGraph, NodeId},
+        tac_tree::*,
+    },
+    dominators::Dominators,
+    remarks::{describe_instruction, Remarks},
+    ssa::{construct_ssa, destruct_ssa},
+};
+
+// a pure computation, named by the values of its operands rather than the variables holding them
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Expression {
+    Unary(UnaryOperatorNode, TValNode),
+    Binary(BinaryOperatorNode, TValNode, TValNode),
+}
+
+fn is_commutative(operator: &BinaryOperatorNode) -> bool {
+    matches!(
+        operator,
+        BinaryOperatorNode::Add
+            | BinaryOperatorNode::Multiply
+            | BinaryOperatorNode::Equal
+            | BinaryOperatorNode::NotEqual
+    )
+}
+
+struct ValueNumbering<'a> {
+    // the earliest value each variable is known to be a copy of; in SSA form a variable never
+    // changes once assigned, so this holds wherever the variable can be used
+    leaders: HashMap<String, TValNode>,
+    // the expressions computed so far, one scope per block on the path down the dominator tree,
+    // since a result can only be reused where its computation is certain to have run
+    available: Vec<HashMap<Expression, TValNode>>,
+    remarks: &'a mut Remarks,
+}
+
+impl ValueNumbering<'_> {
+    fn leader(&self, value: &TValNode) -> TValNode {
+        match value {
+            TValNode::Var(name) => self.leaders.get(name).unwrap_or(value).clone(),
+            TValNode::Constant(_) => value.clone(),
+        }
+    }
+
+    fn lookup(&self, expression: &Expression) -> Option<&TValNode> {
+        self.available
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(expression))
+    }
+
+    fn expression(&self, instruction: &TInstructionNode) -> Option<(Expression, TValNode)> {
+        match instruction {
+            TInstructionNode::Unary(operator, src, dst) => Some((
+                Expression::Unary(operator.clone(), self.leader(src)),
+                dst.clone(),
+            )),
+            TInstructionNode::Binary(operator, src1, src2, dst) => {
+                let (mut v1, mut v2) = (self.leader(src1), self.leader(src2));
+                if is_commutative(operator) && v2 < v1 {
+                    (v1, v2) = (v2, v1);
+                }
+                Some((Expression::Binary(operator.clone(), v1, v2), dst.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    // local value numbering of a single block, with the blocks dominating it already numbered
+    fn number_block(&mut self, instructions: Vec<TInstructionNode>) -> Vec<TInstructionNode> {
+        let mut numbered = vec![];
+        for instruction in instructions {
+            if let TInstructionNode::Copy(src, TValNode::Var(dst)) = &instruction {
+                let leader = self.leader(src);
+                self.leaders.insert(dst.clone(), leader);
+                numbered.push(instruction);
+                continue;
+            }
+            let Some((expression, dst)) = self.expression(&instruction) else {
+                numbered.push(instruction);
+                continue;
+            };
+            match self.lookup(&expression).cloned() {
+                Some(existing) => {
+                    debug!("reusing {existing:?} for {expression:?}");
+                    self.remarks.passed(
+                        std::slice::from_ref(&instruction),
+                        format!(
+                            "reused the result of an earlier `{}`",
+                            describe_instruction(&instruction)
+                        ),
+                    );
+                    if let TValNode::Var(name) = &dst {
+                        self.leaders.insert(name.clone(), existing.clone());
+                    }
+                    numbered.push(TInstructionNode::Copy(existing, dst));
+                }
+                None => {
+                    self.available.last_mut().unwrap().insert(expression, dst);
+                    numbered.push(instruction);
+                }
+            }
+        }
+        numbered
+    }
+
+    fn number_dominated(
+        &mut self,
+        graph: &mut ControlFlowGraph<TInstructionNode>,
+        dominators: &Dominators,
+        node: NodeId,
+    ) {
+        self.available.push(HashMap::new());
+        if let NodeId::Block(id) = node {
+            let block = graph.blocks.get_mut(&id).unwrap();
+            let instructions = std::mem::take(&mut block.instructions);
+            block.instructions = self.number_block(instructions);
+        }
+        for child in dominators.children(node).to_vec() {
+            self.number_dominated(graph, dominators, child);
+        }
+        self.available.pop();
+    }
+}
+
+// replaces pure computations that repeat an earlier one with copies of its result, which copy
+// propagation and dead store elimination then clean up
+pub fn number_values(
+    instructions: Vec<TInstructionNode>,
+    remarks: &mut Remarks,
+) -> Vec<TInstructionNode> {
+    let mut graph = construct_ssa(instructions);
+    let dominators = Dominators::new(&graph);
+    let mut numbering = ValueNumbering {
+        leaders: HashMap::new(),
+        available: vec![],
+        remarks,
+    };
+    numbering.number_dominated(&mut graph, &dominators, NodeId::Entry);
+    destruct_ssa(graph)
+}
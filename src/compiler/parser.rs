@@ -1,47 +1,281 @@
-use std::iter::Peekable;
+use std::{collections::HashMap, sync::Mutex};
 
-use tracing::error;
+use tracing::{debug, error, warn};
 
 use super::ast_tree::*;
-use super::tokens::{KeywordToken, SymbolToken, Token};
+use super::tokens::{
+    IntegerLiteral, KeywordToken, SourceLocation, SymbolToken, Token, TokenStream,
+};
+
+// ordinary identifiers declared in each open scope, mapped to whether they name a typedef; the
+// parser needs this to tell declarations and casts apart from expressions
+static TYPEDEF_SCOPES: Mutex<Vec<HashMap<String, bool>>> = Mutex::new(Vec::new());
+static ANONYMOUS_TAG_COUNTER: Mutex<usize> = Mutex::new(0);
+
+fn with_typedef_scopes<T>(action: impl FnOnce(&mut Vec<HashMap<String, bool>>) -> T) -> T {
+    match TYPEDEF_SCOPES.lock() {
+        Ok(mut scopes) => action(&mut scopes),
+        Err(e) => {
+            error!("typedef scope mutex was poisoned: {e:?}");
+            panic!("Concurrency panic!");
+        }
+    }
+}
+
+fn enter_scope() {
+    with_typedef_scopes(|scopes| scopes.push(HashMap::new()));
+}
+
+fn exit_scope() {
+    with_typedef_scopes(|scopes| scopes.pop());
+}
+
+fn declare_identifier(name: &str, is_typedef: bool) {
+    with_typedef_scopes(|scopes| {
+        scopes
+            .last_mut()
+            .expect("identifier declared outside of any scope")
+            .insert(name.to_owned(), is_typedef)
+    });
+}
+
+fn is_typedef_name(name: &str) -> bool {
+    with_typedef_scopes(|scopes| {
+        scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).copied())
+            .unwrap_or(false)
+    })
+}
+
+#[tracing::instrument]
+fn make_anonymous_tag() -> String {
+    match ANONYMOUS_TAG_COUNTER.lock() {
+        Ok(mut counter) => {
+            let tag = *counter;
+            *counter += 1;
+            debug!("anonymous tag {tag} created");
+            format!("__anonymous_{tag}")
+        }
+        Err(e) => {
+            error!("anonymous tag counter mutex was poisoned: {e:?}");
+            panic!("Concurrency panic!");
+        }
+    }
+}
+
+fn starts_type_name(token: &Token) -> bool {
+    match token {
+        Token::Keyword(
+            KeywordToken::Int | KeywordToken::Struct | KeywordToken::Union | KeywordToken::Enum,
+        ) => true,
+        Token::Identifier(name) => is_typedef_name(name),
+        _ => false,
+    }
+}
+
+fn starts_declaration(token: &Token) -> bool {
+    matches!(token, Token::Keyword(KeywordToken::Typedef)) || starts_type_name(token)
+}
+
+// the next token, which has to be there
+fn next_token<'a>(tokens: &mut TokenStream<'a>) -> &'a Token {
+    match tokens.next() {
+        Some(token) => token,
+        None => {
+            error!("{}: unexpected end of file", tokens.location());
+            panic!("Syntax error!");
+        }
+    }
+}
+
+// the token after what has been parsed so far, which has to be there
+fn peek_token<'a>(tokens: &TokenStream<'a>) -> &'a Token {
+    match tokens.peek() {
+        Some(token) => token,
+        None => {
+            error!("{}: unexpected end of file", tokens.location());
+            panic!("Syntax error!");
+        }
+    }
+}
+
+// consumes the next token, which has to be the given symbol
+fn expect_symbol(tokens: &mut TokenStream, expected: SymbolToken) {
+    let location = tokens.location();
+    match next_token(tokens) {
+        Token::Symbol(symbol) if *symbol == expected => (),
+        found => {
+            error!("{location}: expected {expected:?}, found {found:?}");
+            panic!("Syntax error!");
+        }
+    }
+}
+
+// consumes the next token, which has to be the given keyword
+fn expect_keyword(tokens: &mut TokenStream, expected: KeywordToken) {
+    let location = tokens.location();
+    match next_token(tokens) {
+        Token::Keyword(keyword) if *keyword == expected => (),
+        found => {
+            error!("{location}: expected {expected:?}, found {found:?}");
+            panic!("Syntax error!");
+        }
+    }
+}
+
+fn parse_member_name<'a>(tokens: &mut TokenStream<'a>) -> String {
+    match next_token(tokens) {
+        Token::Identifier(member) => member.to_owned(),
+        other => {
+            error!("expected member name, found {:?}", other);
+            panic!("Syntax error!");
+        }
+    }
+}
+
+fn parse_postfix_operators<'a>(
+    tokens: &mut TokenStream<'a>,
+    mut inner: ExpressionNode,
+) -> ExpressionNode {
+    loop {
+        let location = tokens.location();
+        match tokens.peek() {
+            Some(Token::Symbol(SymbolToken::Period)) => {
+                tokens.next();
+                let member = parse_member_name(tokens);
+                inner = ExpressionNode::Dot(Box::new(inner), member, location);
+            }
+            Some(Token::Symbol(SymbolToken::Arrow)) => {
+                tokens.next();
+                let member = parse_member_name(tokens);
+                inner = ExpressionNode::Arrow(Box::new(inner), member, location);
+            }
+            _ => return inner,
+        }
+    }
+}
+
+// constants keep their own type in the tree, but are used as ints, so any value that doesn't
+// survive the conversion is pointed out the way gcc's -Woverflow does
+fn check_literal_fits_int(literal: &IntegerLiteral, negated: bool, location: SourceLocation) {
+    if let Some(converted) = changed_by_conversion(literal, negated) {
+        let sign = if negated && !literal.literal_type.is_unsigned() {
+            "-"
+        } else {
+            ""
+        };
+        warn!(
+            "{location}: conversion of constant {sign}{literal} from {} to int changes its value to {converted}",
+            literal.literal_type
+        );
+    }
+}
+
+// the int a constant ends up as, if that differs from its value; a minus in front of a signed
+// constant is applied in the constant's own type first, so -2147483648 (a long) fits, as in gcc
+fn changed_by_conversion(literal: &IntegerLiteral, negated: bool) -> Option<i32> {
+    let converted = literal.as_int();
+    if negated && !literal.literal_type.is_unsigned() {
+        let converted = converted.wrapping_neg();
+        return (-(literal.value as i128) != converted as i128).then_some(converted);
+    }
+    (literal.value != converted as u64).then_some(converted)
+}
+
+// match [ <exp> { "," <exp> } ] ")"
+fn parse_argument_list<'a>(tokens: &mut TokenStream<'a>) -> Vec<ExpressionNode> {
+    let mut arguments = vec![];
+    if let Some(Token::Symbol(SymbolToken::CloseParen)) = tokens.peek() {
+        tokens.next();
+        return arguments;
+    }
+    loop {
+        arguments.push(parse_expression(tokens, 0));
+        match next_token(tokens) {
+            Token::Symbol(SymbolToken::Comma) => (),
+            Token::Symbol(SymbolToken::CloseParen) => return arguments,
+            other => {
+                error!("expected ',' or ')' in argument list, found {:?}", other);
+                panic!("Syntax error!");
+            }
+        }
+    }
+}
 
 #[tracing::instrument(skip_all)]
-fn parse_factor<'a>(tokens: &mut Peekable<impl Iterator<Item = &'a Token>>) -> ExpressionNode {
+fn parse_factor<'a>(tokens: &mut TokenStream<'a>) -> ExpressionNode {
+    let location = tokens.location();
     // match <int>
-    let first = tokens.next().unwrap().to_owned();
-    if let Token::Constant(val) = first {
-        return ExpressionNode::Constant(
-            str::parse(&val).expect("Could not parse constant as int"),
-        );
+    let first = next_token(tokens).to_owned();
+    if let Token::Constant(literal) = first {
+        check_literal_fits_int(&literal, false, location);
+        ExpressionNode::Constant(literal, location)
+    } else if let Token::Keyword(KeywordToken::Sizeof) = first {
+        // match "sizeof" "(" <type> ")" or "sizeof" <factor>
+        let is_type_operand = matches!(tokens.peek(), Some(Token::Symbol(SymbolToken::OpenParen)))
+            && tokens.peek_second().is_some_and(starts_type_name);
+        if is_type_operand {
+            tokens.next();
+            let operand = parse_type_name(tokens);
+            expect_symbol(tokens, SymbolToken::CloseParen);
+            return ExpressionNode::SizeOfType(operand, location);
+        }
+        ExpressionNode::SizeOfExpression(Box::new(parse_factor(tokens)), location)
     } else if let Token::Symbol(operator) = first {
         if let SymbolToken::OpenParen = operator {
+            // a type name after "(" makes this a cast rather than a parenthesized expression
+            if starts_type_name(peek_token(tokens)) {
+                let target = parse_type_name(tokens);
+                expect_symbol(tokens, SymbolToken::CloseParen);
+                return ExpressionNode::Cast(target, Box::new(parse_factor(tokens)), location);
+            }
             let inner = parse_expression(tokens, 0);
-            assert!(matches!(
-                tokens.next().unwrap(),
-                Token::Symbol(SymbolToken::CloseParen)
-            ));
-            return inner;
+            expect_symbol(tokens, SymbolToken::CloseParen);
+            parse_postfix_operators(tokens, inner)
         } else {
             let operation: UnaryOperatorNode = match operator {
                 SymbolToken::Minus => UnaryOperatorNode::Negate,
                 SymbolToken::Tilde => UnaryOperatorNode::Complement,
                 SymbolToken::Exclamation => UnaryOperatorNode::Not,
                 _ => {
-                    error!("unrecognized symbol {:?} used as unary operator", operator);
+                    error!(
+                        "unrecognized symbol {:?} used as unary operator at {location}",
+                        operator
+                    );
                     panic!("Syntax error!");
                 }
             };
-            return ExpressionNode::Unary(operation, Box::new(parse_factor(tokens)));
+            // a minus applied straight to a constant is checked as one negative constant
+            if let (UnaryOperatorNode::Negate, Some(Token::Constant(literal))) =
+                (&operation, tokens.peek())
+            {
+                let literal_location = tokens.location();
+                tokens.next();
+                check_literal_fits_int(literal, true, literal_location);
+                let constant = ExpressionNode::Constant(literal.clone(), literal_location);
+                return ExpressionNode::Unary(operation, Box::new(constant), location);
+            }
+            ExpressionNode::Unary(operation, Box::new(parse_factor(tokens)), location)
         }
     } else if let Token::Identifier(name) = first {
-        return ExpressionNode::Var(name);
+        if let Some(Token::Symbol(SymbolToken::OpenParen)) = tokens.peek() {
+            tokens.next();
+            let arguments = parse_argument_list(tokens);
+            return parse_postfix_operators(
+                tokens,
+                ExpressionNode::FunctionCall(name, arguments, location),
+            );
+        }
+        parse_postfix_operators(tokens, ExpressionNode::Var(name, location))
     } else {
-        error!("first token {:?} not a valid start", first);
+        error!("first token {:?} at {location} not a valid start", first);
         panic!("Syntax error!");
     }
 }
 
-fn operator_precedence(operator: &SymbolToken) -> isize {
+pub fn operator_precedence(operator: &SymbolToken) -> isize {
     match operator {
         SymbolToken::Plus => 45,
         SymbolToken::Minus => 45,
@@ -56,27 +290,68 @@ fn operator_precedence(operator: &SymbolToken) -> isize {
         SymbolToken::ExclamationEqual => 30,
         SymbolToken::DoubleAmpersand => 10,
         SymbolToken::DoubleBar => 5,
+        SymbolToken::Question => 3,
         SymbolToken::Equal => 1,
         _ => panic!("finding precedence for unrecognized operator!"),
     }
 }
 
+// the precedence of a binary operator that has already been parsed, for printing it back out
+pub fn binary_operator_precedence(operator: &BinaryOperatorNode) -> isize {
+    let token = match operator {
+        BinaryOperatorNode::Add => SymbolToken::Plus,
+        BinaryOperatorNode::Subtract => SymbolToken::Minus,
+        BinaryOperatorNode::Multiply => SymbolToken::Asterisk,
+        BinaryOperatorNode::Divide => SymbolToken::ForwardSlash,
+        BinaryOperatorNode::Remainder => SymbolToken::Percent,
+        BinaryOperatorNode::And => SymbolToken::DoubleAmpersand,
+        BinaryOperatorNode::Or => SymbolToken::DoubleBar,
+        BinaryOperatorNode::Equal => SymbolToken::DoubleEqual,
+        BinaryOperatorNode::NotEqual => SymbolToken::ExclamationEqual,
+        BinaryOperatorNode::LessThan => SymbolToken::LeftAngleBracket,
+        BinaryOperatorNode::LessOrEqual => SymbolToken::LeftABEqual,
+        BinaryOperatorNode::GreaterThan => SymbolToken::RightAngleBracket,
+        BinaryOperatorNode::GreaterOrEqual => SymbolToken::RightABEqual,
+    };
+    operator_precedence(&token)
+}
+
 #[tracing::instrument(skip_all)]
-fn parse_expression<'a>(
-    tokens: &mut Peekable<impl Iterator<Item = &'a Token>>,
-    min_precedence: isize,
-) -> ExpressionNode {
+fn parse_expression<'a>(tokens: &mut TokenStream<'a>, min_precedence: isize) -> ExpressionNode {
     let mut left = parse_factor(tokens);
     loop {
-        let next = tokens.peek().unwrap();
+        let location = tokens.location();
+        let next = peek_token(tokens);
         if let Token::Symbol(sym) = next {
             use SymbolToken::*;
             // handle the case of an assignment operation
             if matches!(sym, Equal) {
+                if operator_precedence(sym) < min_precedence {
+                    break;
+                }
                 // discard the '=' operator
                 tokens.next();
                 let right = parse_expression(tokens, operator_precedence(sym));
-                left = ExpressionNode::Assignment(Box::new(left), Box::new(right));
+                left = ExpressionNode::Assignment(Box::new(left), Box::new(right), location);
+                continue;
+            }
+            // handle the case of a conditional, whose middle operand is parsed like a
+            // parenthesized expression
+            if matches!(sym, Question) {
+                if operator_precedence(sym) < min_precedence {
+                    break;
+                }
+                // discard the '?' operator
+                tokens.next();
+                let middle = parse_expression(tokens, 0);
+                expect_symbol(tokens, SymbolToken::Colon);
+                let right = parse_expression(tokens, operator_precedence(sym));
+                left = ExpressionNode::Conditional(
+                    Box::new(left),
+                    Box::new(middle),
+                    Box::new(right),
+                    location,
+                );
                 continue;
             }
             match sym {
@@ -87,7 +362,7 @@ fn parse_expression<'a>(
                     if precedence < min_precedence {
                         break;
                     }
-                    let operator = match tokens.next().unwrap() {
+                    let operator = match next_token(tokens) {
                         Token::Symbol(SymbolToken::Plus) => BinaryOperatorNode::Add,
                         Token::Symbol(SymbolToken::Minus) => BinaryOperatorNode::Subtract,
                         Token::Symbol(SymbolToken::Asterisk) => BinaryOperatorNode::Multiply,
@@ -113,7 +388,8 @@ fn parse_expression<'a>(
                     };
 
                     let right = parse_expression(tokens, precedence + 1);
-                    left = ExpressionNode::Binary(operator, Box::new(left), Box::new(right));
+                    left =
+                        ExpressionNode::Binary(operator, Box::new(left), Box::new(right), location);
                 }
                 _ => break,
             }
@@ -125,166 +401,756 @@ fn parse_expression<'a>(
 }
 
 #[tracing::instrument(skip_all)]
-fn parse_statement<'a>(tokens: &mut Peekable<impl Iterator<Item = &'a Token>>) -> StatementNode {
-    let statement: StatementNode = match tokens.peek().unwrap() {
+fn parse_statement<'a>(tokens: &mut TokenStream<'a>) -> StatementNode {
+    let location = tokens.location();
+    let statement: StatementNode = match peek_token(tokens) {
         Token::Keyword(KeywordToken::Return) => {
             // match "return"
-            assert!(matches!(
-                tokens.next().unwrap().to_owned(),
-                Token::Keyword(KeywordToken::Return)
-            ));
+            expect_keyword(tokens, KeywordToken::Return);
 
             // match <expression>
             let expression = parse_expression(tokens, 0);
 
-            StatementNode::Return(expression)
+            StatementNode::Return(expression, location)
         }
-        Token::Identifier(_) => {
+        Token::Symbol(SymbolToken::Semicolon) => StatementNode::Null(location),
+        _ => {
             let expression = parse_expression(tokens, 0);
 
-            StatementNode::Expression(expression)
+            StatementNode::Expression(expression, location)
         }
-        Token::Symbol(SymbolToken::Semicolon) => StatementNode::Null,
-        _ => panic!("unexpected token beginning statement"),
     };
 
     // ensure statement is closed properly
-    assert!(matches!(
-        tokens.next().unwrap().to_owned(),
-        Token::Symbol(SymbolToken::Semicolon)
-    ));
+    expect_symbol(tokens, SymbolToken::Semicolon);
 
     return statement;
 }
 
+fn parse_tag<'a>(tokens: &mut TokenStream<'a>) -> String {
+    match next_token(tokens) {
+        Token::Identifier(tag) => tag.to_owned(),
+        other => {
+            error!("expected tag, found {:?}", other);
+            panic!("Syntax error!");
+        }
+    }
+}
+
+fn parse_optional_tag<'a>(tokens: &mut TokenStream<'a>) -> Option<String> {
+    match peek_token(tokens) {
+        Token::Identifier(_) => Some(parse_tag(tokens)),
+        _ => None,
+    }
+}
+
+// match <specifier>, collecting any struct, union or enum definitions written inline into
+// `definitions` so that they are declared before whatever uses the specifier
+fn parse_type_specifier<'a>(
+    tokens: &mut TokenStream<'a>,
+    definitions: &mut Vec<DeclarationNode>,
+) -> TypeNode {
+    let location = tokens.location();
+    match next_token(tokens) {
+        Token::Keyword(KeywordToken::Int) => TypeNode::Int,
+        Token::Identifier(name) if is_typedef_name(name) => TypeNode::Typedef(name.to_owned()),
+        Token::Keyword(keyword @ (KeywordToken::Struct | KeywordToken::Union)) => {
+            let tag = parse_optional_tag(tokens);
+            let tag = match peek_token(tokens) {
+                Token::Symbol(SymbolToken::OpenBrace) => {
+                    tokens.next();
+                    let members = parse_member_list(tokens, definitions);
+                    let tag = tag.unwrap_or_else(make_anonymous_tag);
+                    definitions.push(match keyword {
+                        KeywordToken::Struct => {
+                            DeclarationNode::StructDeclaration(tag.clone(), Some(members), location)
+                        }
+                        _ => {
+                            DeclarationNode::UnionDeclaration(tag.clone(), Some(members), location)
+                        }
+                    });
+                    tag
+                }
+                next => match tag {
+                    Some(tag) => tag,
+                    None => {
+                        error!("expected tag or member list, found {:?}", next);
+                        panic!("Syntax error!");
+                    }
+                },
+            };
+            match keyword {
+                KeywordToken::Struct => TypeNode::Struct(tag),
+                _ => TypeNode::Union(tag),
+            }
+        }
+        Token::Keyword(KeywordToken::Enum) => {
+            let tag = parse_optional_tag(tokens);
+            match (peek_token(tokens), tag) {
+                (Token::Symbol(SymbolToken::OpenBrace), tag) => {
+                    tokens.next();
+                    let enumerators = parse_enumerator_list(tokens);
+                    definitions.push(DeclarationNode::EnumDeclaration(
+                        tag.clone(),
+                        enumerators,
+                        location,
+                    ));
+                    // objects of an anonymous enum type are plain ints
+                    tag.map_or(TypeNode::Int, TypeNode::Enum)
+                }
+                (_, Some(tag)) => TypeNode::Enum(tag),
+                (next, None) => {
+                    error!("expected tag or enumerator list, found {:?}", next);
+                    panic!("Syntax error!");
+                }
+            }
+        }
+        other => {
+            error!("expected type specifier, found {:?}", other);
+            panic!("Syntax error!");
+        }
+    }
+}
+
+// match { "*" }
+fn parse_pointer_declarator<'a>(tokens: &mut TokenStream<'a>, mut base: TypeNode) -> TypeNode {
+    while let Some(Token::Symbol(SymbolToken::Asterisk)) = tokens.peek() {
+        tokens.next();
+        base = TypeNode::Pointer(Box::new(base));
+    }
+    base
+}
+
+// match the <specifier> { "*" } inside a cast
+fn parse_type_name<'a>(tokens: &mut TokenStream<'a>) -> TypeNode {
+    let mut definitions = vec![];
+    let specifier = parse_type_specifier(tokens, &mut definitions);
+    if !definitions.is_empty() {
+        error!(
+            "type definitions {:?} are not allowed in a cast",
+            definitions
+        );
+        panic!("Syntax error!");
+    }
+    parse_pointer_declarator(tokens, specifier)
+}
+
+fn parse_member_list<'a>(
+    tokens: &mut TokenStream<'a>,
+    definitions: &mut Vec<DeclarationNode>,
+) -> Vec<MemberDeclarationNode> {
+    let mut members = vec![];
+
+    while !matches!(peek_token(tokens), Token::Symbol(SymbolToken::CloseBrace)) {
+        // match <type> <identifier> ";"
+        let specifier = parse_type_specifier(tokens, definitions);
+        let member_type = parse_pointer_declarator(tokens, specifier);
+        let location = tokens.location();
+        let name = parse_member_name(tokens);
+        expect_symbol(tokens, SymbolToken::Semicolon);
+        members.push(MemberDeclarationNode::Member(member_type, name, location));
+    }
+
+    // match "}"
+    expect_symbol(tokens, SymbolToken::CloseBrace);
+
+    if members.is_empty() {
+        error!("struct or union declared without any members");
+        panic!("Syntax error!");
+    }
+    members
+}
+
+fn parse_enumerator_list<'a>(tokens: &mut TokenStream<'a>) -> Vec<EnumeratorNode> {
+    let mut enumerators = vec![];
+
+    // match <identifier> [ "=" <exp> ] { "," <identifier> [ "=" <exp> ] } [ "," ] "}"
+    while !matches!(peek_token(tokens), Token::Symbol(SymbolToken::CloseBrace)) {
+        let location = tokens.location();
+        let name = parse_member_name(tokens);
+        let value = match peek_token(tokens) {
+            Token::Symbol(SymbolToken::Equal) => {
+                tokens.next();
+                Some(parse_expression(tokens, 0))
+            }
+            _ => None,
+        };
+        declare_identifier(&name, false);
+        enumerators.push(EnumeratorNode::Enumerator(name, value, location));
+
+        match peek_token(tokens) {
+            Token::Symbol(SymbolToken::Comma) => {
+                tokens.next();
+            }
+            Token::Symbol(SymbolToken::CloseBrace) => (),
+            other => {
+                error!("expected ',' or '}}' in enumerator list, found {:?}", other);
+                panic!("Syntax error!");
+            }
+        }
+    }
+
+    // match "}"
+    expect_symbol(tokens, SymbolToken::CloseBrace);
+
+    if enumerators.is_empty() {
+        error!("enum declared without any enumerators");
+        panic!("Syntax error!");
+    }
+    enumerators
+}
+
+// a declaration, which at file scope can also be a function definition; any struct, union or
+// enum definitions written inline come first
 #[tracing::instrument(skip_all)]
 fn parse_declaration<'a>(
-    tokens: &mut Peekable<impl Iterator<Item = &'a Token>>,
-) -> DeclarationNode {
-    // match "int"
-    assert!(matches!(
-        tokens.next().unwrap().to_owned(),
-        Token::Keyword(KeywordToken::Int)
-    ));
+    tokens: &mut TokenStream<'a>,
+    file_scope: bool,
+) -> Vec<ExternalDeclarationNode> {
+    let mut declarations = vec![];
 
-    // match <identifier>
-    let identifier_token = tokens.next().unwrap().to_owned();
-    assert!(matches!(identifier_token, Token::Identifier(_)));
+    // match [ "typedef" ] <type>
+    let is_typedef = matches!(peek_token(tokens), Token::Keyword(KeywordToken::Typedef));
+    if is_typedef {
+        tokens.next();
+    }
+    let specifier_location = tokens.location();
+    let specifier = parse_type_specifier(tokens, &mut declarations);
+
+    // a specifier on its own declares or defines a tag
+    if let Token::Symbol(SymbolToken::Semicolon) = peek_token(tokens) {
+        tokens.next();
+        if is_typedef {
+            error!("typedef of {:?} does not declare a name", specifier);
+            panic!("Syntax error!");
+        }
+        if declarations.is_empty() {
+            match specifier {
+                TypeNode::Struct(tag) => declarations.push(DeclarationNode::StructDeclaration(
+                    tag,
+                    None,
+                    specifier_location,
+                )),
+                TypeNode::Union(tag) => declarations.push(DeclarationNode::UnionDeclaration(
+                    tag,
+                    None,
+                    specifier_location,
+                )),
+                other => {
+                    error!("declaration of {:?} does not declare anything", other);
+                    panic!("Syntax error!");
+                }
+            }
+        }
+        return external(declarations);
+    }
+
+    // match { "*" } <identifier>
+    let declared_type = parse_pointer_declarator(tokens, specifier);
+    let location = tokens.location();
+    let identifier_token = next_token(tokens).to_owned();
+    let Token::Identifier(name) = identifier_token else {
+        error!("expected declarator, found {:?}", identifier_token);
+        panic!("syntax error!");
+    };
+    // a function is in scope within its own body, so it can call itself
+    declare_identifier(&name, is_typedef);
+
+    // match "(" <parameter list> followed by ";", or at file scope by a body in braces
+    if let Some(Token::Symbol(SymbolToken::OpenParen)) = tokens.peek() {
+        if is_typedef {
+            error!("{location}: typedef {name} of a function type is not supported");
+            panic!("Syntax error!");
+        }
+        tokens.next();
+        // the parameter names only mean something within the function itself
+        enter_scope();
+        let parameters = parse_parameter_list(tokens);
+        let body_location = tokens.location();
+        match next_token(tokens) {
+            Token::Symbol(SymbolToken::Semicolon) => {
+                exit_scope();
+                declarations.push(DeclarationNode::FunctionDeclaration(
+                    declared_type,
+                    name,
+                    parameters,
+                    location,
+                ));
+                return external(declarations);
+            }
+            Token::Symbol(SymbolToken::OpenBrace) if file_scope => {
+                let definition =
+                    parse_function_body(tokens, declared_type, name, parameters, location);
+                exit_scope();
+                let mut items = external(declarations);
+                items.push(ExternalDeclarationNode::Function(definition));
+                return items;
+            }
+            Token::Symbol(SymbolToken::OpenBrace) => {
+                error!("{body_location}: function {name} can only be defined at file scope");
+                panic!("Syntax error!");
+            }
+            other => {
+                error!(
+                    "{body_location}: expected ';' or a body after the parameters of {name}, found {:?}",
+                    other
+                );
+                panic!("Syntax error!");
+            }
+        }
+    }
+
+    if is_typedef {
+        expect_symbol(tokens, SymbolToken::Semicolon);
+        declarations.push(DeclarationNode::TypedefDeclaration(
+            declared_type,
+            name.to_owned(),
+            location,
+        ));
+        return external(declarations);
+    }
 
     // match next symbol as ";" or "="
 
-    let expression = match tokens.next().unwrap().to_owned() {
+    let expression = match next_token(tokens).to_owned() {
         Token::Symbol(SymbolToken::Semicolon) => None,
         Token::Symbol(SymbolToken::Equal) => {
             let ret = Some(parse_expression(tokens, 0)); // match ";"
-            assert!(matches!(
-                tokens.next().unwrap().to_owned(),
-                Token::Symbol(SymbolToken::Semicolon)
-            ));
+            expect_symbol(tokens, SymbolToken::Semicolon);
             ret
         }
-        _ => panic!("syntax error!"),
+        other => {
+            error!(
+                "expected ';' or '=' after declaring {name}, found {:?}",
+                other
+            );
+            panic!("Syntax error!");
+        }
     };
 
-    if let Token::Identifier(name) = identifier_token {
-        return DeclarationNode::Declaration(name.to_owned(), expression);
-    } else {
-        panic!("syntax error!");
-    }
+    declarations.push(DeclarationNode::Declaration(
+        declared_type,
+        name.to_owned(),
+        expression,
+        location,
+    ));
+    external(declarations)
 }
 
-fn parse_block_item<'a>(tokens: &mut Peekable<impl Iterator<Item = &'a Token>>) -> BlockItemNode {
-    let next = tokens.peek().unwrap();
-    match next {
-        Token::Keyword(KeywordToken::Return) => {
-            BlockItemNode::StatementItem(parse_statement(tokens))
-        }
-        Token::Keyword(KeywordToken::Int) => {
-            BlockItemNode::DeclarationItem(parse_declaration(tokens))
-        }
-        Token::Identifier(_) => BlockItemNode::StatementItem(parse_statement(tokens)),
-        _ => {
-            error!("unexpected token {:?} in block item", next);
-            panic!("syntax error!");
-        }
+fn external(declarations: Vec<DeclarationNode>) -> Vec<ExternalDeclarationNode> {
+    declarations
+        .into_iter()
+        .map(ExternalDeclarationNode::Declaration)
+        .collect()
+}
+
+fn parse_block_item<'a>(tokens: &mut TokenStream<'a>) -> Vec<BlockItemNode> {
+    let next = peek_token(tokens);
+    if starts_declaration(next) {
+        return parse_declaration(tokens, false)
+            .into_iter()
+            .map(|item| match item {
+                ExternalDeclarationNode::Declaration(declaration) => {
+                    BlockItemNode::DeclarationItem(declaration)
+                }
+                ExternalDeclarationNode::Function(_) => {
+                    unreachable!("functions are only defined at file scope")
+                }
+            })
+            .collect();
     }
+    vec![BlockItemNode::StatementItem(parse_statement(tokens))]
 }
 
-fn parse_function_definition<'a>(
-    tokens: &mut Peekable<impl Iterator<Item = &'a Token>>,
-) -> Vec<BlockItemNode> {
+fn parse_function_definition<'a>(tokens: &mut TokenStream<'a>) -> Vec<BlockItemNode> {
     let mut items = vec![];
 
     while (!matches!(
-        tokens.peek().unwrap().to_owned(),
+        peek_token(tokens).to_owned(),
         Token::Symbol(SymbolToken::CloseBrace)
     )) {
-        items.push(parse_block_item(tokens));
+        items.append(&mut parse_block_item(tokens));
     }
 
     return items;
 }
 
-fn parse_function<'a>(
-    tokens: &mut Peekable<impl Iterator<Item = &'a Token>>,
-) -> FunctionDefinitionNode {
-    // match "int"
-    assert!(matches!(
-        tokens.next().unwrap().to_owned(),
-        Token::Keyword(KeywordToken::Int)
-    ));
-
-    // match <identifier>
-    let name_token = tokens.next().unwrap().to_owned();
-    assert!(matches!(name_token, Token::Identifier(_)));
-
-    // match "("
-    assert!(matches!(
-        tokens.next().unwrap().to_owned(),
-        Token::Symbol(SymbolToken::OpenParen)
-    ));
-
-    // match "void"
-    assert!(matches!(
-        tokens.next().unwrap().to_owned(),
-        Token::Keyword(KeywordToken::Void)
-    ));
-
-    // match ")"
-    assert!(matches!(
-        tokens.next().unwrap().to_owned(),
-        Token::Symbol(SymbolToken::CloseParen)
-    ));
+// match "void" ")" or <parameter> { "," <parameter> } ")", where each parameter is a type
+// specifier, any number of "*" and an optional name; the names are declared in whatever scope is
+// open, which the caller sets up for them
+fn parse_parameter_list<'a>(
+    tokens: &mut TokenStream<'a>,
+) -> Vec<(TypeNode, Option<String>, SourceLocation)> {
+    let mut parameters = vec![];
+    if let (
+        Some(Token::Keyword(KeywordToken::Void)),
+        Some(Token::Symbol(SymbolToken::CloseParen)),
+    ) = (tokens.peek(), tokens.peek_second())
+    {
+        tokens.next();
+        tokens.next();
+        return parameters;
+    }
+    loop {
+        let location = tokens.location();
+        let mut definitions = vec![];
+        let specifier = parse_type_specifier(tokens, &mut definitions);
+        if !definitions.is_empty() {
+            error!(
+                "{location}: type definitions {:?} are not allowed in a parameter list",
+                definitions
+            );
+            panic!("Syntax error!");
+        }
+        let parameter_type = parse_pointer_declarator(tokens, specifier);
+        match tokens.peek() {
+            Some(Token::Identifier(name)) => {
+                let location = tokens.location();
+                tokens.next();
+                declare_identifier(name, false);
+                parameters.push((parameter_type, Some(name.to_owned()), location));
+            }
+            _ => parameters.push((parameter_type, None, location)),
+        }
+        let location = tokens.location();
+        match next_token(tokens) {
+            Token::Symbol(SymbolToken::Comma) => (),
+            Token::Symbol(SymbolToken::CloseParen) => return parameters,
+            other => {
+                error!(
+                    "{location}: expected ',' or ')' in parameter list, found {:?}",
+                    other
+                );
+                panic!("Syntax error!");
+            }
+        }
+    }
+}
 
-    // match "{"
-    assert!(matches!(
-        tokens.next().unwrap().to_owned(),
-        Token::Symbol(SymbolToken::OpenBrace)
-    ));
+// match { <block item> } "}" after the "{" opening a function's body, with the scope its
+// parameters were declared in still open
+fn parse_function_body<'a>(
+    tokens: &mut TokenStream<'a>,
+    return_type: TypeNode,
+    name: String,
+    parameters: Vec<(TypeNode, Option<String>, SourceLocation)>,
+    location: SourceLocation,
+) -> FunctionDefinitionNode {
+    // the body refers to its parameters by name
+    let parameters = parameters
+        .into_iter()
+        .enumerate()
+        .map(
+            |(index, (parameter_type, parameter, location))| match parameter {
+                Some(parameter) => (parameter_type, parameter, location),
+                None => {
+                    error!(
+                        "{location}: parameter {} of {name} is not named in its definition",
+                        index + 1
+                    );
+                    panic!("Syntax error!");
+                }
+            },
+        )
+        .collect();
 
     // match <statement>
     let definition = parse_function_definition(tokens);
 
     // match "}"
-    assert!(matches!(
-        tokens.next().unwrap().to_owned(),
-        Token::Symbol(SymbolToken::CloseBrace)
-    ));
+    expect_symbol(tokens, SymbolToken::CloseBrace);
 
-    if let Token::Identifier(name) = name_token {
-        return FunctionDefinitionNode::Function(name.to_owned(), definition);
-    } else {
+    FunctionDefinitionNode::Function(return_type, name, parameters, definition, location)
+}
+
+#[tracing::instrument(skip_all)]
+pub fn parse_program<'a>(tokens: &mut TokenStream<'a>) -> ProgramNode {
+    let mut items = vec![];
+    with_typedef_scopes(|scopes| scopes.clear());
+    enter_scope();
+
+    // match { <declaration> | <function> }
+    while let Some(next) = tokens.peek() {
+        if !starts_declaration(next) {
+            error!(
+                "{}: expected a declaration or function definition, found {:?}",
+                tokens.location(),
+                next
+            );
+            panic!("Syntax error!");
+        }
+        for item in parse_declaration(tokens, true) {
+            if let ExternalDeclarationNode::Declaration(DeclarationNode::Declaration(_, name, ..)) =
+                &item
+            {
+                error!("file scope variable {name} is not supported");
+                panic!("Syntax error!");
+            }
+            items.push(item);
+        }
+    }
+    let defines_function = items
+        .iter()
+        .any(|item| matches!(item, ExternalDeclarationNode::Function(_)));
+    if !defines_function {
+        error!("program does not define any functions");
         panic!("Syntax error!");
     }
+
+    ProgramNode::Program(items)
 }
 
-#[tracing::instrument(skip_all)]
-pub fn parse_program<'a>(tokens: &mut Peekable<impl Iterator<Item = &'a Token>>) -> ProgramNode {
-    // match <function>
-    let child = parse_function(tokens);
+#[cfg(test)]
+pub mod tests {
+    use super::super::lexer::lex;
+    use super::*;
+
+    // the typedef scopes are shared by the whole parser, so only one test can parse at a time
+    static PARSE_LOCK: Mutex<()> = Mutex::new(());
+
+    pub fn at(line: usize, column: usize) -> SourceLocation {
+        SourceLocation { line, column }
+    }
+
+    pub fn parse_source(source: &str) -> ProgramNode {
+        let _guard = PARSE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        parse_program(&mut TokenStream::new(&lex(source.to_owned())))
+    }
+
+    #[test]
+    fn prototypes_stay_where_they_were_written() {
+        let ProgramNode::Program(items) = parse_source(
+            "int g(int, int b);\nint main(void) { return g(1, 2); }\nint g(int a, int b) { return a; }\n",
+        );
+        let [ExternalDeclarationNode::Declaration(DeclarationNode::FunctionDeclaration(
+            TypeNode::Int,
+            name,
+            parameters,
+            _,
+        )), ExternalDeclarationNode::Function(_), ExternalDeclarationNode::Function(_)] =
+            &items[..]
+        else {
+            panic!("expected a prototype and two definitions, got {items:?}");
+        };
+        assert_eq!(name, "g");
+        assert_eq!(
+            parameters,
+            &[
+                (TypeNode::Int, None, at(1, 7)),
+                (TypeNode::Int, Some(String::from("b")), at(1, 16))
+            ]
+        );
+    }
+
+    #[test]
+    fn prototypes_can_be_declared_in_a_block() {
+        let ProgramNode::Program(items) = parse_source(
+            "int main(void) { int f(void); return f(); }\nint f(void) { return 1; }\n",
+        );
+        let ExternalDeclarationNode::Function(FunctionDefinitionNode::Function(_, _, _, body, _)) =
+            &items[0]
+        else {
+            panic!("expected main to come first, got {items:?}");
+        };
+        assert!(matches!(
+            &body[0],
+            BlockItemNode::DeclarationItem(DeclarationNode::FunctionDeclaration(_, name, parameters, _))
+                if name == "f" && parameters.is_empty()
+        ));
+    }
 
-    // ensure tokens is empty after parsing
-    assert!(matches!(tokens.next(), Option::None));
+    #[test]
+    fn functions_can_take_and_return_structures() {
+        let ProgramNode::Program(items) = parse_source(
+            "struct s { int a; };\nstruct s f(struct s x, int);\nstruct s f(struct s x, int n) { return x; }\n",
+        );
+        let [ExternalDeclarationNode::Declaration(DeclarationNode::StructDeclaration(..)), ExternalDeclarationNode::Declaration(DeclarationNode::FunctionDeclaration(
+            return_type,
+            _,
+            declared,
+            _,
+        )), ExternalDeclarationNode::Function(FunctionDefinitionNode::Function(
+            defined_return_type,
+            _,
+            defined,
+            _,
+            _,
+        ))] = &items[..]
+        else {
+            panic!("expected a struct, a prototype and a definition, got {items:?}");
+        };
+        let structure = TypeNode::Struct(String::from("s"));
+        assert_eq!((return_type, defined_return_type), (&structure, &structure));
+        assert_eq!(
+            declared,
+            &[
+                (structure.clone(), Some(String::from("x")), at(2, 21)),
+                (TypeNode::Int, None, at(2, 24))
+            ]
+        );
+        assert_eq!(
+            defined,
+            &[
+                (structure, String::from("x"), at(3, 21)),
+                (TypeNode::Int, String::from("n"), at(3, 28))
+            ]
+        );
+    }
+
+    // the items of the body of the function defined at `index`
+    fn body_of(items: &[ExternalDeclarationNode], index: usize) -> &[BlockItemNode] {
+        match &items[index] {
+            ExternalDeclarationNode::Function(FunctionDefinitionNode::Function(
+                _,
+                _,
+                _,
+                body,
+                _,
+            )) => body,
+            other => panic!("expected a function definition, got {other:?}"),
+        }
+    }
+
+    fn is_var(expression: &ExpressionNode, expected: &str) -> bool {
+        matches!(expression, ExpressionNode::Var(name, _) if name == expected)
+    }
+
+    #[test]
+    fn a_typedef_name_starts_a_declaration_or_a_cast() {
+        let ProgramNode::Program(items) = parse_source(
+            "typedef int T;\n\
+             int main(void) {\n\
+                 int a = 2;\n\
+                 T * x;\n\
+                 a * a;\n\
+                 return (T)a + (a);\n\
+             }\n",
+        );
+        let body = body_of(&items, 1);
+        assert!(matches!(
+            &body[1],
+            BlockItemNode::DeclarationItem(DeclarationNode::Declaration(TypeNode::Pointer(pointee), name, None, _))
+                if **pointee == TypeNode::Typedef(String::from("T")) && name == "x"
+        ));
+        let BlockItemNode::StatementItem(StatementNode::Expression(
+            ExpressionNode::Binary(BinaryOperatorNode::Multiply, left, right, _),
+            _,
+        )) = &body[2]
+        else {
+            panic!("expected a multiplication, got {:?}", body[2]);
+        };
+        assert!(is_var(left, "a") && is_var(right, "a"));
+        let BlockItemNode::StatementItem(StatementNode::Return(
+            ExpressionNode::Binary(BinaryOperatorNode::Add, cast, parenthesized, _),
+            _,
+        )) = &body[3]
+        else {
+            panic!("expected a sum, got {:?}", body[3]);
+        };
+        assert!(matches!(
+            &**cast,
+            ExpressionNode::Cast(TypeNode::Typedef(name), operand, _)
+                if name == "T" && is_var(operand, "a")
+        ));
+        assert!(is_var(parenthesized, "a"));
+    }
+
+    // a variable hides the typedef for the rest of the block, and the typedef is back after it
+    #[test]
+    fn a_variable_can_shadow_a_typedef() {
+        let ProgramNode::Program(items) = parse_source(
+            "typedef int T;\n\
+             int main(void) {\n\
+                 int T = 2;\n\
+                 T * 3;\n\
+                 return (T);\n\
+             }\n\
+             int f(void) {\n\
+                 T * y;\n\
+                 return 0;\n\
+             }\n",
+        );
+        let body = body_of(&items, 1);
+        assert!(matches!(
+            &body[1],
+            BlockItemNode::StatementItem(StatementNode::Expression(ExpressionNode::Binary(
+                BinaryOperatorNode::Multiply,
+                left,
+                _,
+                _,
+            ), _)) if is_var(left, "T")
+        ));
+        assert!(matches!(
+            &body[2],
+            BlockItemNode::StatementItem(StatementNode::Return(value, _)) if is_var(value, "T")
+        ));
+        assert!(matches!(
+            &body_of(&items, 2)[0],
+            BlockItemNode::DeclarationItem(DeclarationNode::Declaration(TypeNode::Pointer(_), name, None, _))
+                if name == "y"
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "Syntax error!")]
+    fn a_definition_names_all_its_parameters() {
+        parse_source("int f(int) { return 1; }\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "Syntax error!")]
+    fn functions_are_not_defined_inside_functions() {
+        parse_source("int main(void) { int f(void) { return 1; } return 0; }\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "Syntax error!")]
+    fn a_missing_parenthesis_is_a_syntax_error() {
+        parse_source("int main(void) { return (1 + 2; }\n");
+    }
+
+    #[test]
+    fn input_that_stops_early_is_a_syntax_error() {
+        let truncated = [
+            "int main(void) { return 1",
+            "int main(void) { return",
+            "int main(",
+            "int main(void) { return 1 ? 2",
+            "struct s { int a;",
+            "typedef int T",
+            "enum e { A,",
+        ];
+        for source in truncated {
+            let result = std::panic::catch_unwind(|| parse_source(source));
+            let message = result.expect_err(source).downcast::<&str>().unwrap();
+            assert_eq!(*message, "Syntax error!", "{source}");
+        }
+    }
 
-    return ProgramNode::Program(child);
+    fn literal(text: &str) -> IntegerLiteral {
+        IntegerLiteral::try_from(text).unwrap()
+    }
+
+    #[test]
+    fn the_most_negative_int_is_not_changed_by_conversion() {
+        assert_eq!(changed_by_conversion(&literal("2147483648"), true), None);
+        assert_eq!(
+            changed_by_conversion(&literal("2147483648"), false),
+            Some(i32::MIN)
+        );
+        assert_eq!(
+            changed_by_conversion(&literal("2147483649"), true),
+            Some(i32::MAX)
+        );
+    }
+
+    #[test]
+    fn a_negated_constant_stays_a_negation() {
+        let ProgramNode::Program(items) = parse_source("int main(void) { return -2147483648; }\n");
+        let body = format!("{items:?}");
+        assert!(
+            body.contains("Unary(Negate, Constant(IntegerLiteral { value: 2147483648"),
+            "{body}"
+        );
+    }
 }
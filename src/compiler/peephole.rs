@@ -0,0 +1,503 @@
+use std::collections::{HashMap, HashSet};
+
+use tracing::debug;
+
+use super::asm_tree::*;
+
+// the exponent of a positive power of two
+fn power_of_two_exponent(value: i32) -> Option<i32> {
+    if value > 1 && (value as u32).is_power_of_two() {
+        return Some(value.trailing_zeros() as i32);
+    }
+    None
+}
+
+// the operands an instruction names explicitly
+fn operands(instruction: &AInstructionNode) -> Vec<&AOperandNode> {
+    match instruction {
+        AInstructionNode::Mov(op1, op2)
+        | AInstructionNode::Movq(op1, op2)
+        | AInstructionNode::Binary(_, op1, op2)
+        | AInstructionNode::Cmp(op1, op2)
+        | AInstructionNode::Test(op1, op2) => vec![op1, op2],
+        AInstructionNode::Lea(address, dst) => {
+            let mut operands = address.operands();
+            operands.push(dst);
+            operands
+        }
+        AInstructionNode::Unary(_, operand)
+        | AInstructionNode::Idiv(operand)
+        | AInstructionNode::SetCC(_, operand)
+        | AInstructionNode::LoadAddress(operand, _) => vec![operand],
+        _ => vec![],
+    }
+}
+
+fn remove_no_ops(instructions: Vec<AInstructionNode>) -> Vec<AInstructionNode> {
+    instructions
+        .into_iter()
+        .filter(|instruction| match instruction {
+            AInstructionNode::Mov(src, dst) if src == dst => {
+                debug!("removing move of {src:?} to itself");
+                false
+            }
+            AInstructionNode::AllocateStack(0) => false,
+            _ => true,
+        })
+        .collect()
+}
+
+// the operand an instruction compares with zero, if that's all it does
+fn tested_against_zero(instruction: &AInstructionNode) -> Option<&AOperandNode> {
+    match instruction {
+        AInstructionNode::Cmp(AOperandNode::Imm(0), tested) => Some(tested),
+        AInstructionNode::Test(op1, op2) if op1 == op2 => Some(op1),
+        _ => None,
+    }
+}
+
+// `cmp; mov $0, t; setcc t; test t, t; je/jne` tests the flags set by the first comparison a
+// second time, so the jump can use them directly; mov and setcc leave the flags alone
+fn fold_compare_and_branch(instructions: Vec<AInstructionNode>) -> Vec<AInstructionNode> {
+    use AInstructionNode::*;
+    let mut folded = vec![];
+    let mut idx = 0;
+    while idx < instructions.len() {
+        if let [Cmp(..) | Test(..), Mov(AOperandNode::Imm(0), zeroed), SetCC(cc, set), second, JmpCC(jump_cc, target), ..] =
+            &instructions[idx..]
+        {
+            if zeroed == set
+                && tested_against_zero(second) == Some(set)
+                && matches!(jump_cc, AConditionCode::E | AConditionCode::NE)
+            {
+                let tested = set;
+                let cc = match jump_cc {
+                    AConditionCode::E => cc.inverse(),
+                    _ => cc.clone(),
+                };
+                debug!("folding comparison of {tested:?} into a j{cc} to {target}");
+                folded.push(instructions[idx].clone());
+                // a stack slot nothing else touches only existed to carry the condition to the
+                // jump, so it doesn't need to be written at all
+                let uses = instructions
+                    .iter()
+                    .flat_map(operands)
+                    .filter(|operand| *operand == tested)
+                    .count();
+                if !(matches!(tested, AOperandNode::Stack(_)) && uses == 3) {
+                    folded.push(instructions[idx + 1].clone());
+                    folded.push(instructions[idx + 2].clone());
+                }
+                folded.push(JmpCC(cc, target.clone()));
+                idx += 5;
+                continue;
+            }
+        }
+        folded.push(instructions[idx].clone());
+        idx += 1;
+    }
+    folded
+}
+
+// multiplying by 2^k is a left shift by k; signed division rounds towards zero, so negative
+// dividends need 2^k - 1 added before shifting right, and after cdq the mask of %edx picks out
+// exactly that bias
+fn strength_reduce(instructions: Vec<AInstructionNode>) -> Vec<AInstructionNode> {
+    use AInstructionNode::*;
+    use AOperandNode::{Imm, Reg};
+    let mut reduced = vec![];
+    let mut idx = 0;
+    while idx < instructions.len() {
+        match &instructions[idx..] {
+            [Binary(ABinaryOperatorNode::Mult, Imm(factor), dst), ..] => {
+                if let Some(exponent) = power_of_two_exponent(*factor) {
+                    debug!("replacing multiplication by {factor} with a shift");
+                    reduced.push(Binary(ABinaryOperatorNode::Sal, Imm(exponent), dst.clone()));
+                    idx += 1;
+                    continue;
+                }
+            }
+            // only the quotient, since the shift leaves no remainder behind in %edx
+            [Cdq, Mov(Imm(divisor), Reg(ARegisterNode::R10)), Idiv(Reg(ARegisterNode::R10)), Mov(Reg(ARegisterNode::AX), _), ..] => {
+                if let Some(exponent) = power_of_two_exponent(*divisor) {
+                    debug!("replacing division by {divisor} with a shift");
+                    reduced.extend([
+                        Cdq,
+                        Binary(
+                            ABinaryOperatorNode::And,
+                            Imm(divisor - 1),
+                            Reg(ARegisterNode::DX),
+                        ),
+                        Binary(
+                            ABinaryOperatorNode::Add,
+                            Reg(ARegisterNode::DX),
+                            Reg(ARegisterNode::AX),
+                        ),
+                        Binary(
+                            ABinaryOperatorNode::Sar,
+                            Imm(exponent),
+                            Reg(ARegisterNode::AX),
+                        ),
+                    ]);
+                    idx += 3;
+                    continue;
+                }
+            }
+            _ => (),
+        }
+        reduced.push(instructions[idx].clone());
+        idx += 1;
+    }
+    reduced
+}
+
+// whether some path from `position` reads the flags before they are next overwritten, going on
+// past labels and through unconditional jumps; a conditional jump reads them itself, so there is
+// only ever the one path to follow
+fn flags_live_from(
+    instructions: &[AInstructionNode],
+    mut position: usize,
+    labels: &HashMap<&str, usize>,
+) -> bool {
+    // a label seen twice has closed a loop that never reads the flags
+    let mut seen: HashSet<&str> = HashSet::new();
+    while let Some(instruction) = instructions.get(position) {
+        match instruction {
+            AInstructionNode::SetCC(..) | AInstructionNode::JmpCC(..) => return true,
+            AInstructionNode::Label(label) if !seen.insert(label) => return false,
+            AInstructionNode::Jmp(target) => match labels.get(target.as_str()) {
+                Some(&label) => {
+                    position = label;
+                    continue;
+                }
+                None => return true,
+            },
+            AInstructionNode::Cmp(..)
+            | AInstructionNode::Test(..)
+            | AInstructionNode::Binary(..)
+            | AInstructionNode::Unary(AUnaryOperatorNode::Neg, _)
+            | AInstructionNode::Idiv(_)
+            | AInstructionNode::AllocateStack(_)
+            | AInstructionNode::DeallocateStack(_)
+            | AInstructionNode::Call(..)
+            | AInstructionNode::TailCall(..)
+            | AInstructionNode::Ret => return false,
+            _ => (),
+        }
+        position += 1;
+    }
+    false
+}
+
+// xor of a register with itself is the shorter way to zero it, but unlike mov it sets the flags
+fn zero_with_xor(instructions: Vec<AInstructionNode>) -> Vec<AInstructionNode> {
+    let labels: HashMap<&str, usize> = instructions
+        .iter()
+        .enumerate()
+        .filter_map(|(position, instruction)| match instruction {
+            AInstructionNode::Label(label) => Some((label.as_str(), position)),
+            _ => None,
+        })
+        .collect();
+    let mut rewritten = vec![];
+    for (idx, instruction) in instructions.iter().enumerate() {
+        match instruction {
+            AInstructionNode::Mov(AOperandNode::Imm(0), AOperandNode::Reg(reg))
+                if !flags_live_from(&instructions, idx + 1, &labels) =>
+            {
+                rewritten.push(AInstructionNode::Binary(
+                    ABinaryOperatorNode::Xor,
+                    AOperandNode::Reg(*reg),
+                    AOperandNode::Reg(*reg),
+                ))
+            }
+            _ => rewritten.push(instruction.clone()),
+        }
+    }
+    rewritten
+}
+
+// a jump to the label straight after it goes where execution would have gone anyway
+fn remove_jumps_to_next_label(instructions: Vec<AInstructionNode>) -> Vec<AInstructionNode> {
+    let mut kept = vec![];
+    for (idx, instruction) in instructions.iter().enumerate() {
+        if let (AInstructionNode::Jmp(target), Some(AInstructionNode::Label(label))) =
+            (instruction, instructions.get(idx + 1))
+        {
+            if target == label {
+                debug!("removing jump to the label {label} that follows it");
+                continue;
+            }
+        }
+        kept.push(instruction.clone());
+    }
+    kept
+}
+
+#[tracing::instrument(skip_all)]
+pub fn optimize_peephole(instructions: Vec<AInstructionNode>) -> Vec<AInstructionNode> {
+    let count = instructions.len();
+    let instructions = remove_no_ops(instructions);
+    let instructions = remove_jumps_to_next_label(instructions);
+    let instructions = fold_compare_and_branch(instructions);
+    let instructions = strength_reduce(instructions);
+    let instructions = zero_with_xor(instructions);
+    debug!(
+        "{count} instructions before peephole optimisation, {} after",
+        instructions.len()
+    );
+    instructions
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use AInstructionNode::*;
+    use AOperandNode::{Imm, Reg, Stack};
+    use ARegisterNode::{AX, DX, R10};
+
+    // runs straight-line arithmetic on registers, enough to check the rewrites compute the same
+    // values as the code they replace
+    fn run(instructions: &[AInstructionNode], registers: &mut HashMap<ARegisterNode, i32>) {
+        let read = |registers: &HashMap<ARegisterNode, i32>, operand: &AOperandNode| match operand {
+            Imm(value) => *value,
+            Reg(reg) => registers[reg],
+            _ => panic!("only immediates and registers are evaluated"),
+        };
+        for instruction in instructions {
+            match instruction {
+                Mov(src, Reg(dst)) => {
+                    let value = read(registers, src);
+                    registers.insert(*dst, value);
+                }
+                Cdq => {
+                    let sign = if registers[&AX] < 0 { -1 } else { 0 };
+                    registers.insert(DX, sign);
+                }
+                Idiv(divisor) => {
+                    let (dividend, divisor) = (registers[&AX], read(registers, divisor));
+                    registers.insert(AX, dividend / divisor);
+                    registers.insert(DX, dividend % divisor);
+                }
+                Binary(operator, src, Reg(dst)) => {
+                    let (src, old) = (read(registers, src), registers[dst]);
+                    let value = match operator {
+                        ABinaryOperatorNode::Add => old.wrapping_add(src),
+                        ABinaryOperatorNode::Mult => old.wrapping_mul(src),
+                        ABinaryOperatorNode::And => old & src,
+                        ABinaryOperatorNode::Sal => old << src,
+                        ABinaryOperatorNode::Sar => old >> src,
+                        _ => panic!("{operator:?} is not evaluated"),
+                    };
+                    registers.insert(*dst, value);
+                }
+                _ => panic!("{instruction:?} is not evaluated"),
+            }
+        }
+    }
+
+    fn compare_then_branch(tested: AOperandNode) -> Vec<AInstructionNode> {
+        vec![
+            Cmp(Reg(R10), Reg(AX)),
+            Mov(Imm(0), tested.clone()),
+            SetCC(AConditionCode::L, tested.clone()),
+            Cmp(Imm(0), tested),
+            JmpCC(AConditionCode::E, String::from("else")),
+        ]
+    }
+
+    #[test]
+    fn moves_to_the_same_place_are_removed() {
+        let instructions = vec![Mov(Reg(AX), Reg(AX)), Mov(Stack(-4), Stack(-4)), Ret];
+        assert_eq!(remove_no_ops(instructions), vec![Ret]);
+    }
+
+    #[test]
+    fn allocating_no_stack_is_removed() {
+        let instructions = vec![AllocateStack(0), AllocateStack(16), Ret];
+        assert_eq!(remove_no_ops(instructions), vec![AllocateStack(16), Ret]);
+    }
+
+    #[test]
+    fn zeroing_uses_xor_when_nothing_reads_the_flags() {
+        let instructions = vec![Mov(Imm(0), Reg(AX)), Ret];
+        assert_eq!(
+            zero_with_xor(instructions),
+            vec![Binary(ABinaryOperatorNode::Xor, Reg(AX), Reg(AX)), Ret]
+        );
+    }
+
+    #[test]
+    fn zeroing_keeps_mov_when_the_flags_are_read_afterwards() {
+        // the mov sits between the comparison and the setcc that reads its result
+        let instructions = vec![
+            Cmp(Reg(R10), Reg(DX)),
+            Mov(Imm(0), Reg(AX)),
+            SetCC(AConditionCode::L, Reg(AX)),
+            Ret,
+        ];
+        assert_eq!(zero_with_xor(instructions.clone()), instructions);
+        // the flags are read past a label, and after the jump to another one
+        let instructions = vec![
+            Cmp(Reg(R10), Reg(DX)),
+            Mov(Imm(0), Reg(AX)),
+            Label(String::from("next")),
+            JmpCC(AConditionCode::L, String::from("end")),
+            Mov(Imm(0), Reg(DX)),
+            Jmp(String::from("test")),
+            Label(String::from("end")),
+            Ret,
+            Label(String::from("test")),
+            SetCC(AConditionCode::L, Reg(AX)),
+            Ret,
+        ];
+        assert_eq!(zero_with_xor(instructions.clone()), instructions);
+    }
+
+    #[test]
+    fn zeroing_uses_xor_across_labels_and_jumps_that_never_read_the_flags() {
+        let xor = |reg| Binary(ABinaryOperatorNode::Xor, Reg(reg), Reg(reg));
+        let instructions = vec![Mov(Imm(0), Reg(AX)), Label(String::from("end")), Ret];
+        assert_eq!(
+            zero_with_xor(instructions),
+            vec![xor(AX), Label(String::from("end")), Ret]
+        );
+        // the jump back closes a loop that only ever moves values around
+        let instructions = vec![
+            Label(String::from("loop")),
+            Mov(Imm(0), Reg(AX)),
+            Mov(Reg(AX), Reg(DX)),
+            Jmp(String::from("loop")),
+        ];
+        assert_eq!(
+            zero_with_xor(instructions),
+            vec![
+                Label(String::from("loop")),
+                xor(AX),
+                Mov(Reg(AX), Reg(DX)),
+                Jmp(String::from("loop")),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_jump_to_the_next_label_is_removed() {
+        let instructions = vec![
+            Jmp(String::from("end")),
+            Label(String::from("end")),
+            Jmp(String::from("end")),
+            Label(String::from("other")),
+            Label(String::from("end.2")),
+            Ret,
+        ];
+        // only the jump with its label right behind it goes; the other has one in between
+        assert_eq!(
+            remove_jumps_to_next_label(instructions),
+            vec![
+                Label(String::from("end")),
+                Jmp(String::from("end")),
+                Label(String::from("other")),
+                Label(String::from("end.2")),
+                Ret,
+            ]
+        );
+    }
+
+    #[test]
+    fn a_condition_only_used_by_the_branch_is_folded_into_it() {
+        let folded = fold_compare_and_branch(compare_then_branch(Stack(-4)));
+        // je on the setl result jumps when the comparison failed
+        assert_eq!(
+            folded,
+            vec![
+                Cmp(Reg(R10), Reg(AX)),
+                JmpCC(AConditionCode::GE, String::from("else")),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_condition_read_again_later_is_still_written() {
+        // the slot is used beyond the three places the pattern itself touches
+        let mut instructions = compare_then_branch(Stack(-4));
+        instructions.push(Mov(Stack(-4), Reg(AX)));
+        let folded = fold_compare_and_branch(instructions);
+        assert_eq!(
+            folded,
+            vec![
+                Cmp(Reg(R10), Reg(AX)),
+                Mov(Imm(0), Stack(-4)),
+                SetCC(AConditionCode::L, Stack(-4)),
+                JmpCC(AConditionCode::GE, String::from("else")),
+                Mov(Stack(-4), Reg(AX)),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_condition_in_a_register_is_still_written() {
+        // only stack slots are known not to be read by anything the function doesn't show
+        let folded = fold_compare_and_branch(compare_then_branch(Reg(DX)));
+        assert_eq!(
+            folded,
+            vec![
+                Cmp(Reg(R10), Reg(AX)),
+                Mov(Imm(0), Reg(DX)),
+                SetCC(AConditionCode::L, Reg(DX)),
+                JmpCC(AConditionCode::GE, String::from("else")),
+            ]
+        );
+    }
+
+    #[test]
+    fn multiplying_by_a_power_of_two_is_a_left_shift() {
+        let multiply = vec![Binary(ABinaryOperatorNode::Mult, Imm(8), Reg(AX))];
+        let reduced = strength_reduce(multiply.clone());
+        assert_eq!(
+            reduced,
+            vec![Binary(ABinaryOperatorNode::Sal, Imm(3), Reg(AX))]
+        );
+        for value in [-5, -1, 0, 7, 1 << 28] {
+            let mut expected = HashMap::from([(AX, value)]);
+            run(&multiply, &mut expected);
+            let mut got = HashMap::from([(AX, value)]);
+            run(&reduced, &mut got);
+            assert_eq!(got, expected, "{value} * 8");
+        }
+        // other factors are left as they are
+        let multiply = vec![Binary(ABinaryOperatorNode::Mult, Imm(6), Reg(AX))];
+        assert_eq!(strength_reduce(multiply.clone()), multiply);
+    }
+
+    #[test]
+    fn dividing_by_a_power_of_two_rounds_negative_dividends_towards_zero() {
+        let divide = vec![
+            Cdq,
+            Mov(Imm(4), Reg(R10)),
+            Idiv(Reg(R10)),
+            Mov(Reg(AX), Reg(DX)),
+        ];
+        let reduced = strength_reduce(divide.clone());
+        assert_eq!(
+            reduced,
+            vec![
+                Cdq,
+                Binary(ABinaryOperatorNode::And, Imm(3), Reg(DX)),
+                Binary(ABinaryOperatorNode::Add, Reg(DX), Reg(AX)),
+                Binary(ABinaryOperatorNode::Sar, Imm(2), Reg(AX)),
+                Mov(Reg(AX), Reg(DX)),
+            ]
+        );
+        // an arithmetic shift alone would give -2 for -7 / 4
+        for dividend in [-8, -7, -4, -1, 0, 1, 7, i32::MIN, i32::MAX] {
+            let mut expected = HashMap::from([(AX, dividend)]);
+            run(&divide, &mut expected);
+            let mut got = HashMap::from([(AX, dividend)]);
+            run(&reduced, &mut got);
+            assert_eq!(got[&DX], expected[&DX], "{dividend} / 4");
+            assert_eq!(got[&DX], dividend / 4, "{dividend} / 4");
+        }
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+
+use tracing::debug;
+
+use super::{
+    asm_tree::*,
+    cfg::{ControlFlowGraph, NodeId},
+};
+
+// registers the allocator may hand out, caller-saved ones first so they are preferred; R10 and
+// R11 are kept free as scratch registers for fixing up invalid instructions, and RBP and RSP
+// hold the frame
+const ALLOCATABLE_REGISTERS: [ARegisterNode; 12] = [
+    ARegisterNode::AX,
+    ARegisterNode::CX,
+    ARegisterNode::DX,
+    ARegisterNode::SI,
+    ARegisterNode::DI,
+    ARegisterNode::R8,
+    ARegisterNode::R9,
+    ARegisterNode::BX,
+    ARegisterNode::R12,
+    ARegisterNode::R13,
+    ARegisterNode::R14,
+    ARegisterNode::R15,
+];
+
+// a node in the interference graph, either a hard register or a pseudoregister competing for one
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Location {
+    Register(ARegisterNode),
+    Pseudo(String),
+}
+
+type LiveLocations = BTreeSet<Location>;
+
+// aggregates are addressed by offset, so they always live on the stack
+fn aggregate_names(instructions: &[AInstructionNode]) -> HashSet<String> {
+    let mut names = HashSet::new();
+    for instruction in instructions {
+        let operands = match instruction {
+            AInstructionNode::Mov(op1, op2)
+            | AInstructionNode::Movq(op1, op2)
+            | AInstructionNode::Binary(_, op1, op2)
+            | AInstructionNode::Cmp(op1, op2)
+            | AInstructionNode::Test(op1, op2) => vec![op1, op2],
+            AInstructionNode::Lea(address, dst) => {
+                let mut operands = address.operands();
+                operands.push(dst);
+                operands
+            }
+            AInstructionNode::Unary(_, operand)
+            | AInstructionNode::Idiv(operand)
+            | AInstructionNode::SetCC(_, operand)
+            | AInstructionNode::LoadAddress(operand, _) => vec![operand],
+            _ => vec![],
+        };
+        for operand in operands {
+            if let AOperandNode::PseudoMem(name, _) = operand {
+                names.insert(name.clone());
+            }
+        }
+    }
+    names
+}
+
+struct Allocator {
+    aggregates: HashSet<String>,
+    registers: Vec<ARegisterNode>,
+}
+
+impl Allocator {
+    fn location_of(&self, operand: &AOperandNode) -> Option<Location> {
+        match operand {
+            AOperandNode::Reg(reg) if self.registers.contains(reg) => {
+                Some(Location::Register(*reg))
+            }
+            AOperandNode::Pseudo(name) if !self.aggregates.contains(name) => {
+                Some(Location::Pseudo(name.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    // the locations an instruction reads and the ones it writes
+    fn uses_and_defs(&self, instruction: &AInstructionNode) -> (Vec<Location>, Vec<Location>) {
+        let ax = Location::Register(ARegisterNode::AX);
+        let dx = Location::Register(ARegisterNode::DX);
+        let (uses, defs): (Vec<&AOperandNode>, Vec<&AOperandNode>) = match instruction {
+            AInstructionNode::Mov(src, dst) | AInstructionNode::Movq(src, dst) => {
+                (vec![src], vec![dst])
+            }
+            AInstructionNode::Binary(_, src, dst) => (vec![src, dst], vec![dst]),
+            AInstructionNode::Unary(_, dst) => (vec![dst], vec![dst]),
+            AInstructionNode::Cmp(op1, op2) | AInstructionNode::Test(op1, op2) => {
+                (vec![op1, op2], vec![])
+            }
+            AInstructionNode::Lea(address, dst) => (address.operands(), vec![dst]),
+            // setcc only writes the low byte, so the rest of the register has to survive
+            AInstructionNode::SetCC(_, dst) => (vec![dst], vec![dst]),
+            AInstructionNode::Idiv(divisor) => {
+                let mut uses: Vec<Location> = self.location_of(divisor).into_iter().collect();
+                uses.extend([ax.clone(), dx.clone()]);
+                return (uses, vec![ax, dx]);
+            }
+            AInstructionNode::Cdq => return (vec![ax], vec![dx]),
+            // the address is of an aggregate, which is never in a register
+            AInstructionNode::LoadAddress(_, dst) => {
+                let defs = self.location_of(&AOperandNode::Reg(*dst)).into_iter();
+                return (vec![], defs.collect());
+            }
+            AInstructionNode::Push(reg) => return (vec![Location::Register(*reg)], vec![]),
+            // a call reads its register arguments and may overwrite every caller-saved register
+            AInstructionNode::Call(_, register_arguments) => {
+                let uses = ARGUMENT_REGISTERS[..*register_arguments]
+                    .iter()
+                    .map(|reg| Location::Register(*reg))
+                    .collect();
+                let defs = self
+                    .registers
+                    .iter()
+                    .filter(|reg| !reg.is_callee_saved())
+                    .map(|reg| Location::Register(*reg))
+                    .collect();
+                return (uses, defs);
+            }
+            // nothing runs after a tail call, so only its arguments are live
+            AInstructionNode::TailCall(_, register_arguments) => {
+                let uses = ARGUMENT_REGISTERS[..*register_arguments]
+                    .iter()
+                    .map(|reg| Location::Register(*reg))
+                    .collect();
+                return (uses, vec![]);
+            }
+            AInstructionNode::Ret => return (vec![ax], vec![]),
+            _ => (vec![], vec![]),
+        };
+        // writing to memory reads the registers used to address it, but no operand does that yet
+        let uses = uses
+            .into_iter()
+            .filter_map(|operand| self.location_of(operand))
+            .collect();
+        let defs = defs
+            .into_iter()
+            .filter_map(|operand| self.location_of(operand))
+            .collect();
+        (uses, defs)
+    }
+
+    fn transfer(&self, instruction: &AInstructionNode, live: &mut LiveLocations) {
+        let (uses, defs) = self.uses_and_defs(instruction);
+        for def in defs {
+            live.remove(&def);
+        }
+        live.extend(uses);
+    }
+
+    // backward liveness analysis, giving the locations live at the end of each block
+    fn find_live_locations(
+        &self,
+        graph: &ControlFlowGraph<AInstructionNode>,
+    ) -> HashMap<usize, LiveLocations> {
+        let mut incoming: HashMap<usize, LiveLocations> = graph
+            .blocks
+            .keys()
+            .map(|id| (*id, LiveLocations::new()))
+            .collect();
+        let live_out = |incoming: &HashMap<usize, LiveLocations>, id: usize| {
+            let mut live = LiveLocations::new();
+            for successor in graph.successors(NodeId::Block(id)) {
+                if let NodeId::Block(successor_id) = successor {
+                    live.extend(incoming[successor_id].iter().cloned());
+                }
+            }
+            live
+        };
+
+        let mut worklist: VecDeque<usize> = graph.blocks.keys().rev().copied().collect();
+        while let Some(id) = worklist.pop_front() {
+            let mut live = live_out(&incoming, id);
+            for instruction in graph.blocks[&id].instructions.iter().rev() {
+                self.transfer(instruction, &mut live);
+            }
+            if live != incoming[&id] {
+                incoming.insert(id, live);
+                for predecessor in graph.predecessors(NodeId::Block(id)) {
+                    if let NodeId::Block(predecessor_id) = predecessor {
+                        if !worklist.contains(predecessor_id) {
+                            worklist.push_back(*predecessor_id);
+                        }
+                    }
+                }
+            }
+        }
+        graph
+            .blocks
+            .keys()
+            .map(|id| (*id, live_out(&incoming, *id)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
+struct InterferenceGraph {
+    neighbours: BTreeMap<Location, BTreeSet<Location>>,
+}
+
+impl InterferenceGraph {
+    fn add_node(&mut self, node: &Location) {
+        self.neighbours.entry(node.clone()).or_default();
+    }
+
+    fn add_edge(&mut self, a: &Location, b: &Location) {
+        if a == b {
+            return;
+        }
+        self.neighbours
+            .entry(a.clone())
+            .or_default()
+            .insert(b.clone());
+        self.neighbours
+            .entry(b.clone())
+            .or_default()
+            .insert(a.clone());
+    }
+}
+
+fn build_interference_graph(
+    allocator: &Allocator,
+    instructions: Vec<AInstructionNode>,
+) -> (InterferenceGraph, Vec<AInstructionNode>) {
+    let mut interference = InterferenceGraph::default();
+    for (index, a) in allocator.registers.iter().enumerate() {
+        for b in &allocator.registers[index + 1..] {
+            interference.add_edge(&Location::Register(*a), &Location::Register(*b));
+        }
+    }
+
+    let graph = ControlFlowGraph::new(instructions);
+    let live_out = allocator.find_live_locations(&graph);
+    for (id, block) in &graph.blocks {
+        let mut live = live_out[id].clone();
+        for instruction in block.instructions.iter().rev() {
+            let (uses, defs) = allocator.uses_and_defs(instruction);
+            for location in uses.iter().chain(defs.iter()) {
+                interference.add_node(location);
+            }
+            for def in &defs {
+                for live_location in &live {
+                    // the source and destination of a move hold the same value, so they may
+                    // share a register
+                    let is_move_source = matches!(
+                        instruction,
+                        AInstructionNode::Mov(src, _)
+                            if allocator.location_of(src).as_ref() == Some(live_location)
+                    );
+                    if !is_move_source {
+                        interference.add_edge(def, live_location);
+                    }
+                }
+            }
+            allocator.transfer(instruction, &mut live);
+        }
+    }
+    (interference, graph.into_instructions())
+}
+
+// how many times each pseudoregister is read or written, used as the cost of spilling it
+fn spill_costs(allocator: &Allocator, instructions: &[AInstructionNode]) -> HashMap<String, usize> {
+    let mut costs = HashMap::new();
+    for instruction in instructions {
+        let (uses, defs) = allocator.uses_and_defs(instruction);
+        for location in uses.into_iter().chain(defs) {
+            if let Location::Pseudo(name) = location {
+                *costs.entry(name).or_insert(0) += 1;
+            }
+        }
+    }
+    costs
+}
+
+// Chaitin-Briggs colouring: nodes with fewer neighbours than there are registers are removed
+// first since they can always be coloured, and when none are left the cheapest node to spill is
+// removed optimistically, in the hope that its neighbours end up sharing registers
+fn colour_graph(
+    registers: &[ARegisterNode],
+    interference: &InterferenceGraph,
+    costs: &HashMap<String, usize>,
+) -> HashMap<String, ARegisterNode> {
+    let register_count = registers.len();
+    let mut remaining: BTreeSet<String> = interference
+        .neighbours
+        .keys()
+        .filter_map(|node| match node {
+            Location::Pseudo(name) => Some(name.clone()),
+            Location::Register(_) => None,
+        })
+        .collect();
+    let degree = |name: &String, remaining: &BTreeSet<String>| {
+        interference.neighbours[&Location::Pseudo(name.clone())]
+            .iter()
+            .filter(|neighbour| match neighbour {
+                Location::Register(_) => true,
+                Location::Pseudo(other) => remaining.contains(other),
+            })
+            .count()
+    };
+
+    let mut removed = vec![];
+    while !remaining.is_empty() {
+        let trivially_colourable = remaining
+            .iter()
+            .find(|name| degree(name, &remaining) < register_count)
+            .cloned();
+        let next = match trivially_colourable {
+            Some(name) => name,
+            None => remaining
+                .iter()
+                .min_by(|a, b| {
+                    let metric = |name: &String| {
+                        costs.get(name).copied().unwrap_or(0) as f64
+                            / degree(name, &remaining) as f64
+                    };
+                    metric(a).total_cmp(&metric(b))
+                })
+                .cloned()
+                .unwrap(),
+        };
+        remaining.remove(&next);
+        removed.push(next);
+    }
+
+    let mut colours: HashMap<String, ARegisterNode> = HashMap::new();
+    while let Some(name) = removed.pop() {
+        let taken: HashSet<ARegisterNode> = interference.neighbours
+            [&Location::Pseudo(name.clone())]
+            .iter()
+            .filter_map(|neighbour| match neighbour {
+                Location::Register(reg) => Some(*reg),
+                Location::Pseudo(other) => colours.get(other).copied(),
+            })
+            .collect();
+        match registers.iter().find(|reg| !taken.contains(reg)) {
+            Some(reg) => {
+                colours.insert(name, *reg);
+            }
+            None => debug!("spilling {name} to the stack"),
+        }
+    }
+    colours
+}
+
+fn replace_operand(
+    operand: AOperandNode,
+    colours: &HashMap<String, ARegisterNode>,
+) -> AOperandNode {
+    if let AOperandNode::Pseudo(name) = &operand {
+        if let Some(reg) = colours.get(name) {
+            return AOperandNode::Reg(*reg);
+        }
+    }
+    operand
+}
+
+fn replace_instruction(
+    instruction: AInstructionNode,
+    colours: &HashMap<String, ARegisterNode>,
+) -> AInstructionNode {
+    use AInstructionNode::*;
+    match instruction {
+        Mov(src, dst) => Mov(replace_operand(src, colours), replace_operand(dst, colours)),
+        Movq(src, dst) => Movq(replace_operand(src, colours), replace_operand(dst, colours)),
+        Binary(operator, src, dst) => Binary(
+            operator,
+            replace_operand(src, colours),
+            replace_operand(dst, colours),
+        ),
+        Unary(operator, dst) => Unary(operator, replace_operand(dst, colours)),
+        Cmp(op1, op2) => Cmp(replace_operand(op1, colours), replace_operand(op2, colours)),
+        Test(op1, op2) => Test(replace_operand(op1, colours), replace_operand(op2, colours)),
+        Lea(address, dst) => Lea(
+            address.map_operands(|operand| replace_operand(operand.clone(), colours)),
+            replace_operand(dst, colours),
+        ),
+        Idiv(divisor) => Idiv(replace_operand(divisor, colours)),
+        SetCC(cc, dst) => SetCC(cc, replace_operand(dst, colours)),
+        other => other,
+    }
+}
+
+// assigns registers to as many pseudoregisters as possible, leaving the rest for the stack, and
+// returns the callee-saved registers that ended up in use; without a frame pointer, %rbp is
+// one more callee-saved register to use
+#[tracing::instrument(skip_all)]
+pub fn allocate_registers(
+    instructions: Vec<AInstructionNode>,
+    omit_frame_pointer: bool,
+) -> (Vec<AInstructionNode>, Vec<ARegisterNode>) {
+    let mut registers = ALLOCATABLE_REGISTERS.to_vec();
+    if omit_frame_pointer {
+        registers.push(ARegisterNode::BP);
+    }
+    let allocator = Allocator {
+        aggregates: aggregate_names(&instructions),
+        registers,
+    };
+    let costs = spill_costs(&allocator, &instructions);
+    let (interference, instructions) = build_interference_graph(&allocator, instructions);
+    let colours = colour_graph(&allocator.registers, &interference, &costs);
+    debug!("allocated {} pseudoregisters to registers", colours.len());
+
+    let instructions = instructions
+        .into_iter()
+        .map(|instruction| replace_instruction(instruction, &colours))
+        .filter(|instruction| {
+            !matches!(instruction, AInstructionNode::Mov(AOperandNode::Reg(src), AOperandNode::Reg(dst)) if src == dst)
+        })
+        .collect();
+    let callee_saved: BTreeSet<ARegisterNode> = colours
+        .values()
+        .filter(|reg| reg.is_callee_saved())
+        .copied()
+        .collect();
+    (instructions, callee_saved.into_iter().collect())
+}
@@ -1,14 +1,21 @@
-use super::ast_tree::{BinaryOperatorNode, UnaryOperatorNode};
+use super::{
+    ast_tree::{BinaryOperatorNode, UnaryOperatorNode},
+    cfg::NodeId,
+    tokens::SourceLocation,
+    types::FunctionTypes,
+};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum TValNode {
-    Constant(usize),
+    Constant(i32),
     Var(String),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum TInstructionNode {
-    Return(TValNode),
+    // where the return statement is, as a returned variable or constant has no location of its
+    // own; the return added at the end of a function has none
+    Return(TValNode, Option<SourceLocation>),
     Unary(UnaryOperatorNode, TValNode, TValNode),
     Binary(BinaryOperatorNode, TValNode, TValNode, TValNode),
     Copy(TValNode, TValNode),
@@ -16,14 +23,23 @@ pub enum TInstructionNode {
     JumpIfZero(TValNode, String),
     JumpIfNotZero(TValNode, String),
     Label(String),
+    CopyToOffset(TValNode, String, usize),
+    CopyFromOffset(String, usize, TValNode),
+    FunCall(String, Vec<TValNode>, TValNode),
+    // only present in SSA form: the destination takes the value paired with whichever
+    // predecessor block control arrived from
+    Phi(TValNode, Vec<(NodeId, TValNode)>),
 }
 
+// a structure passed or returned by value is named as a whole by a variable that is otherwise only
+// read and written through CopyFromOffset and CopyToOffset
 #[derive(Debug)]
 pub enum TFunctionDefinitionNode {
-    Function(String, Vec<TInstructionNode>),
+    Function(String, Vec<String>, Vec<TInstructionNode>),
 }
 
 #[derive(Debug)]
 pub enum TProgramNode {
-    Program(TFunctionDefinitionNode),
+    // the types of every function declared, defined here or not, for lowering calls
+    Program(Vec<TFunctionDefinitionNode>, FunctionTypes),
 }
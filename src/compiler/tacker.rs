@@ -1,14 +1,23 @@
-use std::sync::Mutex;
+use std::{collections::BTreeMap, sync::Mutex};
 
 use tracing::{debug, error};
 
-use super::{ast_tree::*, tac_tree::*};
+use super::{
+    ast_tree::*,
+    const_eval::evaluate_constant_expression,
+    tac_tree::*,
+    tokens::SourceLocation,
+    types::{typedef_as_value, IdentifierEntry, SymbolTable, TagKind},
+};
 
 static TEMPORARY_COUNTER: Mutex<usize> = Mutex::new(0);
 static LABEL_COUNTER: Mutex<usize> = Mutex::new(0);
+// where in the source each temporary and label was made, so that the optimiser can say which
+// code it changed; the names are unique across the whole program
+static SOURCE_LOCATIONS: Mutex<BTreeMap<String, SourceLocation>> = Mutex::new(BTreeMap::new());
 
 #[tracing::instrument]
-fn make_temporary_var() -> String {
+pub fn make_temporary_var() -> String {
     debug!("temporary variable creation called");
     match TEMPORARY_COUNTER.lock() {
         Ok(mut counter) => {
@@ -25,13 +34,8 @@ fn make_temporary_var() -> String {
 }
 
 #[tracing::instrument]
-fn make_label_name(op: &BinaryOperatorNode, suffix: &str) -> String {
+pub fn make_label_name(label_name: &str, suffix: &str) -> String {
     debug!("label name creation called");
-    let label_name = match op {
-        BinaryOperatorNode::And => "false_label",
-        BinaryOperatorNode::Or => "true_label",
-        _ => unimplemented!(),
-    };
     match LABEL_COUNTER.lock() {
         Ok(mut counter) => {
             let temp = *counter;
@@ -46,15 +50,129 @@ fn make_label_name(op: &BinaryOperatorNode, suffix: &str) -> String {
     }
 }
 
+pub fn record_source_location(name: &str, location: SourceLocation) {
+    match SOURCE_LOCATIONS.lock() {
+        Ok(mut locations) => {
+            locations.insert(name.to_owned(), location);
+        }
+        Err(e) => {
+            error!("source location table mutex was poisoned: {e:?}");
+            panic!("Concurrency panic!");
+        }
+    }
+}
+
+pub fn source_location(name: &str) -> Option<SourceLocation> {
+    match SOURCE_LOCATIONS.lock() {
+        Ok(locations) => locations.get(name).copied(),
+        Err(e) => {
+            error!("source location table mutex was poisoned: {e:?}");
+            panic!("Concurrency panic!");
+        }
+    }
+}
+
+fn make_located_temporary(location: SourceLocation) -> String {
+    let name = make_temporary_var();
+    record_source_location(&name, location);
+    name
+}
+
+fn make_located_label(label_name: &str, suffix: &str, location: SourceLocation) -> String {
+    let name = make_label_name(label_name, suffix);
+    record_source_location(&name, location);
+    name
+}
+
+// the result of tacking an expression is either a plain value or a member of some aggregate
+#[derive(Debug, Clone)]
+enum ExpResult {
+    PlainOperand(TValNode),
+    SubObject(String, usize),
+}
+
+// returns the base object and byte offset backing an aggregate expression result
+fn object_location(result: &ExpResult) -> (String, usize) {
+    match result {
+        ExpResult::PlainOperand(TValNode::Var(name)) => (name.to_owned(), 0),
+        ExpResult::SubObject(base, offset) => (base.to_owned(), *offset),
+        ExpResult::PlainOperand(constant) => {
+            error!("constant {constant:?} cannot be used as an object");
+            panic!("Semantic error!");
+        }
+    }
+}
+
+fn copy_aggregate(
+    source: &ExpResult,
+    destination: &ExpResult,
+    size: usize,
+    instruction_buffer: &mut Vec<TInstructionNode>,
+) {
+    let (src_name, src_offset) = object_location(source);
+    let (dst_name, dst_offset) = object_location(destination);
+    // every scalar member is 4 bytes wide, so aggregates are copied one 4-byte chunk at a time
+    for chunk in (0..size).step_by(4) {
+        let dst = TValNode::Var(make_temporary_var());
+        instruction_buffer.push(TInstructionNode::CopyFromOffset(
+            src_name.clone(),
+            src_offset + chunk,
+            dst.clone(),
+        ));
+        instruction_buffer.push(TInstructionNode::CopyToOffset(
+            dst,
+            dst_name.clone(),
+            dst_offset + chunk,
+        ));
+    }
+}
+
+// an aggregate passed or returned by value has to be a whole object of its own, so a member of
+// another one is copied out into a temporary first
+fn tack_aggregate(
+    expression: ExpressionNode,
+    instruction_buffer: &mut Vec<TInstructionNode>,
+    symbols: &mut SymbolTable,
+) -> TValNode {
+    let location = expression.location();
+    let size = symbols.size_of_expression(&expression);
+    match tack_exp(expression, instruction_buffer, symbols) {
+        ExpResult::PlainOperand(value) => value,
+        member @ ExpResult::SubObject(..) => {
+            let copy = TValNode::Var(make_located_temporary(location));
+            let destination = ExpResult::PlainOperand(copy.clone());
+            copy_aggregate(&member, &destination, size, instruction_buffer);
+            copy
+        }
+    }
+}
+
+// tacks a value being passed or returned as the given type, which has already been checked
+fn tack_value_as(
+    value_type: &TypeNode,
+    expression: ExpressionNode,
+    instruction_buffer: &mut Vec<TInstructionNode>,
+    symbols: &mut SymbolTable,
+) -> TValNode {
+    match value_type {
+        TypeNode::Struct(_) | TypeNode::Union(_) => {
+            tack_aggregate(expression, instruction_buffer, symbols)
+        }
+        _ => tack_exp_and_convert(expression, instruction_buffer, symbols),
+    }
+}
+
 fn handle_regular_operation(
     operator: BinaryOperatorNode,
     op1: Box<ExpressionNode>,
     op2: Box<ExpressionNode>,
+    location: SourceLocation,
     instruction_buffer: &mut Vec<TInstructionNode>,
+    symbols: &mut SymbolTable,
 ) -> TValNode {
-    let v1 = tack_exp(*op1, instruction_buffer);
-    let v2 = tack_exp(*op2, instruction_buffer);
-    let dst_name = make_temporary_var();
+    let v1 = tack_exp_and_convert(*op1, instruction_buffer, symbols);
+    let v2 = tack_exp_and_convert(*op2, instruction_buffer, symbols);
+    let dst_name = make_located_temporary(location);
     let dst = TValNode::Var(dst_name);
     let ret = dst.clone();
     instruction_buffer.push(TInstructionNode::Binary(operator, v1, v2, dst));
@@ -65,10 +183,17 @@ fn handle_shortcircuiting_operation(
     operator: BinaryOperatorNode,
     op1: Box<ExpressionNode>,
     op2: Box<ExpressionNode>,
+    location: SourceLocation,
     instruction_buffer: &mut Vec<TInstructionNode>,
+    symbols: &mut SymbolTable,
 ) -> TValNode {
-    let sclabel_name = make_label_name(&operator, "");
-    let end_label_name = make_label_name(&operator, "_end");
+    let base_name = match operator {
+        BinaryOperatorNode::And => "false_label",
+        BinaryOperatorNode::Or => "true_label",
+        _ => unimplemented!(),
+    };
+    let sclabel_name = make_located_label(base_name, "", location);
+    let end_label_name = make_located_label(base_name, "_end", location);
     let shortcircuit_label = TInstructionNode::Label(sclabel_name.clone());
     let end_label = TInstructionNode::Label(end_label_name.clone());
     let jump_op = match operator {
@@ -76,9 +201,9 @@ fn handle_shortcircuiting_operation(
         BinaryOperatorNode::Or => TInstructionNode::JumpIfNotZero,
         _ => unimplemented!(),
     };
-    let v1 = tack_exp(*op1, instruction_buffer);
+    let v1 = tack_exp_and_convert(*op1, instruction_buffer, symbols);
     instruction_buffer.push(jump_op(v1, sclabel_name.clone()));
-    let v2 = tack_exp(*op2, instruction_buffer);
+    let v2 = tack_exp_and_convert(*op2, instruction_buffer, symbols);
     instruction_buffer.push(jump_op(v2, sclabel_name));
     let jump_val = match operator {
         BinaryOperatorNode::And => 0,
@@ -90,7 +215,7 @@ fn handle_shortcircuiting_operation(
         BinaryOperatorNode::Or => 0,
         _ => unimplemented!(),
     };
-    let dst_name = make_temporary_var();
+    let dst_name = make_located_temporary(location);
     let dst = TValNode::Var(dst_name);
     instruction_buffer.push(TInstructionNode::Copy(
         TValNode::Constant(no_jump_val),
@@ -107,67 +232,528 @@ fn handle_shortcircuiting_operation(
     return ret;
 }
 
+fn handle_conditional(
+    condition: ExpressionNode,
+    then_branch: ExpressionNode,
+    else_branch: ExpressionNode,
+    location: SourceLocation,
+    instruction_buffer: &mut Vec<TInstructionNode>,
+    symbols: &mut SymbolTable,
+) -> TValNode {
+    let else_label_name = make_located_label("else_label", "", location);
+    let end_label_name = make_located_label("else_label", "_end", location);
+    let dst = TValNode::Var(make_located_temporary(location));
+    // a structure or union chosen by the condition is copied into the result whole
+    let aggregate_size = match symbols.type_of_expression(&then_branch) {
+        TypeNode::Struct(_) | TypeNode::Union(_) => Some(symbols.size_of_expression(&then_branch)),
+        _ => None,
+    };
+    let c = tack_exp_and_convert(condition, instruction_buffer, symbols);
+    instruction_buffer.push(TInstructionNode::JumpIfZero(c, else_label_name.clone()));
+    tack_branch(
+        then_branch,
+        &dst,
+        aggregate_size,
+        instruction_buffer,
+        symbols,
+    );
+    instruction_buffer.push(TInstructionNode::Jump(end_label_name.clone()));
+    instruction_buffer.push(TInstructionNode::Label(else_label_name));
+    tack_branch(
+        else_branch,
+        &dst,
+        aggregate_size,
+        instruction_buffer,
+        symbols,
+    );
+    instruction_buffer.push(TInstructionNode::Label(end_label_name));
+    dst
+}
+
+fn tack_branch(
+    branch: ExpressionNode,
+    dst: &TValNode,
+    aggregate_size: Option<usize>,
+    instruction_buffer: &mut Vec<TInstructionNode>,
+    symbols: &mut SymbolTable,
+) {
+    match aggregate_size {
+        Some(size) => {
+            let value = tack_exp(branch, instruction_buffer, symbols);
+            let destination = ExpResult::PlainOperand(dst.clone());
+            copy_aggregate(&value, &destination, size, instruction_buffer);
+        }
+        None => {
+            let value = tack_exp_and_convert(branch, instruction_buffer, symbols);
+            instruction_buffer.push(TInstructionNode::Copy(value, dst.clone()));
+        }
+    }
+}
+
+fn handle_assignment(
+    lhs: Box<ExpressionNode>,
+    rhs: Box<ExpressionNode>,
+    instruction_buffer: &mut Vec<TInstructionNode>,
+    symbols: &mut SymbolTable,
+) -> ExpResult {
+    let is_lvalue = match &*lhs {
+        ExpressionNode::Var(name, _) => {
+            matches!(symbols.lookup(name), IdentifierEntry::Variable(_))
+        }
+        ExpressionNode::Dot(..) => true,
+        _ => false,
+    };
+    if !is_lvalue {
+        error!("invalid lvalue {lhs:?} on the left of an assignment");
+        panic!("Semantic error!");
+    }
+    let lhs_type = symbols.type_of_expression(&lhs);
+    let target = tack_exp(*lhs, instruction_buffer, symbols);
+    match lhs_type {
+        TypeNode::Struct(_) | TypeNode::Union(_) => {
+            let rhs_type = symbols.type_of_expression(&rhs);
+            if rhs_type != lhs_type {
+                error!("cannot assign '{rhs_type}' to '{lhs_type}'");
+                panic!("Semantic error!");
+            }
+            let source = tack_exp(*rhs, instruction_buffer, symbols);
+            let size = symbols.types.size_of(&lhs_type);
+            copy_aggregate(&source, &target, size, instruction_buffer);
+            target
+        }
+        TypeNode::Typedef(_) | TypeNode::Pointer(_) => {
+            unreachable!("variable types are resolved when they are declared")
+        }
+        TypeNode::Int | TypeNode::Enum(_) => {
+            let value = tack_exp_and_convert(*rhs, instruction_buffer, symbols);
+            match target {
+                ExpResult::PlainOperand(dst) => {
+                    instruction_buffer.push(TInstructionNode::Copy(value, dst.clone()));
+                    ExpResult::PlainOperand(dst)
+                }
+                ExpResult::SubObject(base, offset) => {
+                    instruction_buffer.push(TInstructionNode::CopyToOffset(
+                        value.clone(),
+                        base,
+                        offset,
+                    ));
+                    ExpResult::PlainOperand(value)
+                }
+            }
+        }
+    }
+}
+
 fn tack_exp(
     expression: ExpressionNode,
     instruction_buffer: &mut Vec<TInstructionNode>,
-) -> TValNode {
+    symbols: &mut SymbolTable,
+) -> ExpResult {
     match expression {
-        ExpressionNode::Constant(c) => TValNode::Constant(c),
-        ExpressionNode::Unary(operator, exp) => {
-            let src = tack_exp(*exp, instruction_buffer);
-            let dst_name = make_temporary_var();
+        ExpressionNode::Constant(literal, _) => {
+            ExpResult::PlainOperand(TValNode::Constant(literal.as_int()))
+        }
+        ExpressionNode::Var(name, _) => match symbols.lookup(&name) {
+            IdentifierEntry::Variable(_) => ExpResult::PlainOperand(TValNode::Var(name)),
+            IdentifierEntry::Enumerator(value) => {
+                ExpResult::PlainOperand(TValNode::Constant(*value))
+            }
+            IdentifierEntry::Typedef(_) => typedef_as_value(&name),
+            IdentifierEntry::Function(_) => {
+                error!("function {name} used as a value");
+                panic!("Semantic error!");
+            }
+        },
+        ExpressionNode::Unary(operator, exp, location) => {
+            symbols.ensure_scalar(&exp);
+            let src = tack_exp_and_convert(*exp, instruction_buffer, symbols);
+            let dst_name = make_located_temporary(location);
             let dst = TValNode::Var(dst_name);
             let ret = dst.clone();
             instruction_buffer.push(TInstructionNode::Unary(operator, src, dst));
-            return ret;
+            ExpResult::PlainOperand(ret)
         }
-        ExpressionNode::Binary(operator, op1, op2) => {
+        ExpressionNode::Binary(operator, op1, op2, location) => {
+            symbols.ensure_scalar(&op1);
+            symbols.ensure_scalar(&op2);
             use BinaryOperatorNode::*;
-            match operator {
+            let result = match operator {
                 Add | Subtract | Multiply | Divide | Remainder | Equal | NotEqual | GreaterThan
-                | GreaterOrEqual | LessThan | LessOrEqual => {
-                    handle_regular_operation(operator, op1, op2, instruction_buffer)
+                | GreaterOrEqual | LessThan | LessOrEqual => handle_regular_operation(
+                    operator,
+                    op1,
+                    op2,
+                    location,
+                    instruction_buffer,
+                    symbols,
+                ),
+                _ => handle_shortcircuiting_operation(
+                    operator,
+                    op1,
+                    op2,
+                    location,
+                    instruction_buffer,
+                    symbols,
+                ),
+            };
+            ExpResult::PlainOperand(result)
+        }
+        ExpressionNode::Assignment(lhs, rhs, _) => {
+            handle_assignment(lhs, rhs, instruction_buffer, symbols)
+        }
+        ExpressionNode::Dot(inner, member, location) => {
+            let (TypeNode::Struct(tag) | TypeNode::Union(tag)) = symbols.type_of_expression(&inner)
+            else {
+                error!("member {member} accessed on a non-aggregate value at {location}");
+                panic!("Semantic error!");
+            };
+            let member_offset = match symbols.types.layout_of(&tag).member(&member) {
+                Some(layout) => layout.offset,
+                None => {
+                    error!("{tag} has no member named {member} at {location}");
+                    panic!("Semantic error!");
                 }
-                _ => handle_shortcircuiting_operation(operator, op1, op2, instruction_buffer),
-            }
+            };
+            let (base, offset) = object_location(&tack_exp(*inner, instruction_buffer, symbols));
+            ExpResult::SubObject(base, offset + member_offset)
+        }
+        ExpressionNode::Arrow(..) => {
+            symbols.type_of_expression(&expression);
+            unreachable!("member access through a pointer is rejected while typing")
+        }
+        ExpressionNode::Cast(target, inner, _) => {
+            symbols.cast_target_type(&target);
+            symbols.ensure_scalar(&inner);
+            ExpResult::PlainOperand(tack_exp_and_convert(*inner, instruction_buffer, symbols))
+        }
+        conditional @ ExpressionNode::Conditional(..) => {
+            symbols.type_of_expression(&conditional);
+            let ExpressionNode::Conditional(condition, then_branch, else_branch, location) =
+                conditional
+            else {
+                unreachable!()
+            };
+            ExpResult::PlainOperand(handle_conditional(
+                *condition,
+                *then_branch,
+                *else_branch,
+                location,
+                instruction_buffer,
+                symbols,
+            ))
+        }
+        ExpressionNode::SizeOfType(value_type, _) => {
+            let size = symbols.types.size_of(&symbols.lookup_type(&value_type));
+            ExpResult::PlainOperand(TValNode::Constant(
+                i32::try_from(size).expect("size does not fit in an int"),
+            ))
+        }
+        ExpressionNode::SizeOfExpression(inner, _) => {
+            let size = symbols.size_of_expression(&inner);
+            ExpResult::PlainOperand(TValNode::Constant(
+                i32::try_from(size).expect("size does not fit in an int"),
+            ))
+        }
+        call @ ExpressionNode::FunctionCall(..) => {
+            symbols.type_of_expression(&call);
+            let ExpressionNode::FunctionCall(name, arguments, location) = call else {
+                unreachable!()
+            };
+            let IdentifierEntry::Function(function_type) = symbols.lookup(&name) else {
+                unreachable!("typing the call checked that {name} is a function")
+            };
+            let parameters = function_type.parameters.clone();
+            let arguments = arguments
+                .into_iter()
+                .zip(&parameters)
+                .map(|(argument, parameter)| {
+                    tack_value_as(parameter, argument, instruction_buffer, symbols)
+                })
+                .collect();
+            let dst = TValNode::Var(make_located_temporary(location));
+            instruction_buffer.push(TInstructionNode::FunCall(name, arguments, dst.clone()));
+            ExpResult::PlainOperand(dst)
         }
-        _ => unimplemented!(),
     }
 }
 
-fn tack_instructions(statement: StatementNode) -> Vec<TInstructionNode> {
+// tacks an expression whose value is needed, loading struct members into a temporary
+fn tack_exp_and_convert(
+    expression: ExpressionNode,
+    instruction_buffer: &mut Vec<TInstructionNode>,
+    symbols: &mut SymbolTable,
+) -> TValNode {
+    let location = expression.location();
+    match tack_exp(expression, instruction_buffer, symbols) {
+        ExpResult::PlainOperand(val) => val,
+        ExpResult::SubObject(base, offset) => {
+            let dst = TValNode::Var(make_located_temporary(location));
+            instruction_buffer.push(TInstructionNode::CopyFromOffset(base, offset, dst.clone()));
+            dst
+        }
+    }
+}
+
+fn tack_instructions(statement: StatementNode, symbols: &mut SymbolTable) -> Vec<TInstructionNode> {
     let mut instruction_buffer: Vec<TInstructionNode> = vec![];
     match statement {
-        StatementNode::Return(expression) => {
-            let final_return =
-                TInstructionNode::Return(tack_exp(expression, &mut instruction_buffer));
-            instruction_buffer.push(final_return);
+        StatementNode::Return(expression, location) => {
+            let return_type = symbols.return_type().clone();
+            symbols.ensure_assignable(&return_type, &expression);
+            let value = tack_value_as(&return_type, expression, &mut instruction_buffer, symbols);
+            instruction_buffer.push(TInstructionNode::Return(value, Some(location)));
+        }
+        StatementNode::Expression(expression, _) => {
+            symbols.type_of_expression(&expression);
+            tack_exp(expression, &mut instruction_buffer, symbols);
+        }
+        StatementNode::Null(_) => (),
+    }
+    instruction_buffer
+}
+
+// enters what a declaration declares into the symbol table, without tacking its initializer
+pub fn declare(declaration: &DeclarationNode, symbols: &mut SymbolTable) {
+    match declaration {
+        DeclarationNode::Declaration(value_type, name, ..) => {
+            symbols.declare_variable(name, value_type)
+        }
+        DeclarationNode::FunctionDeclaration(return_type, name, parameters, _) => {
+            let parameter_types: Vec<TypeNode> = parameters
+                .iter()
+                .map(|(parameter_type, ..)| parameter_type.clone())
+                .collect();
+            symbols.declare_function(name, return_type, &parameter_types);
+        }
+        DeclarationNode::StructDeclaration(tag, Some(members), _) => {
+            symbols.define_aggregate(TagKind::Struct, tag, members)
         }
-        StatementNode::Expression(_) => todo!("implement tacking for instructions (expression)"),
-        StatementNode::Null => todo!("implement tacking for instructions (null)"),
+        DeclarationNode::StructDeclaration(tag, None, _) => {
+            symbols.types.declare_tag(TagKind::Struct, tag)
+        }
+        DeclarationNode::UnionDeclaration(tag, Some(members), _) => {
+            symbols.define_aggregate(TagKind::Union, tag, members)
+        }
+        DeclarationNode::UnionDeclaration(tag, None, _) => {
+            symbols.types.declare_tag(TagKind::Union, tag)
+        }
+        DeclarationNode::EnumDeclaration(tag, enumerators, _) => {
+            declare_enumerators(tag, enumerators, symbols)
+        }
+        DeclarationNode::TypedefDeclaration(value_type, name, _) => {
+            symbols.declare_typedef(name, value_type)
+        }
+    }
+}
+
+fn tack_declaration(
+    declaration: DeclarationNode,
+    symbols: &mut SymbolTable,
+) -> Vec<TInstructionNode> {
+    let mut instruction_buffer: Vec<TInstructionNode> = vec![];
+    declare(&declaration, symbols);
+    if let DeclarationNode::Declaration(_, name, Some(expression), _) = declaration {
+        let location = expression.location();
+        let assignment = ExpressionNode::Assignment(
+            Box::new(ExpressionNode::Var(name, location)),
+            Box::new(expression),
+            location,
+        );
+        tack_exp(assignment, &mut instruction_buffer, symbols);
     }
     return instruction_buffer;
 }
 
-fn tack_block_items(block_items: Vec<BlockItemNode>) -> Vec<TInstructionNode> {
+fn declare_enumerators(
+    tag: &Option<String>,
+    enumerators: &[EnumeratorNode],
+    symbols: &mut SymbolTable,
+) {
+    if let Some(tag) = tag {
+        symbols.types.define_enum(tag);
+    }
+    // enumerators without an explicit value continue counting from the previous one
+    let mut next_value: Option<i32> = Some(0);
+    for EnumeratorNode::Enumerator(name, value, _) in enumerators {
+        let value = match value {
+            Some(expression) => match evaluate_constant_expression(expression, symbols) {
+                Ok(value) => value,
+                Err(e) => {
+                    error!("value of enumerator {name} is not an integer constant: {e}");
+                    panic!("Semantic error!");
+                }
+            },
+            None => match next_value {
+                Some(value) => value,
+                None => {
+                    error!("value of enumerator {name} overflows an int");
+                    panic!("Semantic error!");
+                }
+            },
+        };
+        symbols.declare_enumerator(name, value);
+        next_value = value.checked_add(1);
+    }
+}
+
+fn tack_block_items(
+    block_items: Vec<BlockItemNode>,
+    symbols: &mut SymbolTable,
+) -> Vec<TInstructionNode> {
     let mut nodes = vec![];
     for block_item in block_items {
         let mut instruction_nodes: Vec<TInstructionNode> = match block_item {
-            BlockItemNode::DeclarationItem(_) => todo!("implement tacking for block"),
-            BlockItemNode::StatementItem(statement) => tack_instructions(statement),
+            BlockItemNode::DeclarationItem(declaration) => tack_declaration(declaration, symbols),
+            BlockItemNode::StatementItem(statement) => tack_instructions(statement, symbols),
         };
         nodes.append(&mut instruction_nodes);
     }
     return nodes;
 }
 
-fn tack_functions(function: FunctionDefinitionNode) -> TFunctionDefinitionNode {
-    let FunctionDefinitionNode::Function(name, block_items) = function;
-    return TFunctionDefinitionNode::Function(name, tack_block_items(block_items));
+fn tack_functions(
+    function: FunctionDefinitionNode,
+    symbols: &mut SymbolTable,
+) -> TFunctionDefinitionNode {
+    let FunctionDefinitionNode::Function(return_type, name, parameters, block_items, _) = function;
+    let parameter_types: Vec<TypeNode> = parameters
+        .iter()
+        .map(|(parameter_type, ..)| parameter_type.clone())
+        .collect();
+    let function_type = symbols.define_function(&name, &return_type, &parameter_types);
+    symbols.enter_scope();
+    for (parameter_type, parameter, _) in &parameters {
+        symbols.declare_variable(parameter, parameter_type);
+    }
+    let ends_in_return = matches!(
+        block_items.last(),
+        Some(BlockItemNode::StatementItem(StatementNode::Return(..)))
+    );
+    let mut instructions = tack_block_items(block_items, symbols);
+    symbols.exit_scope();
+    // falling off the end of main returns 0, and of any other function leaves the result
+    // undefined, so returning 0 covers both, and a structure comes from a temporary that is never
+    // written; there are no branching statements, so a function whose last statement is a return
+    // never gets there
+    if !ends_in_return {
+        let value = match function_type.return_type {
+            TypeNode::Struct(_) | TypeNode::Union(_) => TValNode::Var(make_temporary_var()),
+            _ => TValNode::Constant(0),
+        };
+        instructions.push(TInstructionNode::Return(value, None));
+    }
+    let parameters = parameters.into_iter().map(|(_, name, _)| name).collect();
+    TFunctionDefinitionNode::Function(name, parameters, instructions)
 }
 
 #[tracing::instrument(skip_all)]
 pub fn tack_program(program: ProgramNode) -> TProgramNode {
-    let ProgramNode::Program(function) = program;
-    return TProgramNode::Program(tack_functions(function));
+    let ProgramNode::Program(items) = program;
+    let mut symbols = SymbolTable::default();
+    symbols.enter_scope();
+    let mut functions = vec![];
+    for item in items {
+        match item {
+            ExternalDeclarationNode::Declaration(declaration) => {
+                tack_declaration(declaration, &mut symbols);
+            }
+            ExternalDeclarationNode::Function(function) => {
+                functions.push(tack_functions(function, &mut symbols))
+            }
+        }
+    }
+    TProgramNode::Program(functions, symbols.into_function_types())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::parser::tests::parse_source;
+    use super::*;
+
+    fn tack_source(source: &str) -> TProgramNode {
+        tack_program(parse_source(source))
+    }
+
+    #[test]
+    fn a_prototype_lets_a_call_come_before_the_definition() {
+        let TProgramNode::Program(functions, _) = tack_source(
+            "int twice(int x);\nint main(void) { return twice(2); }\nint twice(int x) { return x + x; }\n",
+        );
+        let names: Vec<&str> = functions
+            .iter()
+            .map(|TFunctionDefinitionNode::Function(name, _, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["main", "twice"]);
+        let TFunctionDefinitionNode::Function(_, _, instructions) = &functions[0];
+        assert!(instructions.iter().any(|instruction| matches!(
+            instruction,
+            TInstructionNode::FunCall(callee, arguments, _) if callee == "twice" && arguments.len() == 1
+        )));
+    }
+
+    #[test]
+    fn functions_can_be_declared_again_in_any_scope() {
+        tack_source(
+            "int f(int);\nint f(int a);\nint main(void) { int f(int b); return f(2); }\nint f(int c) { return c; }\n",
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Semantic error!")]
+    fn calls_are_checked_against_the_prototype() {
+        tack_source("int f(int a, int b);\nint main(void) { return f(1); }\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "Semantic error!")]
+    fn declarations_have_to_agree() {
+        tack_source("int f(int a);\nint main(void) { int f(void); return 0; }\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "Semantic error!")]
+    fn a_function_is_defined_once() {
+        tack_source(
+            "int f(void) { return 0; }\nint f(void) { return 1; }\nint main(void) { return 0; }\n",
+        );
+    }
+
+    #[test]
+    fn a_typedef_can_be_repeated_for_the_same_type() {
+        tack_source(
+            "typedef int T;\ntypedef int T;\nstruct s { int a; };\ntypedef struct s S;\ntypedef struct s S;\nint main(void) { T x = 1; return x; }\n",
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Semantic error!")]
+    fn a_repeated_typedef_names_the_same_type() {
+        tack_source(
+            "typedef int T;\nstruct s { int a; };\ntypedef struct s T;\nint main(void) { return 0; }\n",
+        );
+    }
+
+    // each branch copies both members of its structure into the result; the condition reads x.a too
+    #[test]
+    fn a_conditional_can_choose_between_structures() {
+        let TProgramNode::Program(functions, _) = tack_source(
+            "struct s { int a; int b; };\nint main(void) { struct s x; struct s y; x.a = 1; y.a = 2; return (x.a ? x : y).b; }\n",
+        );
+        let TFunctionDefinitionNode::Function(_, _, instructions) = &functions[0];
+        let copies_to = |name: &str| {
+            instructions
+                .iter()
+                .filter(|instruction| matches!(instruction, TInstructionNode::CopyFromOffset(src, _, _) if src == name))
+                .count()
+        };
+        assert_eq!((copies_to("x"), copies_to("y")), (3, 2));
+    }
+
+    #[test]
+    #[should_panic(expected = "Semantic error!")]
+    fn a_conditional_chooses_between_structures_of_the_same_type() {
+        tack_source(
+            "struct s { int a; };\nstruct t { int a; };\nint main(void) { struct s x; struct t y; return (1 ? x : y).a; }\n",
+        );
+    }
 }
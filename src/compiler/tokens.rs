@@ -1,5 +1,19 @@
+use std::fmt::{self, Display};
+
 use regex::Regex;
 
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SourceLocation {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum CommentToken {
     LineComment,
@@ -20,7 +34,7 @@ impl TryFrom<&str> for CommentToken {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SymbolToken {
     OpenParen,
     CloseParen,
@@ -46,6 +60,11 @@ pub enum SymbolToken {
     LeftABEqual,
     RightABEqual,
     Equal,
+    Period,
+    Arrow,
+    Comma,
+    Question,
+    Colon,
 }
 
 impl TryFrom<&str> for SymbolToken {
@@ -78,16 +97,26 @@ impl TryFrom<&str> for SymbolToken {
             "<=" => Ok(LeftABEqual),
             ">=" => Ok(RightABEqual),
             "=" => Ok(Equal),
+            "." => Ok(Period),
+            "->" => Ok(Arrow),
+            "," => Ok(Comma),
+            "?" => Ok(Question),
+            ":" => Ok(Colon),
             _ => Err("coercion from char to SymbolToken failed"),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum KeywordToken {
     Int,
     Void,
     Return,
+    Struct,
+    Union,
+    Enum,
+    Typedef,
+    Sizeof,
 }
 
 impl TryFrom<&str> for KeywordToken {
@@ -99,15 +128,187 @@ impl TryFrom<&str> for KeywordToken {
             "int" => Ok(Int),
             "void" => Ok(Void),
             "return" => Ok(Return),
+            "struct" => Ok(Struct),
+            "union" => Ok(Union),
+            "enum" => Ok(Enum),
+            "typedef" => Ok(Typedef),
+            "sizeof" => Ok(Sizeof),
             _ => Err("coercion from str to KeywordToken failed"),
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IntegerType {
+    Int,
+    UnsignedInt,
+    Long,
+    UnsignedLong,
+    LongLong,
+    UnsignedLongLong,
+}
+
+impl IntegerType {
+    // sizes follow the LP64 model, where long and long long are both 64 bits wide
+    pub fn max_value(&self) -> u64 {
+        use IntegerType::*;
+        match self {
+            Int => i32::MAX as u64,
+            UnsignedInt => u32::MAX as u64,
+            Long | LongLong => i64::MAX as u64,
+            UnsignedLong | UnsignedLongLong => u64::MAX,
+        }
+    }
+
+    fn long_count(&self) -> usize {
+        use IntegerType::*;
+        match self {
+            Int | UnsignedInt => 0,
+            Long | UnsignedLong => 1,
+            LongLong | UnsignedLongLong => 2,
+        }
+    }
+
+    pub fn is_unsigned(&self) -> bool {
+        use IntegerType::*;
+        matches!(self, UnsignedInt | UnsignedLong | UnsignedLongLong)
+    }
+
+    // the suffix that makes a decimal constant in this type's range lex as this type
+    fn suffix(&self) -> &'static str {
+        use IntegerType::*;
+        match self {
+            Int => "",
+            UnsignedInt => "u",
+            Long => "l",
+            UnsignedLong => "ul",
+            LongLong => "ll",
+            UnsignedLongLong => "ull",
+        }
+    }
+}
+
+impl Display for IntegerType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use IntegerType::*;
+        let name = match self {
+            Int => "int",
+            UnsignedInt => "unsigned int",
+            Long => "long",
+            UnsignedLong => "unsigned long",
+            LongLong => "long long",
+            UnsignedLongLong => "unsigned long long",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IntegerLiteral {
+    pub value: u64,
+    pub literal_type: IntegerType,
+}
+
+impl IntegerLiteral {
+    // int is the only integer type so far, so every constant is converted to it, keeping the low
+    // 32 bits the same way gcc does
+    pub fn as_int(&self) -> i32 {
+        self.value as u32 as i32
+    }
+}
+
+// printed in decimal with the suffix that gives it the same type when it is lexed again
+impl Display for IntegerLiteral {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.value, self.literal_type.suffix())
+    }
+}
+
+impl TryFrom<&str> for IntegerLiteral {
+    type Error = String;
+
+    fn try_from(text: &str) -> Result<Self, Self::Error> {
+        let (base, base_name, digits) =
+            if let Some(rest) = text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+                (16, "hexadecimal", rest)
+            } else if let Some(rest) = text.strip_prefix("0b").or(text.strip_prefix("0B")) {
+                (2, "binary", rest)
+            } else if text.len() > 1 && text.starts_with('0') {
+                (8, "octal", &text[1..])
+            } else {
+                (10, "decimal", text)
+            };
+        let suffix_start = digits
+            .find(|c: char| !c.is_digit(base))
+            .unwrap_or(digits.len());
+        let (digits, suffix) = digits.split_at(suffix_start);
+        if let Some(digit) = suffix.chars().next().filter(|c| c.is_ascii_digit()) {
+            return Err(format!(
+                "invalid digit '{digit}' in {base_name} constant {text}"
+            ));
+        }
+        if digits.is_empty() && base != 8 {
+            return Err(format!("{base_name} constant {text} has no digits"));
+        }
+
+        let (is_unsigned, long_count) = match suffix {
+            "" => (false, 0),
+            "u" | "U" => (true, 0),
+            "l" | "L" => (false, 1),
+            "ll" | "LL" => (false, 2),
+            "ul" | "uL" | "Ul" | "UL" | "lu" | "lU" | "Lu" | "LU" => (true, 1),
+            "ull" | "uLL" | "Ull" | "ULL" | "llu" | "llU" | "LLu" | "LLU" => (true, 2),
+            _ => {
+                return Err(format!(
+                    "invalid suffix \"{suffix}\" on integer constant {text}"
+                ))
+            }
+        };
+        // an octal constant's leading 0 is itself a digit
+        let value = if digits.is_empty() {
+            0
+        } else {
+            match u64::from_str_radix(digits, base) {
+                Ok(value) => value,
+                Err(_) => {
+                    return Err(format!(
+                        "integer constant {text} is too large for any integer type"
+                    ))
+                }
+            }
+        };
+
+        // a constant takes the first type from its candidate list that can represent it;
+        // decimal constants without a u suffix never become unsigned
+        use IntegerType::*;
+        let candidates = [
+            Int,
+            UnsignedInt,
+            Long,
+            UnsignedLong,
+            LongLong,
+            UnsignedLongLong,
+        ];
+        let literal_type = candidates
+            .into_iter()
+            .filter(|candidate| candidate.long_count() >= long_count)
+            .filter(|candidate| !is_unsigned || candidate.is_unsigned())
+            .filter(|candidate| base != 10 || is_unsigned || !candidate.is_unsigned())
+            .find(|candidate| value <= candidate.max_value());
+        match literal_type {
+            Some(literal_type) => Ok(IntegerLiteral {
+                value,
+                literal_type,
+            }),
+            None => Err(format!("integer constant {text} is too large for its type")),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Token {
     Identifier(String),
-    Constant(String),
+    Constant(IntegerLiteral),
     Keyword(KeywordToken),
     Symbol(SymbolToken),
     Comment(CommentToken),
@@ -116,11 +317,94 @@ pub enum Token {
 
 lazy_static! {
     pub static ref IDENTIFIER_PATTERN: Regex = Regex::new(r"[a-zA-Z_]\w*\b").unwrap();
-    pub static ref CONSTANT_PATTERN: Regex = Regex::new(r"[0-9]+\b").unwrap();
-    pub static ref KEYWORD_PATTERN: Regex = Regex::new(r"int|void|return").unwrap();
-    pub static ref SYMBOL_PATTERN: Regex =
-        Regex::new(r#"\(|\)|\{|\}|;|\"|\n|\+|--|-|\*|\/|%|~| |\t|\n|!=|!|&&|\|\||==|<=|>=|<|>|="#)
-            .unwrap();
+    pub static ref CONSTANT_PATTERN: Regex = Regex::new(r"[0-9]\w*").unwrap();
+    pub static ref KEYWORD_PATTERN: Regex =
+        Regex::new(r"(?:int|void|return|struct|union|enum|typedef|sizeof)\b").unwrap();
+    pub static ref SYMBOL_PATTERN: Regex = Regex::new(
+        r#"\(|\)|\{|\}|;|\"|\n|\+|--|->|-|\*|\/|%|~| |\t|\n|!=|!|&&|\|\||==|<=|>=|<|>|=|\.|,|\?|:"#
+    )
+    .unwrap();
     pub static ref COMMENT_PATTERN: Regex = Regex::new(r"\/\/|\/\*").unwrap();
     pub static ref MACRO_PATTERN: Regex = Regex::new(r"#\w*").unwrap();
+    pub static ref LINEMARKER_PATTERN: Regex =
+        Regex::new(r#"^#\s*(\d+)\s+"[^"\n]*"[^\n]*"#).unwrap();
+}
+
+// the parser's view of the lexed tokens, which also knows where each token came from
+#[derive(Debug)]
+pub struct TokenStream<'a> {
+    tokens: &'a [(Token, SourceLocation)],
+    position: usize,
+}
+
+impl<'a> TokenStream<'a> {
+    pub fn new(tokens: &'a [(Token, SourceLocation)]) -> TokenStream<'a> {
+        TokenStream {
+            tokens,
+            position: 0,
+        }
+    }
+
+    pub fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    pub fn peek_second(&self) -> Option<&'a Token> {
+        self.tokens.get(self.position + 1).map(|(token, _)| token)
+    }
+
+    // location of the next token, or of the last token once the stream is exhausted
+    pub fn location(&self) -> SourceLocation {
+        match self.tokens.get(self.position).or(self.tokens.last()) {
+            Some((_, location)) => *location,
+            None => SourceLocation::default(),
+        }
+    }
+}
+
+impl<'a> Iterator for TokenStream<'a> {
+    type Item = &'a Token;
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.position).map(|(token, _)| token);
+        self.position += 1;
+        token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn printed_literals_lex_back_to_the_same_value_and_type() {
+        for text in [
+            "0",
+            "017",
+            "0x7fffffff",
+            "0xffffffff",
+            "5000000000",
+            "10L",
+            "42u",
+            "0xFFFFFFFFFFFFFFFFull",
+        ] {
+            let literal = IntegerLiteral::try_from(text).unwrap();
+            let printed = format!("{literal}");
+            let relexed = IntegerLiteral::try_from(printed.as_str()).unwrap();
+            assert_eq!(relexed.value, literal.value, "{text} printed as {printed}");
+            assert_eq!(
+                relexed.literal_type, literal.literal_type,
+                "{text} printed as {printed}"
+            );
+        }
+    }
+
+    #[test]
+    fn constants_too_wide_for_int_keep_their_low_bits() {
+        let as_int = |text| IntegerLiteral::try_from(text).unwrap().as_int();
+        assert_eq!(as_int("2147483647"), i32::MAX);
+        assert_eq!(as_int("2147483648"), i32::MIN);
+        assert_eq!(as_int("4294967295u"), -1);
+        assert_eq!(as_int("5000000000"), 705032704);
+    }
 }
@@ -0,0 +1,649 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display},
+};
+
+use tracing::error;
+
+use super::ast_tree::{ExpressionNode, MemberDeclarationNode, TypeNode};
+
+fn unresolved_type(value_type: &TypeNode) -> ! {
+    error!("type {value_type:?} must be resolved through the symbol table first");
+    panic!("Semantic error!");
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TagKind {
+    Struct,
+    Union,
+    Enum,
+}
+
+// the keyword that introduces the tag
+impl Display for TagKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TagKind::Struct => write!(f, "struct"),
+            TagKind::Union => write!(f, "union"),
+            TagKind::Enum => write!(f, "enum"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MemberLayout {
+    pub name: String,
+    pub member_type: TypeNode,
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct StructLayout {
+    pub size: usize,
+    pub alignment: usize,
+    pub members: Vec<MemberLayout>,
+}
+
+impl StructLayout {
+    pub fn member(&self, name: &str) -> Option<&MemberLayout> {
+        self.members.iter().find(|member| member.name == name)
+    }
+}
+
+fn round_up(value: usize, alignment: usize) -> usize {
+    value.div_ceil(alignment) * alignment
+}
+
+#[derive(Debug)]
+struct TagEntry {
+    kind: TagKind,
+    defined: bool,
+    layout: Option<StructLayout>,
+}
+
+// struct, union and enum tags share one namespace; every tag declared in a scope resolves to a
+// unique key so that an inner definition can shadow an outer one
+#[derive(Debug, Default)]
+pub struct TypeTable {
+    entries: HashMap<String, TagEntry>,
+    scopes: Vec<HashMap<String, String>>,
+}
+
+impl TypeTable {
+    pub fn enter_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    pub fn exit_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    fn lookup_tag(&self, tag: &str) -> Option<&String> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(tag))
+    }
+
+    fn check_kind(&self, key: &str, kind: TagKind) {
+        let declared = self.entries[key].kind;
+        if declared != kind {
+            error!("tag {key} declared as {declared} but used as {kind}");
+            panic!("Semantic error!");
+        }
+    }
+
+    // declares the tag in the innermost scope, returning its unique key
+    fn declare_in_scope(&mut self, kind: TagKind, tag: &str) -> String {
+        if let Some(key) = self.scopes.last().and_then(|scope| scope.get(tag)) {
+            let key = key.to_owned();
+            self.check_kind(&key, kind);
+            return key;
+        }
+        let mut key = tag.to_owned();
+        let mut counter = 0;
+        while self.entries.contains_key(&key) {
+            counter += 1;
+            key = format!("{tag}.{counter}");
+        }
+        self.entries.insert(
+            key.clone(),
+            TagEntry {
+                kind,
+                defined: false,
+                layout: None,
+            },
+        );
+        self.scopes
+            .last_mut()
+            .expect("tag declared outside of any scope")
+            .insert(tag.to_owned(), key.clone());
+        key
+    }
+
+    pub fn declare_tag(&mut self, kind: TagKind, tag: &str) {
+        self.declare_in_scope(kind, tag);
+    }
+
+    fn mark_defined(&mut self, kind: TagKind, tag: &str) -> String {
+        let key = self.declare_in_scope(kind, tag);
+        if self.entries[&key].defined {
+            error!("redefinition of {kind} {tag}");
+            panic!("Semantic error!");
+        }
+        key
+    }
+
+    // members must already be resolved, see SymbolTable::define_aggregate
+    fn define_aggregate(&mut self, kind: TagKind, tag: &str, members: &[(String, TypeNode)]) {
+        let key = self.mark_defined(kind, tag);
+
+        // structs place each member at the next suitably aligned offset, unions overlay them all
+        let mut layouts: Vec<MemberLayout> = vec![];
+        let mut offset = 0;
+        let mut size = 0;
+        let mut alignment = 1;
+        for (name, member_type) in members {
+            if layouts.iter().any(|member| &member.name == name) {
+                error!("duplicate member {name} in {kind} {tag}");
+                panic!("Semantic error!");
+            }
+            let member_type = member_type.clone();
+            let member_alignment = self.alignment_of(&member_type);
+            let member_size = self.size_of(&member_type);
+            if kind == TagKind::Struct {
+                offset = round_up(offset, member_alignment);
+                size = offset + member_size;
+            } else {
+                size = std::cmp::max(size, member_size);
+            }
+            layouts.push(MemberLayout {
+                name: name.to_owned(),
+                member_type,
+                offset,
+            });
+            if kind == TagKind::Struct {
+                offset += member_size;
+            }
+            alignment = std::cmp::max(alignment, member_alignment);
+        }
+
+        let layout = StructLayout {
+            size: round_up(size, alignment),
+            alignment,
+            members: layouts,
+        };
+        let entry = self.entries.get_mut(&key).unwrap();
+        entry.defined = true;
+        entry.layout = Some(layout);
+    }
+
+    pub fn define_enum(&mut self, tag: &str) {
+        let key = self.mark_defined(TagKind::Enum, tag);
+        self.entries.get_mut(&key).unwrap().defined = true;
+    }
+
+    // replaces the tags in a type specifier with the unique keys they currently resolve to
+    fn resolve_type(&mut self, value_type: &TypeNode) -> TypeNode {
+        let (kind, tag) = match value_type {
+            TypeNode::Int => return TypeNode::Int,
+            TypeNode::Struct(tag) => (TagKind::Struct, tag),
+            TypeNode::Union(tag) => (TagKind::Union, tag),
+            TypeNode::Enum(tag) => (TagKind::Enum, tag),
+            TypeNode::Typedef(_) | TypeNode::Pointer(_) => unresolved_type(value_type),
+        };
+        let key = match self.lookup_tag(tag) {
+            Some(key) => {
+                let key = key.to_owned();
+                self.check_kind(&key, kind);
+                key
+            }
+            None if kind == TagKind::Enum => {
+                error!("enum {tag} used before it was defined");
+                panic!("Semantic error!");
+            }
+            // naming an unknown struct or union declares it as an incomplete type
+            None => self.declare_in_scope(kind, tag),
+        };
+        match kind {
+            TagKind::Struct => TypeNode::Struct(key),
+            TagKind::Union => TypeNode::Union(key),
+            TagKind::Enum => TypeNode::Enum(key),
+        }
+    }
+
+    // like resolve_type, but for contexts such as sizeof where a tag is never implicitly declared
+    fn lookup_type(&self, value_type: &TypeNode) -> TypeNode {
+        let (kind, tag) = match value_type {
+            TypeNode::Int => return TypeNode::Int,
+            TypeNode::Struct(tag) => (TagKind::Struct, tag),
+            TypeNode::Union(tag) => (TagKind::Union, tag),
+            TypeNode::Enum(tag) => (TagKind::Enum, tag),
+            TypeNode::Typedef(_) | TypeNode::Pointer(_) => unresolved_type(value_type),
+        };
+        let Some(key) = self.lookup_tag(tag) else {
+            error!("{kind} {tag} was never declared");
+            panic!("Semantic error!");
+        };
+        self.check_kind(key, kind);
+        match kind {
+            TagKind::Struct => TypeNode::Struct(key.to_owned()),
+            TagKind::Union => TypeNode::Union(key.to_owned()),
+            TagKind::Enum => TypeNode::Enum(key.to_owned()),
+        }
+    }
+
+    pub fn layout_of(&self, key: &str) -> &StructLayout {
+        match self.entries.get(key) {
+            Some(TagEntry {
+                layout: Some(layout),
+                ..
+            }) => layout,
+            Some(TagEntry { kind, .. }) => {
+                error!("{kind} {key} is an incomplete type");
+                panic!("Semantic error!");
+            }
+            None => {
+                error!("tag {key} was never declared");
+                panic!("Semantic error!");
+            }
+        }
+    }
+
+    pub fn size_of(&self, value_type: &TypeNode) -> usize {
+        match value_type {
+            TypeNode::Int | TypeNode::Enum(_) => 4,
+            TypeNode::Struct(key) | TypeNode::Union(key) => self.layout_of(key).size,
+            TypeNode::Typedef(_) | TypeNode::Pointer(_) => unresolved_type(value_type),
+        }
+    }
+
+    pub fn alignment_of(&self, value_type: &TypeNode) -> usize {
+        match value_type {
+            TypeNode::Int | TypeNode::Enum(_) => 4,
+            TypeNode::Struct(key) | TypeNode::Union(key) => self.layout_of(key).alignment,
+            TypeNode::Typedef(_) | TypeNode::Pointer(_) => unresolved_type(value_type),
+        }
+    }
+}
+
+// what a function takes and returns, with every type resolved
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionType {
+    pub parameters: Vec<TypeNode>,
+    pub return_type: TypeNode,
+}
+
+impl FunctionType {
+    pub fn passes_aggregates(&self) -> bool {
+        std::iter::once(&self.return_type)
+            .chain(&self.parameters)
+            .any(|value_type| matches!(value_type, TypeNode::Struct(_) | TypeNode::Union(_)))
+    }
+}
+
+// written the way C spells the type of a function, a function without parameters taking void
+impl Display for FunctionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parameters: Vec<String> = self
+            .parameters
+            .iter()
+            .map(|parameter| format!("{parameter}"))
+            .collect();
+        match parameters.is_empty() {
+            true => write!(f, "{} (void)", self.return_type),
+            false => write!(f, "{} ({})", self.return_type, parameters.join(", ")),
+        }
+    }
+}
+
+// the types of every function a program declares, along with the tags they refer to, which is
+// what the generator needs to follow the calling convention
+#[derive(Debug, Default)]
+pub struct FunctionTypes {
+    pub functions: HashMap<String, FunctionType>,
+    pub types: TypeTable,
+}
+
+#[derive(Debug, Clone)]
+pub enum IdentifierEntry {
+    Variable(TypeNode),
+    Enumerator(i32),
+    Typedef(TypeNode),
+    Function(FunctionType),
+}
+
+pub fn typedef_as_value(name: &str) -> ! {
+    error!("typedef name {name} used as a value");
+    panic!("Semantic error!");
+}
+
+// ordinary identifiers (variables, functions, enumeration constants and typedef names) share one
+// scoped namespace
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    scopes: Vec<HashMap<String, IdentifierEntry>>,
+    // every function has external linkage, so all declarations of a name, in whatever scope,
+    // are of the same function and have to agree on its type
+    functions: HashMap<String, FunctionType>,
+    defined_functions: HashSet<String>,
+    // the return type of the function whose body is being checked
+    return_type: Option<TypeNode>,
+    pub types: TypeTable,
+}
+
+impl SymbolTable {
+    pub fn enter_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+        self.types.enter_scope();
+    }
+
+    pub fn exit_scope(&mut self) {
+        self.scopes.pop();
+        self.types.exit_scope();
+    }
+
+    fn declare(&mut self, name: &str, entry: IdentifierEntry) {
+        let scope = self
+            .scopes
+            .last_mut()
+            .expect("identifier declared outside of any scope");
+        if scope.contains_key(name) {
+            error!("redeclaration of {name}");
+            panic!("Semantic error!");
+        }
+        scope.insert(name.to_owned(), entry);
+    }
+
+    // resolves typedef names and tags to the types they denote at this point in the program
+    pub fn resolve_type(&mut self, value_type: &TypeNode) -> TypeNode {
+        match value_type {
+            TypeNode::Typedef(name) => match self.lookup(name) {
+                IdentifierEntry::Typedef(resolved) => resolved.clone(),
+                _ => {
+                    error!("{name} does not name a type");
+                    panic!("Semantic error!");
+                }
+            },
+            TypeNode::Pointer(_) => {
+                error!("pointer type '{value_type}' is not supported");
+                panic!("Semantic error!");
+            }
+            other => self.types.resolve_type(other),
+        }
+    }
+
+    pub fn lookup_type(&self, value_type: &TypeNode) -> TypeNode {
+        match value_type {
+            TypeNode::Typedef(name) => match self.lookup(name) {
+                IdentifierEntry::Typedef(resolved) => resolved.clone(),
+                _ => {
+                    error!("{name} does not name a type");
+                    panic!("Semantic error!");
+                }
+            },
+            TypeNode::Pointer(_) => {
+                error!("pointer type '{value_type}' is not supported");
+                panic!("Semantic error!");
+            }
+            other => self.types.lookup_type(other),
+        }
+    }
+
+    pub fn define_aggregate(
+        &mut self,
+        kind: TagKind,
+        tag: &str,
+        members: &[MemberDeclarationNode],
+    ) {
+        // the tag is in scope within its own member list
+        self.types.declare_tag(kind, tag);
+        let members: Vec<(String, TypeNode)> = members
+            .iter()
+            .map(|MemberDeclarationNode::Member(member_type, name, _)| {
+                (name.to_owned(), self.resolve_type(member_type))
+            })
+            .collect();
+        self.types.define_aggregate(kind, tag, &members);
+    }
+
+    // a typedef may be repeated in the same scope, as long as it names the same type again
+    pub fn declare_typedef(&mut self, name: &str, value_type: &TypeNode) {
+        let value_type = self.resolve_type(value_type);
+        let scope = self
+            .scopes
+            .last()
+            .expect("identifier declared outside of any scope");
+        if let Some(IdentifierEntry::Typedef(previous)) = scope.get(name) {
+            if *previous == value_type {
+                return;
+            }
+            error!("conflicting types for typedef {name}, '{previous}' and '{value_type}'");
+            panic!("Semantic error!");
+        }
+        self.declare(name, IdentifierEntry::Typedef(value_type));
+    }
+
+    pub fn declare_variable(&mut self, name: &str, value_type: &TypeNode) {
+        let value_type = self.resolve_type(value_type);
+        // reject objects of incomplete type
+        self.types.size_of(&value_type);
+        self.declare(name, IdentifierEntry::Variable(value_type));
+    }
+
+    pub fn declare_function(
+        &mut self,
+        name: &str,
+        return_type: &TypeNode,
+        parameters: &[TypeNode],
+    ) -> FunctionType {
+        let function_type = FunctionType {
+            parameters: parameters
+                .iter()
+                .map(|parameter| self.resolve_type(parameter))
+                .collect(),
+            return_type: self.resolve_type(return_type),
+        };
+        if let Some(previous) = self
+            .functions
+            .insert(name.to_owned(), function_type.clone())
+        {
+            if previous != function_type {
+                error!(
+                    "conflicting types for {name}, declared as '{previous}' and as '{function_type}'"
+                );
+                panic!("Semantic error!");
+            }
+        }
+        // a function can be declared any number of times in the same scope
+        let scope = self
+            .scopes
+            .last()
+            .expect("identifier declared outside of any scope");
+        if !matches!(scope.get(name), Some(IdentifierEntry::Function(_))) {
+            self.declare(name, IdentifierEntry::Function(function_type.clone()));
+        }
+        function_type
+    }
+
+    // declares the function and starts checking its body, which needs every type it passes or
+    // returns to be complete
+    pub fn define_function(
+        &mut self,
+        name: &str,
+        return_type: &TypeNode,
+        parameters: &[TypeNode],
+    ) -> FunctionType {
+        let function_type = self.declare_function(name, return_type, parameters);
+        if !self.defined_functions.insert(name.to_owned()) {
+            error!("redefinition of function {name}");
+            panic!("Semantic error!");
+        }
+        self.types.size_of(&function_type.return_type);
+        self.return_type = Some(function_type.return_type.clone());
+        function_type
+    }
+
+    pub fn return_type(&self) -> &TypeNode {
+        self.return_type
+            .as_ref()
+            .expect("return statement outside of any function")
+    }
+
+    // what is left once the whole program has been checked
+    pub fn into_function_types(self) -> FunctionTypes {
+        FunctionTypes {
+            functions: self.functions,
+            types: self.types,
+        }
+    }
+
+    pub fn declare_enumerator(&mut self, name: &str, value: i32) {
+        self.declare(name, IdentifierEntry::Enumerator(value));
+    }
+
+    pub fn lookup(&self, name: &str) -> &IdentifierEntry {
+        match self.scopes.iter().rev().find_map(|scope| scope.get(name)) {
+            Some(entry) => entry,
+            None => {
+                error!("use of undeclared identifier {name}");
+                panic!("Semantic error!");
+            }
+        }
+    }
+
+    pub fn type_of_expression(&self, expression: &ExpressionNode) -> TypeNode {
+        match expression {
+            ExpressionNode::Constant(..) => TypeNode::Int,
+            ExpressionNode::Var(name, location) => match self.lookup(name) {
+                IdentifierEntry::Variable(value_type) => value_type.clone(),
+                IdentifierEntry::Enumerator(_) => TypeNode::Int,
+                IdentifierEntry::Typedef(_) => {
+                    error!("at {location}:");
+                    typedef_as_value(name)
+                }
+                IdentifierEntry::Function(_) => {
+                    error!("function {name} used as a value at {location}");
+                    panic!("Semantic error!");
+                }
+            },
+            ExpressionNode::Unary(_, operand, _) => {
+                self.ensure_scalar(operand);
+                TypeNode::Int
+            }
+            ExpressionNode::Binary(_, op1, op2, _) => {
+                self.ensure_scalar(op1);
+                self.ensure_scalar(op2);
+                TypeNode::Int
+            }
+            ExpressionNode::Assignment(lhs, _, _) => self.type_of_expression(lhs),
+            ExpressionNode::Dot(inner, member, location) => {
+                match self.type_of_expression(inner) {
+                    TypeNode::Struct(tag) | TypeNode::Union(tag) => {
+                        match self.types.layout_of(&tag).member(member) {
+                            Some(layout) => layout.member_type.clone(),
+                            None => {
+                                error!("{tag} has no member named {member} at {location}");
+                                panic!("Semantic error!");
+                            }
+                        }
+                    }
+                    other => {
+                        error!("member {member} accessed on non-aggregate type '{other}' at {location}");
+                        panic!("Semantic error!");
+                    }
+                }
+            }
+            ExpressionNode::Arrow(_, member, location) => {
+                error!(
+                    "cannot access member {member} through a pointer at {location}, pointer types are not supported"
+                );
+                panic!("Semantic error!");
+            }
+            ExpressionNode::Cast(target, inner, _) => {
+                self.ensure_scalar(inner);
+                self.cast_target_type(target)
+            }
+            // the branches are either both scalars or both of the same structure or union type
+            ExpressionNode::Conditional(condition, then_branch, else_branch, location) => {
+                self.ensure_scalar(condition);
+                match self.type_of_expression(then_branch) {
+                    then_type @ (TypeNode::Struct(_) | TypeNode::Union(_)) => {
+                        let else_type = self.type_of_expression(else_branch);
+                        if else_type != then_type {
+                            error!(
+                                "mismatched types '{then_type}' and '{else_type}' in a conditional expression at {location}"
+                            );
+                            panic!("Semantic error!");
+                        }
+                        then_type
+                    }
+                    _ => {
+                        self.ensure_scalar(else_branch);
+                        TypeNode::Int
+                    }
+                }
+            }
+            ExpressionNode::SizeOfType(..) | ExpressionNode::SizeOfExpression(..) => TypeNode::Int,
+            ExpressionNode::FunctionCall(name, arguments, location) => {
+                let IdentifierEntry::Function(function_type) = self.lookup(name) else {
+                    error!("called object {name} is not a function at {location}");
+                    panic!("Semantic error!");
+                };
+                if arguments.len() != function_type.parameters.len() {
+                    error!(
+                        "{name} takes {} arguments but is called with {} at {location}",
+                        function_type.parameters.len(),
+                        arguments.len()
+                    );
+                    panic!("Semantic error!");
+                }
+                for (argument, parameter) in arguments.iter().zip(&function_type.parameters) {
+                    self.ensure_assignable(parameter, argument);
+                }
+                // a call can only pass and return complete types
+                for parameter in &function_type.parameters {
+                    self.types.size_of(parameter);
+                }
+                self.types.size_of(&function_type.return_type);
+                function_type.return_type.clone()
+            }
+        }
+    }
+
+    // every scalar type is a 32-bit int, so casts only need to check that their target is a scalar
+    pub fn cast_target_type(&self, target: &TypeNode) -> TypeNode {
+        match self.lookup_type(target) {
+            TypeNode::Struct(tag) | TypeNode::Union(tag) => {
+                error!("cannot cast to non-scalar type {tag}");
+                panic!("Semantic error!");
+            }
+            _ => TypeNode::Int,
+        }
+    }
+
+    pub fn ensure_scalar(&self, expression: &ExpressionNode) {
+        if let TypeNode::Struct(tag) | TypeNode::Union(tag) = self.type_of_expression(expression) {
+            let location = expression.location();
+            error!("{tag} used where a scalar value is required at {location}");
+            panic!("Semantic error!");
+        }
+    }
+
+    // an aggregate can only be assigned, passed or returned as a value of the same type, and
+    // anything else as a scalar
+    pub fn ensure_assignable(&self, target: &TypeNode, expression: &ExpressionNode) {
+        match target {
+            TypeNode::Struct(_) | TypeNode::Union(_) => {
+                let value_type = self.type_of_expression(expression);
+                if value_type != *target {
+                    let location = expression.location();
+                    error!("cannot convert '{value_type}' to '{target}' at {location}");
+                    panic!("Semantic error!");
+                }
+            }
+            _ => self.ensure_scalar(expression),
+        }
+    }
+
+    pub fn size_of_expression(&self, expression: &ExpressionNode) -> usize {
+        self.types.size_of(&self.type_of_expression(expression))
+    }
+}
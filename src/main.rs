@@ -9,9 +9,19 @@ use std::{
 
 use clap::Parser;
 use compiler::{
-    emitter::emit_program, generator::generate_program, lexer::lex, parser::parse_program,
+    ast_dump::dump_program,
+    emitter::emit_program,
+    generator::generate_program,
+    lexer::lex,
+    optimizer::{
+        format_cfgs, format_program, optimize_program, OptimizationOptions, RemarkKind, Remarks,
+        PASS_NAMES,
+    },
+    parser::parse_program,
     tacker::tack_program,
+    tokens::TokenStream,
 };
+use regex::Regex;
 use tracing::{debug, error, info, warn};
 
 mod compiler;
@@ -39,6 +49,158 @@ struct Args {
 
     #[clap(long, help("Compile only until the code generation stage"))]
     codegen: bool,
+
+    #[clap(
+        short('O'),
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(0..=2),
+        help("Optimisation level, from 0 (none) to 2")
+    )]
+    optimization_level: u8,
+
+    #[clap(long, help("Fold operations on constants at compile time"))]
+    fold_constants: bool,
+
+    #[clap(
+        long,
+        help("Remove code that can never run, along with useless jumps and labels")
+    )]
+    eliminate_unreachable_code: bool,
+
+    #[clap(long, help("Replace uses of copied values with their sources"))]
+    propagate_copies: bool,
+
+    #[clap(
+        long,
+        help("Remove writes to variables that are never read afterwards")
+    )]
+    eliminate_dead_stores: bool,
+
+    #[clap(
+        long,
+        help("Propagate constants through variables and resolve branches on them, in SSA form")
+    )]
+    sccp: bool,
+
+    #[clap(long, help("Reuse the results of repeated computations, in SSA form"))]
+    gvn: bool,
+
+    #[clap(
+        long,
+        help("Move computations that don't change inside a loop out in front of it")
+    )]
+    licm: bool,
+
+    #[clap(
+        long,
+        help("Turn multiplications of a loop's induction variables into additions carried from one iteration to the next")
+    )]
+    strength_reduce: bool,
+
+    #[clap(
+        long,
+        help("Inline calls to non-recursive functions of at most this many instructions, 0 to disable")
+    )]
+    inline_threshold: Option<usize>,
+
+    #[clap(long, help("Keep values in registers chosen by graph colouring"))]
+    allocate_registers: bool,
+
+    #[clap(
+        long,
+        help("Clean up the generated assembly with local rewrites of short instruction sequences")
+    )]
+    peephole: bool,
+
+    #[clap(
+        long,
+        help("Turn calls whose result is returned straight away into jumps that reuse the frame")
+    )]
+    tail_calls: bool,
+
+    #[clap(
+        long,
+        help("Convert the optimised TACKY to SSA form and back, logging the SSA form; with --tacky, print the SSA form instead of the TACKY")
+    )]
+    ssa: bool,
+
+    // gcc-style code generation flags, taken as -f<flag>
+    #[clap(
+        short('f'),
+        value_name("FLAG"),
+        value_parser(["omit-frame-pointer", "no-omit-frame-pointer"]),
+        help("Code generation flags: -fomit-frame-pointer addresses the stack through %rsp and frees %rbp for allocation")
+    )]
+    code_generation_flags: Vec<String>,
+
+    // clang-style remark requests, taken as -Rpass=<regex> and -Rpass-missed=<regex>
+    #[clap(
+        short('R'),
+        value_name("pass=REGEX"),
+        value_parser(parse_remark_request),
+        help("Report what optimisation passes matching the regex did (-Rpass=) or could not do (-Rpass-missed=)")
+    )]
+    remark_requests: Vec<(RemarkKind, Regex)>,
+
+    #[clap(
+        long,
+        value_name("FORMAT"),
+        value_parser(["json"]),
+        help("Save every optimisation remark to a .remarks.json file next to the input")
+    )]
+    remarks: Option<String>,
+
+    #[clap(
+        long,
+        value_name("PASS"),
+        value_parser(PASS_NAMES),
+        help("Print each function after the named pass runs on it, as TACKY or assembly depending on the pass")
+    )]
+    print_after: Vec<String>,
+
+    #[clap(long, help("Print each function after every pass that runs on it"))]
+    print_after_all: bool,
+
+    #[clap(
+        long,
+        value_name("N"),
+        help("Run only the first N pass applications, logging each one, to bisect miscompiles")
+    )]
+    opt_bisect_limit: Option<usize>,
+
+    #[clap(
+        long,
+        value_name("FORMAT"),
+        value_parser(["c"]),
+        help("Print the program as the parser understood it, as C with only the parentheses it needs")
+    )]
+    print_ast: Option<String>,
+
+    #[clap(
+        long,
+        help("Print the parsed program as an indented tree, with the names, types and source locations it resolved to")
+    )]
+    dump_ast: bool,
+
+    #[clap(
+        long,
+        help("Save the control flow graph of each optimised function to a graphviz .dot file next to the input")
+    )]
+    dump_cfg: bool,
+}
+
+fn parse_remark_request(request: &str) -> Result<(RemarkKind, Regex), String> {
+    let (kind, pattern) = if let Some(pattern) = request.strip_prefix("pass=") {
+        (RemarkKind::Passed, pattern)
+    } else if let Some(pattern) = request.strip_prefix("pass-missed=") {
+        (RemarkKind::Missed, pattern)
+    } else {
+        return Err(String::from("expected pass=<regex> or pass-missed=<regex>"));
+    };
+    match Regex::new(pattern) {
+        Ok(regex) => Ok((kind, regex)),
+        Err(e) => Err(format!("invalid pass name regex: {e}")),
+    }
 }
 
 fn main() {
@@ -101,7 +263,6 @@ fn preprocess(args: &Args) -> Result<String, Error> {
     match Command::new("gcc")
         .args([
             "-E",
-            "-P",
             &args.input_file,
             "-o",
             &format!("{executable_name}.i"),
@@ -137,7 +298,15 @@ fn compile(args: &Args) -> Result<String, Error> {
         return Ok("Lexing only complete!".to_string());
     }
 
-    let syntax_tree = parse_program(&mut tokens.iter().peekable());
+    let syntax_tree = parse_program(&mut TokenStream::new(&tokens));
+
+    // stdout carries the tracing output, so these go to stderr like the other dumps
+    if args.print_ast.is_some() {
+        eprint!("{syntax_tree}");
+    }
+    if args.dump_ast {
+        eprint!("{}", dump_program(&syntax_tree));
+    }
 
     if args.parse {
         warn!("stopping at parse");
@@ -145,15 +314,49 @@ fn compile(args: &Args) -> Result<String, Error> {
         return Ok("Parsing only complete!".to_string());
     }
 
-    let tacky = tack_program(syntax_tree);
+    let mut options = OptimizationOptions::from_level(args.optimization_level);
+    options.fold_constants |= args.fold_constants;
+    options.eliminate_unreachable_code |= args.eliminate_unreachable_code;
+    options.propagate_copies |= args.propagate_copies;
+    options.eliminate_dead_stores |= args.eliminate_dead_stores;
+    options.propagate_constants |= args.sccp;
+    options.number_values |= args.gvn;
+    options.hoist_loop_invariants |= args.licm;
+    options.reduce_strength |= args.strength_reduce;
+    if let Some(threshold) = args.inline_threshold {
+        options.inline_threshold = threshold;
+    }
+    options.allocate_registers |= args.allocate_registers;
+    options.peephole |= args.peephole;
+    options.tail_calls |= args.tail_calls;
+    // --tacky prints the SSA form itself, so there is no need to go through it here as well
+    options.ssa = args.ssa && !args.tacky;
+    options.print_after = args.print_after.clone();
+    options.print_after_all = args.print_after_all;
+    options.opt_bisect_limit = args.opt_bisect_limit;
+    // the last of a flag and its negation wins
+    for flag in &args.code_generation_flags {
+        match flag.as_str() {
+            "omit-frame-pointer" => options.omit_frame_pointer = true,
+            "no-omit-frame-pointer" => options.omit_frame_pointer = false,
+            _ => unreachable!("clap only accepts the listed flags"),
+        }
+    }
+    let mut remarks = Remarks::default();
+    let tacky = optimize_program(tack_program(syntax_tree), &options, &mut remarks);
+    report_remarks(args, &remarks)?;
+
+    if args.dump_cfg {
+        dump_cfgs(args, &format_cfgs(&tacky))?;
+    }
 
     if args.tacky {
         warn!("stopping at tacking");
-        debug!("tacky generated: {:?}", tacky);
+        eprint!("{}", format_program(&tacky, args.ssa));
         return Ok("Tacky Generation only complete!".to_string());
     }
 
-    let codegen = generate_program(tacky);
+    let codegen = generate_program(tacky, &options);
 
     if args.codegen {
         warn!("stopping at codegen");
@@ -162,7 +365,7 @@ fn compile(args: &Args) -> Result<String, Error> {
     }
 
     let mut buffer = String::new();
-    emit_program(codegen, &mut buffer);
+    emit_program(codegen, &options, &mut buffer);
 
     // create the assembly file
     let assembly_filename = get_executable_name(&args.input_file);
@@ -190,6 +393,57 @@ fn compile(args: &Args) -> Result<String, Error> {
     Ok("Compilation complete!".to_string())
 }
 
+#[tracing::instrument(skip_all)]
+fn report_remarks(args: &Args, remarks: &Remarks) -> Result<(), Error> {
+    for remark in &remarks.remarks {
+        let requested = args
+            .remark_requests
+            .iter()
+            .any(|(kind, pattern)| *kind == remark.kind && pattern.is_match(remark.pass));
+        if requested {
+            eprintln!("{}", remark.format_text(&args.input_file));
+        }
+    }
+
+    if args.remarks.is_some() {
+        let remarks_filename = format!("{}.remarks.json", get_executable_name(&args.input_file));
+        let records: Vec<String> = remarks
+            .remarks
+            .iter()
+            .map(|remark| format!("  {}", remark.format_json()))
+            .collect();
+        let mut remarks_file = match File::create(&remarks_filename) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("error in creating remarks file: {e}");
+                return Result::Err(e);
+            }
+        };
+        remarks_file.write_all(format!("[\n{}\n]\n", records.join(",\n")).as_bytes())?;
+        info!("remarks saved to {remarks_filename}");
+    }
+    Ok(())
+}
+
+// one file per function, as dot only describes a single graph per file
+#[tracing::instrument(skip_all)]
+fn dump_cfgs(args: &Args, graphs: &[(String, String)]) -> Result<(), Error> {
+    let executable_name = get_executable_name(&args.input_file);
+    for (function, dot) in graphs {
+        let dot_filename = format!("{executable_name}.{function}.dot");
+        let mut dot_file = match File::create(&dot_filename) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("error in creating control flow graph file: {e}");
+                return Result::Err(e);
+            }
+        };
+        dot_file.write_all(dot.as_bytes())?;
+        info!("control flow graph of {function} saved to {dot_filename}");
+    }
+    Ok(())
+}
+
 #[tracing::instrument(skip_all)]
 fn assemble_and_link(args: &Args) -> Result<String, Error> {
     // assemble and link the assembly file
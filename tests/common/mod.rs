@@ -0,0 +1,19 @@
+use std::{env, fs, path::PathBuf, process::Command};
+
+// compiles a program in a directory of its own, since the compiler keeps its temporary files in
+// the directory it runs in, and returns the path of the executable
+pub fn compile(name: &str, source: &str, flags: &[&str]) -> PathBuf {
+    let directory = env::temp_dir().join(format!("c-compiler-{name}-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let source_path = directory.join(format!("{name}.c"));
+    fs::write(&source_path, source).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_c-compiler"))
+        .arg(&source_path)
+        .args(flags)
+        .current_dir(&directory)
+        .output()
+        .unwrap()
+        .status;
+    assert!(status.success(), "compiling {name} failed with {status}");
+    directory.join(name)
+}
@@ -0,0 +1,153 @@
+use std::{fs, process::Command};
+
+mod common;
+
+use common::compile;
+
+// structures of one and two eightbytes come back in registers and a bigger one through memory,
+// and `mixed` runs out of registers, putting its second quad on the stack while the int after
+// it still gets a register
+const PASSING_STRUCTURES: &str = "struct pair { int a; int b; };\n\
+    struct triple { int a; int b; int c; };\n\
+    struct quad { int a; int b; int c; int d; };\n\
+    struct big { int a; int b; int c; int d; int e; int f; };\n\
+    \n\
+    struct pair make_pair(int a, int b) {\n\
+        struct pair p;\n\
+        p.a = a;\n\
+        p.b = b;\n\
+        return p;\n\
+    }\n\
+    \n\
+    struct triple make_triple(int a, int b, int c) {\n\
+        struct triple t;\n\
+        t.a = a;\n\
+        t.b = b;\n\
+        t.c = c;\n\
+        return t;\n\
+    }\n\
+    \n\
+    struct quad make_quad(int a, int b, int c, int d) {\n\
+        struct quad q;\n\
+        q.a = a;\n\
+        q.b = b;\n\
+        q.c = c;\n\
+        q.d = d;\n\
+        return q;\n\
+    }\n\
+    \n\
+    struct big make_big(int a, int b, int c, int d, int e, int f) {\n\
+        struct big g;\n\
+        g.a = a;\n\
+        g.b = b;\n\
+        g.c = c;\n\
+        g.d = d;\n\
+        g.e = e;\n\
+        g.f = f;\n\
+        return g;\n\
+    }\n\
+    \n\
+    int weigh_quad(struct quad q) { return q.a + 2 * q.b + 3 * q.c + 4 * q.d; }\n\
+    \n\
+    int weigh_big(struct big g) {\n\
+        return g.a + 2 * g.b + 3 * g.c + 4 * g.d + 5 * g.e + 6 * g.f;\n\
+    }\n\
+    \n\
+    int mixed(struct quad q, struct triple t, struct pair p, struct quad r, int x, struct big g,\n\
+              int y) {\n\
+        return weigh_quad(q) - weigh_quad(r) + (t.a - t.b + t.c) * p.a * p.b + x * y + weigh_big(g);\n\
+    }\n\
+    \n\
+    struct big bump(struct big g, int k) {\n\
+        g.a = g.a + k;\n\
+        g.f = g.f - k;\n\
+        return g;\n\
+    }\n\
+    \n\
+    struct triple rotate(struct triple t) { return make_triple(t.b, t.c, t.a); }\n\
+    \n\
+    int main(void) {\n\
+        struct quad q = make_quad(1, 2, 3, 4);\n\
+        struct quad r = make_quad(4, 3, 2, 1);\n\
+        struct triple t = rotate(make_triple(5, 6, 7));\n\
+        struct pair p = make_pair(2, 3);\n\
+        struct big g = bump(make_big(1, 2, 3, 4, 5, 6), 10);\n\
+        int checks = (q.a == 1 && q.d == 4) + 2 * (t.a == 6 && t.b == 7 && t.c == 5) +\n\
+                     4 * (g.a == 11 && g.f == -4 && g.c == 3);\n\
+        return checks + 8 * (mixed(q, t, p, r, 3, g, 4) == 87);\n\
+    }\n";
+
+#[test]
+fn structures_are_passed_and_returned_by_value() {
+    for flags in [
+        &[][..],
+        &["-O2"],
+        &["-O2", "--ssa"],
+        &["-O2", "-fomit-frame-pointer"],
+        &["--tail-calls"],
+    ] {
+        let executable = compile("passing_structures", PASSING_STRUCTURES, flags);
+        let status = Command::new(&executable).status().unwrap();
+        fs::remove_dir_all(executable.parent().unwrap()).unwrap();
+        assert_eq!(status.code(), Some(15), "with {flags:?}");
+    }
+}
+
+// the calls have to agree with gcc's in both directions, so functions compiled here are linked
+// with a main compiled by gcc that they call back into
+#[test]
+fn structure_passing_matches_gcc() {
+    let executable = compile(
+        "structure_interop",
+        "struct triple { int a; int b; int c; };\n\
+         struct big { int a; int b; int c; int d; int e; int f; };\n\
+         \n\
+         struct big theirs(struct triple t, int x, struct big g);\n\
+         \n\
+         struct triple ours_triple(int a, struct triple t) {\n\
+             t.a = t.a + a;\n\
+             return t;\n\
+         }\n\
+         \n\
+         int ours_calls_theirs(struct triple t) {\n\
+             struct big g;\n\
+             g.a = 1;\n\
+             g.b = 2;\n\
+             g.c = 3;\n\
+             g.d = 4;\n\
+             g.e = 5;\n\
+             g.f = 6;\n\
+             struct big h = theirs(t, 10, g);\n\
+             return h.a + h.f;\n\
+         }\n",
+        &["-S"],
+    );
+    let directory = executable.parent().unwrap();
+    fs::write(
+        directory.join("main.c"),
+        "struct triple { int a; int b; int c; };\n\
+         struct big { int a; int b; int c; int d; int e; int f; };\n\
+         struct triple ours_triple(int a, struct triple t);\n\
+         int ours_calls_theirs(struct triple t);\n\
+         struct big theirs(struct triple t, int x, struct big g) {\n\
+             g.a += t.a * x;\n\
+             g.f += t.c;\n\
+             return g;\n\
+         }\n\
+         int main(void) {\n\
+             struct triple t = {1, 2, 3};\n\
+             struct triple u = ours_triple(5, t);\n\
+             return (u.a == 6 && u.b == 2 && u.c == 3) + 2 * (ours_calls_theirs(t) == 20);\n\
+         }\n",
+    )
+    .unwrap();
+    let status = Command::new("gcc")
+        .args(["main.c", "structure_interop.s", "-o", "interop"])
+        .current_dir(directory)
+        .status()
+        .unwrap();
+    assert!(status.success(), "linking with gcc failed with {status}");
+    let status = Command::new(directory.join("interop")).status().unwrap();
+    fs::remove_dir_all(directory).unwrap();
+    assert_eq!(status.code(), Some(3));
+}
@@ -0,0 +1,21 @@
+use std::{fs, process::Command};
+
+mod common;
+
+use common::compile;
+
+// ten million frames would overflow the stack many times over if either call pushed one
+#[test]
+fn mutual_recursion_runs_in_constant_stack_space_with_tail_calls() {
+    let executable = compile(
+        "mutual_recursion",
+        "int is_odd(int n);\n\
+         int is_even(int n) { return n == 0 ? 1 : is_odd(n - 1); }\n\
+         int is_odd(int n) { return n == 0 ? 0 : is_even(n - 1); }\n\
+         int main(void) { return is_even(10000000) + 2 * is_odd(9999999); }\n",
+        &["--tail-calls"],
+    );
+    let status = Command::new(&executable).status().unwrap();
+    fs::remove_dir_all(executable.parent().unwrap()).unwrap();
+    assert_eq!(status.code(), Some(3));
+}